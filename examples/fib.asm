@@ -0,0 +1,13 @@
+; Fibonacci numbers, a million times over. The sums wrap around long before
+; the loop ends; the point is to keep the VM busy. `asmvm bench` times it.
+        clr $0
+        li $1 #1
+        clr $4
+        li $6 #1000000
+
+loop:   mov $1 $2
+        add $0 $1 $1
+        mov $2 $0
+        inc $4
+        lt $4 $6
+        jmpc @loop
@@ -0,0 +1,410 @@
+use std::fmt;
+use std::fmt::Write;
+
+use crate::instruction::{Encoding, Opcode};
+use crate::vm::custom::CustomInstructions;
+use crate::vm::interrupts::INTERRUPT_COUNT;
+use crate::vm::predecode::decode_instr_with;
+use crate::vm::{VmError, REGISTER_COUNT};
+
+const PRELUDE: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+
+static int32_t registers[REGISTERS];
+static uint32_t remainder_;
+static int conditional;
+
+/* Wrapping arithmetic, like the VM's. */
+static int32_t wrap(uint32_t value) { return (int32_t)value; }
+
+static void print_registers(void) {
+    int i;
+    for (i = 0; i < REGISTERS; i++) {
+        printf("$%d = %ld\n", i, (long)registers[i]);
+    }
+    printf("remainder = %lu\nconditional = %d\n", (unsigned long)remainder_, conditional);
+}
+
+static int fault(long pc, const char *message) {
+    fprintf(stderr, "error: byte %ld: %s\n", pc, message);
+    return 1;
+}
+
+static int run(void) {
+    int64_t target;
+    long from;
+    int32_t a, b;
+"#;
+
+const POSTLUDE: &str = r#"
+jump:
+    if (target < 0) {
+        char message[64];
+        sprintf(message, "jump to invalid address %lld", (long long)target);
+        return fault(from, message);
+    }
+    if (target >= LENGTH) {
+        return 0;
+    }
+    switch (target) {
+"#;
+
+const MAIN: &str = r#"
+/* With --registers, prints the registers once the program stops. */
+int main(int argc, char **argv) {
+    int status = run();
+    if (argc > 1 && strcmp(argv[1], "--registers") == 0) {
+        print_registers();
+    }
+    return status;
+}
+"#;
+
+/// An instruction that `translate` can't give the same behaviour in C. The
+/// translation has no interrupts, so a program that enables them with `ei`
+/// is refused rather than run without them.
+#[derive(Debug, PartialEq)]
+pub struct Unsupported {
+    pub offset: usize,
+    pub opcode: Opcode,
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` is not supported by asmvm aot, which has no interrupts",
+            self.opcode.mnemonic()
+        )
+    }
+}
+
+fn register(number: u8) -> String {
+    format!("registers[{}]", number)
+}
+
+// The C for the instruction at `offset`, where the next one starts, and
+// whether control can carry on there.
+fn statement(program: &[u8], offset: usize, encoding: Encoding) -> (String, usize, bool) {
+    let instr = match decode_instr_with(program, offset, encoding, &CustomInstructions::new()) {
+        Ok(instr) => instr,
+        Err((error, _)) => {
+            let fault = format!("return fault({}, \"{}\");", offset, error);
+            return (fault, offset + encoding.alignment(), false);
+        }
+    };
+    let [x, y, z] = instr.operands;
+    let (x, y, z) = (register(x), register(y), register(z));
+    let next = instr.next;
+    let statement = match instr.opcode {
+        Opcode::HLT => "puts(\"HLT encountered\");\n    return 0;".to_string(),
+        Opcode::LOAD => format!("{} = {};", x, instr.immediate),
+        Opcode::ADD => format!("{} = wrap((uint32_t){} + (uint32_t){});", z, x, y),
+        Opcode::SUB => format!("{} = wrap((uint32_t){} - (uint32_t){});", z, x, y),
+        Opcode::MUL => format!("{} = wrap((uint32_t){} * (uint32_t){});", z, x, y),
+        Opcode::DIV => format!(
+            "a = {}, b = {};\n    \
+             if (b == 0) return fault({}, \"division by zero\");\n    \
+             if (b == -1) {{ {} = wrap(0u - (uint32_t)a); remainder_ = 0; }}\n    \
+             else {{ {} = a / b; remainder_ = (uint32_t)(a % b); }}",
+            x, y, offset, z, z
+        ),
+        Opcode::JMP => format!("target = {}; from = {}; goto jump;", x, offset),
+        Opcode::JMPB => format!(
+            "target = {} - (int64_t){}; from = {}; goto jump;",
+            next, x, offset
+        ),
+        Opcode::JMPF => format!(
+            "target = {} + (int64_t){}; from = {}; goto jump;",
+            next, x, offset
+        ),
+        Opcode::JMPC => format!(
+            "if (conditional) {{ target = {}; from = {}; goto jump; }}",
+            x, offset
+        ),
+        Opcode::EQ => format!("conditional = {} == {};", x, y),
+        Opcode::NEQ => format!("conditional = {} != {};", x, y),
+        Opcode::GT => format!("conditional = {} > {};", x, y),
+        Opcode::LT => format!("conditional = {} < {};", x, y),
+        Opcode::GTQ => format!("conditional = {} >= {};", x, y),
+        Opcode::LTQ => format!("conditional = {} <= {};", x, y),
+        Opcode::LUI => format!(
+            "{} = wrap(((uint32_t){} << 16) | ((uint32_t){} & 0xFFFF));",
+            x, instr.immediate, x
+        ),
+        Opcode::ADDI => format!(
+            "{} = wrap((uint32_t){} + (uint32_t){});",
+            y,
+            x,
+            instr.signed_immediate()
+        ),
+        Opcode::SUBI => format!(
+            "{} = wrap((uint32_t){} - (uint32_t){});",
+            y,
+            x,
+            instr.signed_immediate()
+        ),
+        Opcode::MULI => format!(
+            "{} = wrap((uint32_t){} * (uint32_t){});",
+            y,
+            x,
+            instr.signed_immediate()
+        ),
+        Opcode::CMPI => format!("conditional = {} == {};", x, instr.signed_immediate()),
+        Opcode::BEQ | Opcode::BNEQ | Opcode::BGT | Opcode::BLT | Opcode::BGTQ | Opcode::BLTQ => {
+            let operator = match instr.opcode {
+                Opcode::BEQ => "==",
+                Opcode::BNEQ => "!=",
+                Opcode::BGT => ">",
+                Opcode::BLT => "<",
+                Opcode::BGTQ => ">=",
+                _ => "<=",
+            };
+            format!(
+                "if ({} {} {}) {{ target = {}; from = {}; goto jump; }}",
+                x, operator, y, instr.immediate, offset
+            )
+        }
+        Opcode::NOP | Opcode::YIELD | Opcode::DI | Opcode::TIMER => ";".to_string(),
+        Opcode::SPAWN | Opcode::SEND | Opcode::RECV => {
+            format!("return fault({}, \"{}\");", offset, VmError::NoScheduler)
+        }
+        // Interrupts are never enabled, so handlers only need checking.
+        Opcode::SETVEC => format!(
+            "if ({x} < 0 || {x} >= {}) {{\n        char message[64];\n        \
+             sprintf(message, \"there is no interrupt %ld\", (long){x});\n        \
+             return fault({}, message);\n    }}",
+            INTERRUPT_COUNT,
+            offset,
+            x = x
+        ),
+        // Only reached by jumping into the middle of an instruction, since
+        // `translate` refuses programs with an `ei` of their own.
+        Opcode::EI => format!(
+            "return fault({}, \"interrupts are not supported by asmvm aot\");",
+            offset
+        ),
+        Opcode::IRET => format!("return fault({}, \"{}\");", offset, VmError::NotInInterrupt),
+        Opcode::IGL | Opcode::CUSTOM(_) => unreachable!(),
+    };
+    (statement, next, instr.opcode != Opcode::HLT)
+}
+
+/// Translates a program to a C file with the same behaviour. Every byte
+/// offset gets a label, so jumps into the middle of an instruction work the
+/// way they do in the VM. Running the result prints `HLT encountered` on a
+/// `hlt`, and exits with 1 after printing a fault.
+pub fn translate(bytecode: &[u8]) -> Result<String, Unsupported> {
+    let (encoding, program) = Encoding::detect(bytecode);
+    let custom = CustomInstructions::new();
+    let mut offset = 0;
+    while offset < program.len() {
+        match decode_instr_with(program, offset, encoding, &custom) {
+            Ok(instr) if instr.opcode == Opcode::EI => {
+                return Err(Unsupported {
+                    offset,
+                    opcode: instr.opcode,
+                })
+            }
+            Ok(instr) => offset = instr.next,
+            Err(_) => offset += encoding.alignment(),
+        }
+    }
+
+    let mut c = String::new();
+    writeln!(c, "/* Translated from asmvm bytecode by `asmvm aot`. */").unwrap();
+    writeln!(c, "#define REGISTERS {}", REGISTER_COUNT).unwrap();
+    writeln!(c, "#define LENGTH {}", program.len()).unwrap();
+    c.push_str(PRELUDE);
+    writeln!(c, "    (void)a;\n    (void)b;").unwrap();
+
+    // The program in order, with one instruction falling through to the
+    // next.
+    let mut in_order = vec![false; program.len()];
+    let mut offset = 0;
+    let mut falls_through = true;
+    while offset < program.len() {
+        in_order[offset] = true;
+        let (statement, next, carries_on) = statement(program, offset, encoding);
+        writeln!(c, "b{}:\n    {}", offset, statement).unwrap();
+        offset = next;
+        falls_through = carries_on;
+    }
+    if falls_through {
+        writeln!(c, "    return 0;").unwrap();
+    }
+
+    // Everything else is only reached by jumping into the middle of an
+    // instruction.
+    for offset in (0..program.len()).filter(|offset| !in_order[*offset]) {
+        let (statement, next, carries_on) = statement(program, offset, encoding);
+        writeln!(
+            c,
+            "b{}: /* inside an instruction */\n    {}",
+            offset, statement
+        )
+        .unwrap();
+        if !carries_on {
+            continue;
+        } else if next < program.len() {
+            writeln!(c, "    goto b{};", next).unwrap();
+        } else {
+            writeln!(c, "    return 0;").unwrap();
+        }
+    }
+
+    c.push_str(POSTLUDE);
+    for offset in 0..program.len() {
+        writeln!(c, "    case {}: goto b{};", offset, offset).unwrap();
+    }
+    writeln!(c, "    }}\n    return 0;\n}}").unwrap();
+    c.push_str(MAIN);
+    Ok(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+    use std::fs;
+    use std::process;
+
+    // Compiles the translation of `program` with the system C compiler and
+    // checks that it ends up in the same state as the VM. Returns false if
+    // there is no C compiler to test with.
+    fn differential(name: &str, program: Vec<u8>) -> bool {
+        let dir = std::env::temp_dir().join(format!("asmvm-aot-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("prog.c");
+        let binary = dir.join("prog");
+        fs::write(&source, translate(&program).unwrap()).unwrap();
+        let compiled = process::Command::new("cc")
+            .arg("-O1")
+            .arg("-o")
+            .arg(&binary)
+            .arg(&source)
+            .status();
+        match compiled {
+            Ok(status) => assert!(status.success(), "{} did not compile", source.display()),
+            Err(_) => return false,
+        }
+        let output = process::Command::new(&binary)
+            .arg("--registers")
+            .output()
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut vm = VM::new();
+        vm.load(program, None).unwrap();
+        let mut expected = String::new();
+        match vm.run() {
+            Ok(()) => assert_eq!(output.status.code(), Some(0)),
+            Err(fault) => {
+                assert_eq!(output.status.code(), Some(1));
+                assert_eq!(
+                    String::from_utf8_lossy(&output.stderr),
+                    format!("error: {}\n", fault)
+                );
+            }
+        }
+        for (i, value) in vm.registers().iter().enumerate() {
+            writeln!(expected, "${} = {}", i, value).unwrap();
+        }
+        writeln!(expected, "remainder = {}", vm.remainder()).unwrap();
+        writeln!(expected, "conditional = {}", vm.conditional() as u8).unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.ends_with(&expected), "{}", stdout);
+        true
+    }
+
+    #[test]
+    fn test_translated_programs() {
+        let fib = Assembler::new()
+            .assemble(
+                "\tclr $0\n\tli $1 #1\n\tclr $4\n\tli $6 #20\n\
+                 loop:\n\tmov $1 $2\n\tadd $0 $1 $1\n\tmov $2 $0\n\tinc $4\n\
+                 \tlt $4 $6\n\tjmpc @loop\n\thlt\n",
+            )
+            .unwrap();
+        let arithmetic = Assembler::new()
+            .assemble(
+                "\tli $0 #-70000\n\tli $1 #7\n\tmul $0 $1 $2\n\tsub $2 $1 $3\n\
+                 \tdiv $3 $1 $4\n\tload $5 #65535\n\tlui $5 #32768\n\tdiv $5 $1 $6\n\
+                 \tli $7 #-1\n\tdiv $5 $7 $8\n\tdiv $0 $7 $9\n\tgtq $1 $1\n",
+            )
+            .unwrap();
+        let jumps = vec![
+            1, 1, 0, 0, //  LOAD $1 #0, which ends in a HLT
+            1, 0, 0, 5, //  LOAD $0 #5
+            1, 2, 0, 1, //  LOAD $2 #1
+            1, 6, 0, 3, //  LOAD $6 #3
+            3, 0, 2, 0, //  SUB  $0 $2 $0
+            1, 5, 0, 1, //  LOAD $5 #1
+            10, 0, 1, //    EQ   $0 $1
+            9, 6, //        JMPC $6
+            8, 5,   //        JMPF $5, over the illegal byte
+            200, //
+            1, 4, 0, 22, // LOAD $4 #22
+            7, 4, //        JMPB $4, back to the SUB
+        ];
+        let branches = Assembler::new()
+            .assemble(
+                "\tload $1 #100\nloop:\taddi $0 #1 $0\n\tadd $2 $0 $2\n\tblt $0 $1 @loop\n\
+                 \tmuli $2 #-3 $3\n\tsubi $3 #7 $3\n\tcmpi $3 #-15157\n\
+                 \tbgtq $0 $1 @end\n\thlt\nend:\tbneq $0 $0 @loop\n",
+            )
+            .unwrap();
+        let words = Assembler::new()
+            .encoding(Encoding::Fixed)
+            .assemble(
+                "\tli $1 #100000\nloop:\taddi $0 #-3 $0\n\tsubi $1 #1 $1\n\tbneq $1 $2 @loop\n",
+            )
+            .unwrap();
+        if !differential("fib", fib) {
+            eprintln!("no C compiler, skipping");
+            return;
+        }
+        differential("arithmetic", arithmetic);
+        differential("jumps", jumps);
+        differential("branches", branches);
+        differential("words", words);
+        differential("empty", vec![]);
+    }
+
+    #[test]
+    fn test_translated_faults() {
+        let faults = vec![
+            vec![1, 0, 0, 4, 1, 1, 0, 0, 5, 0, 1, 2],
+            vec![1, 0, 0, 8, 7, 0],
+            vec![1, 0, 0, 1, 2, 0, 0, 0, 200],
+            vec![1, 0, 0, 1, 2, 0, 40, 0],
+            vec![1, 0, 0, 1, 1, 0],
+            vec![1, 0, 0, 9, 34, 0, 0, 0],
+            vec![32, 35, 0, 34, 0, 0, 0, 33],
+        ];
+        for (i, program) in faults.into_iter().enumerate() {
+            if !differential(&format!("fault-{}", i), program) {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn test_interrupts_are_refused() {
+        let program = Assembler::new().assemble("\tdi\n\tei\n\thlt\n").unwrap();
+        let unsupported = translate(&program).unwrap_err();
+        assert_eq!(
+            unsupported,
+            Unsupported {
+                offset: 1,
+                opcode: Opcode::EI
+            }
+        );
+        assert_eq!(
+            unsupported.to_string(),
+            "`ei` is not supported by asmvm aot, which has no interrupts"
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use crate::assembler::expressions::{expression, Expression};
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::label_parsers::identifier;
+use crate::assembler::Token;
+use nom::types::CompleteStr;
+use nom::*;
+
+named!(constant_value<CompleteStr, Expression>,
+    preceded!(
+        opt!(char!('#')),
+        expression
+    )
+);
+
+// `.equ NAME value` defines a constant once; `.set NAME value` may be
+// redefined later on. A comma after the name is optional.
+named!(
+    pub directive_constant<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        char!('.') >>
+        directive: alt!(tag!("equ") | tag!("set")) >>
+        space1 >>
+        name: identifier >>
+        alt!(delimited!(space0, char!(','), space0) => { |_| () } | space1 => { |_| () }) >>
+        value: constant_value >>
+        (
+            AssemblerInstruction {
+                directive: Some(Token::Directive { name: directive.to_string() }),
+                operand1: Some(Token::ConstantDeclaration { name: name.to_string() }),
+                operand2: Some(Token::Expression { expression: value }),
+                ..Default::default()
+            }
+        )
+    )
+);
+
+// `.global NAME` exports a label to other modules when assembling an object
+// file, and `.extern NAME` declares one that another module defines.
+named!(
+    pub directive_symbol<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        char!('.') >>
+        directive: alt!(tag!("global") | tag!("extern")) >>
+        space1 >>
+        name: identifier >>
+        (
+            AssemblerInstruction {
+                directive: Some(Token::Directive { name: directive.to_string() }),
+                operand1: Some(Token::LabelUsage { name: name.to_string() }),
+                ..Default::default()
+            }
+        )
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_equ() {
+        let (rest, parsed) = directive_constant(CompleteStr(".equ BUF_SIZE 4 * 4")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(
+            parsed.directive,
+            Some(Token::Directive {
+                name: "equ".to_string()
+            })
+        );
+        assert_eq!(
+            parsed.operand1,
+            Some(Token::ConstantDeclaration {
+                name: "BUF_SIZE".to_string()
+            })
+        );
+
+        let (_rest, parsed) = directive_constant(CompleteStr(".set count, #-1")).unwrap();
+        assert_eq!(
+            parsed.operand2,
+            Some(Token::Expression {
+                expression: Expression::Number(-1)
+            })
+        );
+        assert_eq!(directive_constant(CompleteStr(".equ SIZE")).is_ok(), false);
+        assert_eq!(
+            directive_constant(CompleteStr(".word SIZE 1")).is_ok(),
+            false
+        );
+    }
+
+    #[test]
+    fn test_parse_global_and_extern() {
+        let (rest, parsed) = directive_symbol(CompleteStr(".global main")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(
+            parsed.operand1,
+            Some(Token::LabelUsage {
+                name: "main".to_string()
+            })
+        );
+        let (_rest, parsed) = directive_symbol(CompleteStr(".extern square")).unwrap();
+        assert_eq!(
+            parsed.directive,
+            Some(Token::Directive {
+                name: "extern".to_string()
+            })
+        );
+        assert_eq!(directive_symbol(CompleteStr(".global")).is_ok(), false);
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use nom::types::CompleteStr;
+use nom::*;
+
+use crate::assembler::label_parsers::identifier;
+use crate::assembler::operand_parsers::integer_literal;
+use crate::assembler::symbols::SymbolTable;
+use crate::assembler::AssemblerError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(i64),
+    Symbol(String),
+    Negate(Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    /// Replaces references to constants with their definitions. Whatever
+    /// symbols are left are assumed to be labels.
+    pub fn inline(&self, constants: &HashMap<String, Expression>) -> Expression {
+        match self {
+            Expression::Symbol(name) => match constants.get(name) {
+                Some(definition) => definition.clone(),
+                None => self.clone(),
+            },
+            Expression::Negate(operand) => Expression::Negate(Box::new(operand.inline(constants))),
+            Expression::Binary(op, lhs, rhs) => Expression::Binary(
+                *op,
+                Box::new(lhs.inline(constants)),
+                Box::new(rhs.inline(constants)),
+            ),
+            Expression::Number(_) => self.clone(),
+        }
+    }
+
+    pub fn references_symbols(&self) -> bool {
+        match self {
+            Expression::Number(_) => false,
+            Expression::Symbol(_) => true,
+            Expression::Negate(operand) => operand.references_symbols(),
+            Expression::Binary(_, lhs, rhs) => lhs.references_symbols() || rhs.references_symbols(),
+        }
+    }
+
+    /// Names of the symbols the expression refers to, in order of appearance.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expression::Number(_) => vec![],
+            Expression::Symbol(name) => vec![name],
+            Expression::Negate(operand) => operand.symbols(),
+            Expression::Binary(_, lhs, rhs) => {
+                let mut symbols = lhs.symbols();
+                symbols.extend(rhs.symbols());
+                symbols
+            }
+        }
+    }
+
+    /// Evaluates the expression, failing if any intermediate result does not
+    /// fit in 32 bits (signed or unsigned).
+    pub fn evaluate(&self, symbols: &SymbolTable) -> Result<i64, AssemblerError> {
+        let value = match self {
+            Expression::Number(value) => Some(*value),
+            Expression::Symbol(name) => match symbols.symbol_value(name) {
+                Some(offset) => Some(i64::from(offset)),
+                None => return Err(AssemblerError::UndefinedSymbol { name: name.clone() }),
+            },
+            Expression::Negate(operand) => operand.evaluate(symbols)?.checked_neg(),
+            Expression::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate(symbols)?;
+                let rhs = rhs.evaluate(symbols)?;
+                match op {
+                    BinaryOp::Add => lhs.checked_add(rhs),
+                    BinaryOp::Sub => lhs.checked_sub(rhs),
+                    BinaryOp::Mul => lhs.checked_mul(rhs),
+                    BinaryOp::Div | BinaryOp::Rem if rhs == 0 => {
+                        return Err(AssemblerError::DivisionByZero)
+                    }
+                    BinaryOp::Div => lhs.checked_div(rhs),
+                    BinaryOp::Rem => lhs.checked_rem(rhs),
+                    BinaryOp::Shl => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shl(rhs)),
+                    BinaryOp::Shr => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shr(rhs)),
+                    BinaryOp::And => Some(lhs & rhs),
+                    BinaryOp::Or => Some(lhs | rhs),
+                    BinaryOp::Xor => Some(lhs ^ rhs),
+                }
+            }
+        };
+        match value {
+            Some(value) if value >= i64::from(i32::MIN) && value <= i64::from(u32::MAX) => {
+                Ok(value)
+            }
+            _ => Err(AssemblerError::ExpressionOverflow),
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expression::Number(value) => write!(f, "{}", value),
+            Expression::Symbol(name) => write!(f, "{}", name),
+            Expression::Negate(operand) => write!(f, "-{}", operand),
+            Expression::Binary(op, lhs, rhs) => {
+                let op = match op {
+                    BinaryOp::Add => "+",
+                    BinaryOp::Sub => "-",
+                    BinaryOp::Mul => "*",
+                    BinaryOp::Div => "/",
+                    BinaryOp::Rem => "%",
+                    BinaryOp::Shl => "<<",
+                    BinaryOp::Shr => ">>",
+                    BinaryOp::And => "&",
+                    BinaryOp::Or => "|",
+                    BinaryOp::Xor => "^",
+                };
+                write!(f, "({} {} {})", lhs, op, rhs)
+            }
+        }
+    }
+}
+
+fn fold(first: Expression, rest: Vec<(BinaryOp, Expression)>) -> Expression {
+    rest.into_iter().fold(first, |lhs, (op, rhs)| {
+        Expression::Binary(op, Box::new(lhs), Box::new(rhs))
+    })
+}
+
+named!(primary<CompleteStr, Expression>,
+    alt_complete!(
+        integer_literal => { Expression::Number } |
+        preceded!(opt!(char!('@')), identifier) => {
+            |name: CompleteStr| Expression::Symbol(name.to_string())
+        } |
+        delimited!(
+            terminated!(char!('('), space0),
+            expression,
+            preceded!(space0, char!(')'))
+        )
+    )
+);
+
+named!(unary<CompleteStr, Expression>,
+    alt_complete!(
+        primary |
+        preceded!(terminated!(char!('-'), space0), unary) => {
+            |operand| Expression::Negate(Box::new(operand))
+        }
+    )
+);
+
+named!(multiplicative<CompleteStr, Expression>,
+    do_parse!(
+        first: unary >>
+        rest: many0!(pair!(
+            delimited!(space0, alt!(
+                char!('*') => { |_| BinaryOp::Mul } |
+                char!('/') => { |_| BinaryOp::Div } |
+                char!('%') => { |_| BinaryOp::Rem }
+            ), space0),
+            unary
+        )) >>
+        (fold(first, rest))
+    )
+);
+
+named!(additive<CompleteStr, Expression>,
+    do_parse!(
+        first: multiplicative >>
+        rest: many0!(pair!(
+            delimited!(space0, alt!(
+                char!('+') => { |_| BinaryOp::Add } |
+                char!('-') => { |_| BinaryOp::Sub }
+            ), space0),
+            multiplicative
+        )) >>
+        (fold(first, rest))
+    )
+);
+
+named!(shift<CompleteStr, Expression>,
+    do_parse!(
+        first: additive >>
+        rest: many0!(pair!(
+            delimited!(space0, alt!(
+                tag!("<<") => { |_| BinaryOp::Shl } |
+                tag!(">>") => { |_| BinaryOp::Shr }
+            ), space0),
+            additive
+        )) >>
+        (fold(first, rest))
+    )
+);
+
+named!(bitwise_and<CompleteStr, Expression>,
+    do_parse!(
+        first: shift >>
+        rest: many0!(pair!(
+            value!(BinaryOp::And, delimited!(space0, char!('&'), space0)),
+            shift
+        )) >>
+        (fold(first, rest))
+    )
+);
+
+named!(bitwise_xor<CompleteStr, Expression>,
+    do_parse!(
+        first: bitwise_and >>
+        rest: many0!(pair!(
+            value!(BinaryOp::Xor, delimited!(space0, char!('^'), space0)),
+            bitwise_and
+        )) >>
+        (fold(first, rest))
+    )
+);
+
+// Operators bind as they do in C, from `|` (loosest) to unary `-`.
+named!(
+    pub expression<CompleteStr, Expression>,
+    do_parse!(
+        first: bitwise_xor >>
+        rest: many0!(pair!(
+            value!(BinaryOp::Or, delimited!(space0, char!('|'), space0)),
+            bitwise_xor
+        )) >>
+        (fold(first, rest))
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(source: &str) -> Result<i64, AssemblerError> {
+        let (rest, expr) = expression(CompleteStr(source)).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        expr.evaluate(&SymbolTable::new())
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        assert_eq!(evaluate("2 + 3 * 4"), Ok(14));
+        assert_eq!(evaluate("(2 + 3) * 4"), Ok(20));
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(3));
+        assert_eq!(evaluate("1 << 4 | 0x0F & 3"), Ok(19));
+        assert_eq!(evaluate("-(7 % 4)"), Ok(-3));
+        assert_eq!(evaluate("'a' + 1"), Ok(98));
+    }
+
+    #[test]
+    fn test_evaluate_symbols() {
+        let (_, expr) = expression(CompleteStr("(end - start) / 4")).unwrap();
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol("start", 8);
+        assert_eq!(
+            expr.evaluate(&symbols),
+            Err(AssemblerError::UndefinedSymbol {
+                name: "end".to_string()
+            })
+        );
+        symbols.add_symbol("end", 24);
+        assert_eq!(expr.evaluate(&symbols), Ok(4));
+    }
+
+    #[test]
+    fn test_inline_constants() {
+        let (_, expr) = expression(CompleteStr("SIZE * 4 + @label")).unwrap();
+        let mut constants = HashMap::new();
+        constants.insert("SIZE".to_string(), Expression::Number(3));
+        let inlined = expr.inline(&constants);
+        assert_eq!(inlined.to_string(), "((3 * 4) + label)");
+        assert_eq!(inlined.references_symbols(), true);
+        assert_eq!(inlined.symbols(), vec!["label"]);
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        assert_eq!(evaluate("0xFFFF * 0x10000"), Ok(0xFFFF_0000));
+        assert_eq!(
+            evaluate("0x10000 * 0x10000"),
+            Err(AssemblerError::ExpressionOverflow)
+        );
+        assert_eq!(evaluate("1 / (2 - 2)"), Err(AssemblerError::DivisionByZero));
+        assert_eq!(evaluate("1 << 40"), Err(AssemblerError::ExpressionOverflow));
+    }
+}
@@ -0,0 +1,214 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use nom::types::CompleteStr;
+use nom::*;
+
+use crate::assembler::instruction_parsers::line_end;
+use crate::assembler::source::{file_lines, Location, SourceLine};
+use crate::assembler::{AssemblerError, Diagnostic};
+
+named!(include_directive<CompleteStr, CompleteStr>,
+    do_parse!(
+        space0 >>
+        tag!(".include") >>
+        space1 >>
+        path: delimited!(char!('"'), is_not!("\""), char!('"')) >>
+        line_end >>
+        (path)
+    )
+);
+
+/// Replaces `.include "path"` lines with the contents of the named file.
+/// Paths are looked up next to the including file first and then in each
+/// of the search paths, in order.
+pub struct IncludeExpander<'a> {
+    search_paths: &'a [PathBuf],
+    // Canonical paths of the files currently being included, used to detect
+    // cycles.
+    stack: Vec<PathBuf>,
+}
+
+impl<'a> IncludeExpander<'a> {
+    pub fn new(search_paths: &'a [PathBuf]) -> IncludeExpander<'a> {
+        IncludeExpander {
+            search_paths,
+            stack: vec![],
+        }
+    }
+
+    pub fn expand_file(&mut self, path: &Path) -> Result<Vec<SourceLine>, Vec<Diagnostic>> {
+        let mut output = vec![];
+        let mut errors = vec![];
+        let location = Location::in_file(&Rc::new(path.to_path_buf()), 0);
+        self.include(path, &location, &mut output, &mut errors);
+        if errors.is_empty() {
+            Ok(output)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn expand(&mut self, lines: Vec<SourceLine>) -> Result<Vec<SourceLine>, Vec<Diagnostic>> {
+        let mut output = vec![];
+        let mut errors = vec![];
+        self.expand_lines(lines, &mut output, &mut errors);
+        if errors.is_empty() {
+            Ok(output)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn expand_lines(
+        &mut self,
+        lines: Vec<SourceLine>,
+        output: &mut Vec<SourceLine>,
+        errors: &mut Vec<Diagnostic>,
+    ) {
+        for line in lines {
+            let path = match include_directive(CompleteStr(&line.text)) {
+                Ok((_, path)) => path.to_string(),
+                Err(_) => {
+                    output.push(line);
+                    continue;
+                }
+            };
+            let including = line.location.file.as_ref().map(|file| file.as_path());
+            match self.resolve(&path, including) {
+                Some(resolved) => self.include(&resolved, &line.location, output, errors),
+                None => errors.push(Diagnostic::new(
+                    line.location,
+                    AssemblerError::IncludeNotFound { path },
+                )),
+            }
+        }
+    }
+
+    fn resolve(&self, path: &str, including: Option<&Path>) -> Option<PathBuf> {
+        let path = Path::new(path);
+        if path.is_absolute() {
+            return Some(path.to_path_buf()).filter(|path| path.is_file());
+        }
+        let base = match including.and_then(Path::parent) {
+            Some(directory) => directory.join(path),
+            None => path.to_path_buf(),
+        };
+        std::iter::once(base)
+            .chain(
+                self.search_paths
+                    .iter()
+                    .map(|directory| directory.join(path)),
+            )
+            .find(|candidate| candidate.is_file())
+    }
+
+    fn include(
+        &mut self,
+        path: &Path,
+        location: &Location,
+        output: &mut Vec<SourceLine>,
+        errors: &mut Vec<Diagnostic>,
+    ) {
+        let display = path.display().to_string();
+        let read_failed = |e: std::io::Error| {
+            Diagnostic::new(
+                location.clone(),
+                AssemblerError::IncludeReadFailed {
+                    path: display.clone(),
+                    reason: e.to_string(),
+                },
+            )
+        };
+        let canonical = match fs::canonicalize(path) {
+            Ok(canonical) => canonical,
+            Err(e) => return errors.push(read_failed(e)),
+        };
+        if self.stack.contains(&canonical) {
+            errors.push(Diagnostic::new(
+                location.clone(),
+                AssemblerError::IncludeCycle { path: display },
+            ));
+            return;
+        }
+        let source = match fs::read_to_string(&canonical) {
+            Ok(source) => source,
+            Err(e) => return errors.push(read_failed(e)),
+        };
+
+        self.stack.push(canonical);
+        let lines = file_lines(&source, &Rc::new(path.to_path_buf()));
+        self.expand_lines(lines, output, errors);
+        self.stack.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::source::source_lines;
+    use crate::scratch::ScratchDir;
+
+    #[test]
+    fn test_include_search_paths() {
+        let dir = ScratchDir::new("include-search");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("lib/util.asm"),
+            "; util\n.include \"consts.asm\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("lib/consts.asm"), "load $30 #1\n").unwrap();
+        fs::write(
+            dir.join("main.asm"),
+            "  .include \"util.asm\"   ; shared\nhlt\n",
+        )
+        .unwrap();
+
+        let search_paths = vec![dir.join("lib")];
+        let lines = IncludeExpander::new(&search_paths)
+            .expand_file(&dir.join("main.asm"))
+            .unwrap();
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, vec!["; util", "load $30 #1", "hlt"]);
+        assert_eq!(
+            lines[1].location,
+            Location::in_file(&Rc::new(dir.join("lib").join("consts.asm")), 1)
+        );
+        assert_eq!(lines[2].location.line, 2);
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = ScratchDir::new("include-errors");
+        fs::write(dir.join("a.asm"), "hlt\n.include \"b.asm\"\n").unwrap();
+        fs::write(dir.join("b.asm"), ".include \"a.asm\"\n").unwrap();
+
+        let errors = IncludeExpander::new(&[])
+            .expand_file(&dir.join("a.asm"))
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].location,
+            Location::in_file(&Rc::new(dir.join("b.asm")), 1)
+        );
+        match &errors[0].error {
+            AssemblerError::IncludeCycle { path } => assert!(path.ends_with("a.asm")),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let errors = IncludeExpander::new(&[])
+            .expand(source_lines("nop\n.include \"missing.asm\"\n"))
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![Diagnostic::new(
+                Location::new(2),
+                AssemblerError::IncludeNotFound {
+                    path: "missing.asm".to_string()
+                }
+            )]
+        );
+    }
+}
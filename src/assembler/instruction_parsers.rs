@@ -1,77 +1,370 @@
+use crate::assembler::directive_parsers::{directive_constant, directive_symbol};
+use crate::assembler::expressions::Expression;
+use crate::assembler::label_parsers::{label_declaration, label_usage};
 use crate::assembler::opcode_parsers::*;
 use crate::assembler::operand_parsers::integer_operand;
 use crate::assembler::register_parsers::register;
-use crate::assembler::Token;
+use crate::assembler::source::Location;
+use crate::assembler::symbols::SymbolTable;
+use crate::assembler::{AssemblerError, Token};
+use crate::instruction::{Encoding, Opcode, OperandKind, Word, WORD_SIZE};
 
 use nom::types::CompleteStr;
 use nom::*;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct AssemblerInstruction {
-    opcode: Token,
-    operand1: Option<Token>,
-    operand2: Option<Token>,
-    operand3: Option<Token>,
+    pub opcode: Option<Token>,
+    pub label: Option<Token>,
+    pub directive: Option<Token>,
+    pub operand1: Option<Token>,
+    pub operand2: Option<Token>,
+    pub operand3: Option<Token>,
+    pub location: Location,
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut results = vec![];
+    /// Number of bytes this instruction assembles to. Label values are not
+    /// known yet, so a `load` of a label is assumed to fit in 16 bits.
+    pub fn size(&self) -> usize {
         match &self.opcode {
-            Token::Op { code } => match code {
-                _ => results.push(*code as u8),
-            },
-            _ => {
-                // TODO: Proper error handling
-                println!("Non-opcode found in opcode field");
-                std::process::exit(1);
+            None => 0,
+            Some(_) if self.expands_load() => 8,
+            Some(_) => {
+                1 + self
+                    .operands()
+                    .map(|operand| match operand {
+                        Token::Register { .. } => 1,
+                        _ => 2,
+                    })
+                    .sum::<usize>()
+            }
+        }
+    }
+
+    /// Number of bytes this instruction assembles to in `encoding`.
+    pub fn size_in(&self, encoding: Encoding) -> usize {
+        match (encoding, &self.opcode) {
+            (Encoding::Variable, _) => self.size(),
+            (Encoding::Fixed, None) => 0,
+            (Encoding::Fixed, Some(_)) if self.expands_load() => 2 * WORD_SIZE,
+            (Encoding::Fixed, Some(_)) => WORD_SIZE,
+        }
+    }
+
+    pub fn label_name(&self) -> Option<&str> {
+        match &self.label {
+            Some(Token::LabelDeclaration { name }) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The kind of each operand as written, counting anything that isn't a
+    /// register as an immediate.
+    pub fn operand_kinds(&self) -> Vec<OperandKind> {
+        self.operands()
+            .map(|operand| match operand {
+                Token::Register { .. } => OperandKind::Register,
+                _ => OperandKind::Immediate,
+            })
+            .collect()
+    }
+
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+        let code = match &self.opcode {
+            None => return Ok(vec![]),
+            Some(Token::Op { code }) => *code,
+            Some(Token::CustomOp { mnemonic }) => {
+                return Err(AssemblerError::UnknownInstruction {
+                    mnemonic: mnemonic.clone(),
+                })
+            }
+            Some(_) => return Err(AssemblerError::NonOpcodeInOpcodeField),
+        };
+
+        if self.expands_load() {
+            if let (Some(register), Some(Token::IntegerOperand { value })) =
+                (&self.operand1, &self.operand2)
+            {
+                return AssemblerInstruction::expand_load(register, *value);
+            }
+        }
+
+        // Custom instructions had their operands checked when they were
+        // looked up.
+        let byte = match (code, code.info()) {
+            (Opcode::CUSTOM(byte), _) => byte,
+            (_, Some(info)) if self.operand_kinds() == info.operands => info.byte,
+            (_, Some(info)) => {
+                return Err(AssemblerError::InvalidOperands {
+                    mnemonic: info.mnemonic.to_string(),
+                })
+            }
+            (_, None) => {
+                return Err(AssemblerError::UnknownInstruction {
+                    mnemonic: code.mnemonic().to_string(),
+                })
+            }
+        };
+
+        let mut results = vec![byte];
+        for t in self.operands() {
+            // The value of a label can't change the size of the instruction,
+            // so it has to fit the immediate as the instruction reads it.
+            // Signed values and addresses written as numbers have to fit too.
+            match t {
+                Token::LabelUsage { .. } | Token::Expression { .. } => {
+                    let value = AssemblerInstruction::resolve(t, symbols)?;
+                    check_immediate(value, code.sign_extends())?;
+                }
+                Token::IntegerOperand { value } if code.sign_extends() || code.takes_address() => {
+                    check_immediate(*value, code.sign_extends())?;
+                }
+                _ => {}
+            }
+            AssemblerInstruction::extract_operand(t, symbols, &mut results)?;
+        }
+
+        Ok(results)
+    }
+
+    /// Encodes the instruction as fixed-width words, one for each
+    /// instruction it assembles to.
+    pub fn to_words(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+        let bytes = self.to_bytes(symbols)?;
+        // A `load` split in two becomes a `load` and a `lui`, which take the
+        // same operands, so every instruction here has the same length.
+        let kinds = self.operand_kinds();
+        let length = 1 + kinds.iter().map(|kind| kind.size()).sum::<usize>();
+        let mut words = vec![];
+        for instruction in bytes.chunks(length) {
+            let mut word = Word {
+                opcode: instruction[0],
+                registers: [0; 3],
+                immediate: 0,
+            };
+            let mut registers = word.registers.iter_mut();
+            let mut position = 1;
+            for kind in &kinds {
+                match kind {
+                    OperandKind::Register => {
+                        if let Some(register) = registers.next() {
+                            *register = instruction[position];
+                        }
+                    }
+                    OperandKind::Immediate => {
+                        word.immediate =
+                            u16::from_be_bytes([instruction[position], instruction[position + 1]]);
+                    }
+                }
+                position += kind.size();
+            }
+
+            if let Some(number) = word.registers.iter().find(|number| **number >= 32) {
+                return Err(AssemblerError::RegisterOutOfRange { number: *number });
             }
+            let signed = Opcode::from(word.opcode).sign_extends();
+            if !word.fits(&kinds, signed) {
+                let value = if signed {
+                    i64::from(word.immediate as i16)
+                } else {
+                    i64::from(word.immediate)
+                };
+                return Err(AssemblerError::ImmediateOutOfRange {
+                    value,
+                    bits: Word::immediate_bits(&kinds) as u8,
+                });
+            }
+            words.extend_from_slice(&word.to_bytes(&kinds));
         }
+        Ok(words)
+    }
 
-        for operand in vec![&self.operand1, &self.operand2, &self.operand3] {
+    /// Operands whose value depends on a label, along with the offset of
+    /// their 16-bit field in the encoded instruction.
+    pub fn relocations(&self) -> Vec<(usize, Expression)> {
+        if self.opcode.is_none() {
+            return vec![];
+        }
+        let mut relocations = vec![];
+        let mut offset = 1;
+        for operand in self.operands() {
             match operand {
-                Some(t) => AssemblerInstruction::extract_operand(t, &mut results),
-                None => {}
+                Token::Register { .. } => {
+                    offset += 1;
+                    continue;
+                }
+                Token::LabelUsage { name } => {
+                    relocations.push((offset, Expression::Symbol(name.clone())))
+                }
+                Token::Expression { expression } => relocations.push((offset, expression.clone())),
+                _ => {}
             }
+            offset += 2;
         }
+        relocations
+    }
+
+    /// A copy of the instruction with every label-dependent operand replaced
+    /// by zero, for encoding before label addresses are known.
+    pub fn with_placeholders(&self) -> AssemblerInstruction {
+        let mut placeholder = self.clone();
+        for operand in [
+            &mut placeholder.operand1,
+            &mut placeholder.operand2,
+            &mut placeholder.operand3,
+        ] {
+            if let Some(Token::LabelUsage { .. }) | Some(Token::Expression { .. }) = operand {
+                *operand = Some(Token::IntegerOperand { value: 0 });
+            }
+        }
+        placeholder
+    }
 
-        return results;
+    pub fn operands(&self) -> impl Iterator<Item = &Token> {
+        self.operand1
+            .iter()
+            .chain(self.operand2.iter())
+            .chain(self.operand3.iter())
     }
 
-    pub fn extract_operand(t: &Token, results: &mut Vec<u8>) {
+    // A `load` whose value does not fit in its 16-bit immediate is split into
+    // a `load` of the low half followed by a `lui` of the high half.
+    fn expands_load(&self) -> bool {
+        match (&self.opcode, &self.operand2) {
+            (Some(Token::Op { code: Opcode::LOAD }), Some(Token::IntegerOperand { value })) => {
+                *value < 0 || *value > i64::from(u16::MAX)
+            }
+            _ => false,
+        }
+    }
+
+    fn expand_load(register: &Token, value: i64) -> Result<Vec<u8>, AssemblerError> {
+        if value < i64::from(i32::MIN) || value > i64::from(u32::MAX) {
+            return Err(AssemblerError::ImmediateOutOfRange { value, bits: 32 });
+        }
+        let bits = value as u32;
+        let half = |code, value: u32| AssemblerInstruction {
+            opcode: Some(Token::Op { code }),
+            operand1: Some(register.clone()),
+            operand2: Some(Token::IntegerOperand {
+                value: i64::from(value),
+            }),
+            ..Default::default()
+        };
+        let symbols = SymbolTable::new();
+        let mut results = half(Opcode::LOAD, bits & 0xFFFF).to_bytes(&symbols)?;
+        results.extend(half(Opcode::LUI, bits >> 16).to_bytes(&symbols)?);
+        Ok(results)
+    }
+
+    pub fn extract_operand(
+        t: &Token,
+        symbols: &SymbolTable,
+        results: &mut Vec<u8>,
+    ) -> Result<(), AssemblerError> {
         match t {
             Token::Register { number } => {
                 results.push(*number);
             }
             Token::IntegerOperand { value } => {
+                if *value < i64::from(i16::MIN) || *value > i64::from(u16::MAX) {
+                    return Err(AssemblerError::ImmediateOutOfRange {
+                        value: *value,
+                        bits: 16,
+                    });
+                }
                 let converted = *value as u16;
                 let byte1 = converted;
                 let byte2 = converted >> 8;
                 results.push(byte2 as u8);
                 results.push(byte1 as u8);
             }
-            _ => {
-                // TODO: Proper error handling
-                println!("Opcode found in operand field");
-                std::process::exit(1);
+            Token::Expression { .. } | Token::LabelUsage { .. } => {
+                AssemblerInstruction::extract_operand(
+                    &Token::IntegerOperand {
+                        value: AssemblerInstruction::resolve(t, symbols)?,
+                    },
+                    symbols,
+                    results,
+                )?
             }
+            _ => return Err(AssemblerError::OpcodeInOperandField),
+        }
+        Ok(())
+    }
+
+    /// Whether the immediate of the instruction is sign-extended.
+    pub fn sign_extends(&self) -> bool {
+        matches!(&self.opcode, Some(Token::Op { code }) if code.sign_extends())
+    }
+
+    // The value of an operand that depends on a label.
+    fn resolve(t: &Token, symbols: &SymbolTable) -> Result<i64, AssemblerError> {
+        match t {
+            Token::Expression { expression } => expression.evaluate(symbols),
+            Token::LabelUsage { name } => match symbols.symbol_value(name) {
+                Some(offset) => Ok(i64::from(offset)),
+                None => Err(AssemblerError::UndefinedLabel { name: name.clone() }),
+            },
+            _ => Err(AssemblerError::OpcodeInOperandField),
         }
     }
 }
 
+/// Checks that `value` fits a 16-bit immediate, which takes -32768..=32767
+/// if the instruction sign-extends it and 0..=65535 otherwise.
+pub fn check_immediate(value: i64, signed: bool) -> Result<(), AssemblerError> {
+    let (min, max) = if signed {
+        (i64::from(i16::MIN), i64::from(i16::MAX))
+    } else {
+        (0, i64::from(u16::MAX))
+    };
+    if value < min || value > max {
+        return Err(AssemblerError::ImmediateOutOfRange { value, bits: 16 });
+    }
+    Ok(())
+}
+
+named!(
+    pub comment<CompleteStr, CompleteStr>,
+    recognize!(
+        preceded!(
+            alt!(tag!(";") | tag!("//")),
+            not_line_ending
+        )
+    )
+);
+
+// Trailing whitespace and comment, then the end of the line or of the input.
+named!(
+    pub line_end<CompleteStr, ()>,
+    do_parse!(
+        space0 >>
+        opt!(comment) >>
+        alt!(line_ending | eof!()) >>
+        ()
+    )
+);
+
+named!(immediate<CompleteStr, Token>,
+    alt!(integer_operand | label_usage)
+);
+
 named!(
     pub instruction_o_r_i<CompleteStr, AssemblerInstruction>,
     do_parse!(
         o: opcode >>
+        space1 >>
         r: register >>
-        i: integer_operand >>
-        opt!(multispace) >>
+        space1 >>
+        i: immediate >>
         (
             AssemblerInstruction{
-                opcode: o,
+                opcode: Some(o),
                 operand1: Some(r),
                 operand2: Some(i),
-                operand3: None,
+                ..Default::default()
             }
         )
     )
@@ -81,15 +374,47 @@ named!(
     pub instruction_o_r_r<CompleteStr, AssemblerInstruction>,
     do_parse!(
         o: opcode >>
+        space1 >>
         r1: register >>
+        space1 >>
         r2: register >>
-        opt!(multispace) >>
         (
             AssemblerInstruction{
-                opcode: o,
+                opcode: Some(o),
                 operand1: Some(r1),
                 operand2: Some(r2),
-                operand3: None,
+                ..Default::default()
+            }
+        )
+    )
+);
+
+named!(instruction_o_r<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        o: opcode >>
+        space1 >>
+        r: register >>
+        (
+            AssemblerInstruction{
+                opcode: Some(o),
+                operand1: Some(r),
+                ..Default::default()
+            }
+        )
+    )
+);
+
+// A jump straight to a label or address, e.g. `jmpc @loop`.
+named!(instruction_o_i<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        o: opcode >>
+        space1 >>
+        i: immediate >>
+        (
+            AssemblerInstruction{
+                opcode: Some(o),
+                operand1: Some(i),
+                ..Default::default()
             }
         )
     )
@@ -98,13 +423,10 @@ named!(
 named!(instruction_o<CompleteStr, AssemblerInstruction>,
     do_parse!(
         o: opcode >>
-        opt!(multispace) >>
         (
             AssemblerInstruction{
-                opcode: o,
-                operand1: None,
-                operand2: None,
-                operand3: None,
+                opcode: Some(o),
+                ..Default::default()
             }
         )
     )
@@ -113,37 +435,101 @@ named!(instruction_o<CompleteStr, AssemblerInstruction>,
 named!(instruction_o_r_r_r<CompleteStr, AssemblerInstruction>,
     do_parse!(
         o: opcode >>
+        space1 >>
         r1: register >>
+        space1 >>
         r2: register >>
+        space1 >>
         r3: register >>
-        opt!(multispace) >>
         (
             AssemblerInstruction{
-                opcode: o,
+                opcode: Some(o),
                 operand1: Some(r1),
                 operand2: Some(r2),
                 operand3: Some(r3),
+                ..Default::default()
+            }
+        )
+    )
+);
+
+// An immediate between registers, as in `addi $0 #1 $0`.
+named!(instruction_o_r_i_r<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        o: opcode >>
+        space1 >>
+        r1: register >>
+        space1 >>
+        i: immediate >>
+        space1 >>
+        r2: register >>
+        (
+            AssemblerInstruction{
+                opcode: Some(o),
+                operand1: Some(r1),
+                operand2: Some(i),
+                operand3: Some(r2),
+                ..Default::default()
+            }
+        )
+    )
+);
+
+// A branch to a label or address, as in `blt $0 $1 @loop`.
+named!(instruction_o_r_r_i<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        o: opcode >>
+        space1 >>
+        r1: register >>
+        space1 >>
+        r2: register >>
+        space1 >>
+        i: immediate >>
+        (
+            AssemblerInstruction{
+                opcode: Some(o),
+                operand1: Some(r1),
+                operand2: Some(r2),
+                operand3: Some(i),
+                ..Default::default()
             }
         )
     )
 );
 
+// A single line holding a label declaration, an instruction or both, with
+// optional indentation and a trailing comment.
 named!(
     pub instruction<CompleteStr, AssemblerInstruction>,
     do_parse!(
+        space0 >>
+        label: opt!(terminated!(label_declaration, space0)) >>
         ins: alt_complete!(
+            directive_constant |
+            directive_symbol |
             instruction_o_r_r_r |
+            instruction_o_r_r_i |
+            instruction_o_r_i_r |
             instruction_o_r_i |
             instruction_o_r_r |
-            instruction_o 
-        ) >> (ins)
+            instruction_o_r |
+            instruction_o_i |
+            instruction_o |
+            cond_reduce!(label.is_some(), value!(AssemblerInstruction::default()))
+        ) >>
+        line_end >>
+        (
+            AssemblerInstruction {
+                label,
+                ..ins
+            }
+        )
     )
 );
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::instruction::Opcode;
 
     #[test]
     fn test_parse_instruction_load() {
@@ -153,10 +539,10 @@ mod tests {
             Ok((
                 CompleteStr(""),
                 AssemblerInstruction {
-                    opcode: Token::Op { code: Opcode::LOAD },
+                    opcode: Some(Token::Op { code: Opcode::LOAD }),
                     operand1: Some(Token::Register { number: 0 }),
                     operand2: Some(Token::IntegerOperand { value: 100 }),
-                    operand3: None
+                    ..Default::default()
                 }
             ))
         );
@@ -170,10 +556,8 @@ mod tests {
             Ok((
                 CompleteStr(""),
                 AssemblerInstruction {
-                    opcode: Token::Op { code: Opcode::HLT },
-                    operand1: None,
-                    operand2: None,
-                    operand3: None
+                    opcode: Some(Token::Op { code: Opcode::HLT }),
+                    ..Default::default()
                 }
             ))
         );
@@ -187,10 +571,11 @@ mod tests {
             Ok((
                 CompleteStr(""),
                 AssemblerInstruction {
-                    opcode: Token::Op { code: Opcode::MUL },
+                    opcode: Some(Token::Op { code: Opcode::MUL }),
                     operand1: Some(Token::Register { number: 1 }),
                     operand2: Some(Token::Register { number: 2 }),
                     operand3: Some(Token::Register { number: 3 }),
+                    ..Default::default()
                 }
             ))
         );
@@ -203,11 +588,269 @@ mod tests {
         assert_eq!(
             instruction,
             AssemblerInstruction {
-                opcode: Token::Op { code: Opcode::LT },
+                opcode: Some(Token::Op { code: Opcode::LT }),
                 operand1: Some(Token::Register { number: 1 }),
                 operand2: Some(Token::Register { number: 2 }),
-                operand3: None,
+                ..Default::default()
             }
         )
     }
+
+    #[test]
+    fn test_parse_instruction_jmp() {
+        let result = instruction(CompleteStr("jmp $4"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                AssemblerInstruction {
+                    opcode: Some(Token::Op { code: Opcode::JMP }),
+                    operand1: Some(Token::Register { number: 4 }),
+                    ..Default::default()
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_instruction_immediate_forms() {
+        let (_rest, parsed) = instruction(CompleteStr("addi $1 #-1 $2 ; dec\n")).unwrap();
+        assert_eq!(
+            parsed,
+            AssemblerInstruction {
+                opcode: Some(Token::Op { code: Opcode::ADDI }),
+                operand1: Some(Token::Register { number: 1 }),
+                operand2: Some(Token::IntegerOperand { value: -1 }),
+                operand3: Some(Token::Register { number: 2 }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            parsed.to_bytes(&SymbolTable::new()),
+            Ok(vec![17, 1, 0xFF, 0xFF, 2])
+        );
+
+        let (_rest, parsed) = instruction(CompleteStr("blt $1 $2 @loop\n")).unwrap();
+        assert_eq!(
+            parsed.operand3,
+            Some(Token::LabelUsage {
+                name: "loop".to_string()
+            })
+        );
+        assert_eq!(parsed.relocations().len(), 1);
+        assert_eq!(parsed.relocations()[0].0, 3);
+    }
+
+    #[test]
+    fn test_parse_instruction_whitespace_and_comments() {
+        let result = instruction(CompleteStr("\t  add\t$1  $2 $3   ; $3 = $1 + $2\r\nhlt"));
+        let (rest, parsed) = result.unwrap();
+        assert_eq!(rest, CompleteStr("hlt"));
+        assert_eq!(parsed.operand3, Some(Token::Register { number: 3 }));
+
+        let (rest, _) = instruction(CompleteStr("hlt// done")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+    }
+
+    #[test]
+    fn test_parse_instruction_trailing_garbage() {
+        assert_eq!(instruction(CompleteStr("add $1 $2 x\n")).is_ok(), false);
+        assert_eq!(instruction(CompleteStr("hlt $1 $2 $3 $4\n")).is_ok(), false);
+        assert_eq!(
+            instruction(CompleteStr("   ; just a comment\n")).is_ok(),
+            false
+        );
+    }
+
+    #[test]
+    fn test_parse_instruction_labels() {
+        let (_rest, parsed) = instruction(CompleteStr("loop: load $7 @loop\n")).unwrap();
+        assert_eq!(parsed.label_name(), Some("loop"));
+        assert_eq!(
+            parsed.operand2,
+            Some(Token::LabelUsage {
+                name: "loop".to_string()
+            })
+        );
+
+        let (_rest, parsed) = instruction(CompleteStr("  end:   ; nothing else\n")).unwrap();
+        assert_eq!(parsed.label_name(), Some("end"));
+        assert_eq!(parsed.opcode, None);
+        assert_eq!(parsed.size(), 0);
+    }
+
+    #[test]
+    fn test_parse_instruction_directive() {
+        let (_rest, parsed) = instruction(CompleteStr("  .equ SIZE 16 ; bytes\n")).unwrap();
+        assert_eq!(
+            parsed.directive,
+            Some(Token::Directive {
+                name: "equ".to_string()
+            })
+        );
+        assert_eq!(parsed.size(), 0);
+    }
+
+    #[test]
+    fn test_expression_to_bytes() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol("start", 4);
+        symbols.add_symbol("end", 20);
+        let (_rest, parsed) = instruction(CompleteStr("load $1 #((end - start) / 4)\n")).unwrap();
+        assert_eq!(parsed.size(), 4);
+        assert_eq!(parsed.to_bytes(&symbols), Ok(vec![1, 1, 0, 4]));
+    }
+
+    #[test]
+    fn test_label_to_bytes() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol("loop", 300);
+        let (_rest, parsed) = instruction(CompleteStr("load $7 @loop\n")).unwrap();
+        assert_eq!(parsed.to_bytes(&symbols), Ok(vec![1, 7, 1, 44]));
+        assert_eq!(
+            parsed.to_bytes(&SymbolTable::new()),
+            Err(AssemblerError::UndefinedLabel {
+                name: "loop".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_small_value_to_bytes() {
+        let (_rest, parsed) = instruction(CompleteStr("load $2 #0xFFFF\n")).unwrap();
+        assert_eq!(parsed.size(), 4);
+        assert_eq!(
+            parsed.to_bytes(&SymbolTable::new()),
+            Ok(vec![1, 2, 255, 255])
+        );
+    }
+
+    #[test]
+    fn test_load_large_value_expands_to_lui() {
+        let (_rest, parsed) = instruction(CompleteStr("load $2 #70000\n")).unwrap();
+        assert_eq!(parsed.size(), 8);
+        assert_eq!(
+            parsed.to_bytes(&SymbolTable::new()),
+            Ok(vec![
+                1, 2, 0x11, 0x70, // load $2 #0x1170
+                16, 2, 0, 1, //      lui $2 #1
+            ])
+        );
+        let (_rest, parsed) = instruction(CompleteStr("load $0 #-1\n")).unwrap();
+        assert_eq!(
+            parsed.to_bytes(&SymbolTable::new()),
+            Ok(vec![1, 0, 255, 255, 16, 0, 255, 255])
+        );
+    }
+
+    #[test]
+    fn test_instruction_to_words() {
+        let symbols = SymbolTable::new();
+        let words = |source: &str| {
+            let (_, parsed) = instruction(CompleteStr(source)).unwrap();
+            assert_eq!(parsed.size_in(Encoding::Fixed), 4);
+            parsed.to_words(&symbols)
+        };
+        assert_eq!(words("add $1 $2 $3\n"), Ok(vec![2, 0x08, 0x86, 0]));
+        assert_eq!(words("jmp $31\n"), Ok(vec![6, 0xF8, 0, 0]));
+        assert_eq!(words("subi $1 #-1 $2\n"), Ok(vec![18, 0x08, 0xBF, 0xFF]));
+        assert_eq!(
+            words("subi $1 #8192 $2\n"),
+            Err(AssemblerError::ImmediateOutOfRange {
+                value: 8192,
+                bits: 14
+            })
+        );
+        assert_eq!(
+            words("beq $1 $2 #16384\n"),
+            Err(AssemblerError::ImmediateOutOfRange {
+                value: 16384,
+                bits: 14
+            })
+        );
+        // The parser never gives a register past $31, but an instruction
+        // built by hand can.
+        let (_, mut parsed) = instruction(CompleteStr("load $1 #1\n")).unwrap();
+        parsed.operand1 = Some(Token::Register { number: 32 });
+        assert_eq!(
+            parsed.to_words(&symbols),
+            Err(AssemblerError::RegisterOutOfRange { number: 32 })
+        );
+
+        // A large `load` is two words.
+        let (_, parsed) = instruction(CompleteStr("load $2 #100000\n")).unwrap();
+        assert_eq!(parsed.size_in(Encoding::Fixed), 8);
+        assert_eq!(
+            parsed.to_words(&symbols),
+            Ok(vec![1, 0x10, 0x86, 0xA0, 16, 0x10, 0, 1])
+        );
+    }
+
+    #[test]
+    fn test_operands_checked_against_instruction_set() {
+        let symbols = SymbolTable::new();
+        let (_rest, parsed) = instruction(CompleteStr("nop\n")).unwrap();
+        assert_eq!(parsed.to_bytes(&symbols), Ok(vec![255]));
+        let (_rest, parsed) = instruction(CompleteStr("jmp $0 $1\n")).unwrap();
+        assert_eq!(
+            parsed.to_bytes(&symbols),
+            Err(AssemblerError::InvalidOperands {
+                mnemonic: "jmp".to_string()
+            })
+        );
+        let (_rest, parsed) = instruction(CompleteStr("load $0 $1\n")).unwrap();
+        assert!(parsed.to_bytes(&symbols).is_err());
+        let (_rest, parsed) = instruction(CompleteStr("aold $1\n")).unwrap();
+        assert_eq!(
+            parsed.to_bytes(&symbols),
+            Err(AssemblerError::UnknownInstruction {
+                mnemonic: "aold".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_immediate_out_of_range() {
+        let (_rest, parsed) = instruction(CompleteStr("load $0 #0x100000000\n")).unwrap();
+        assert_eq!(
+            parsed.to_bytes(&SymbolTable::new()),
+            Err(AssemblerError::ImmediateOutOfRange {
+                value: 0x1_0000_0000,
+                bits: 32
+            })
+        );
+        let (_rest, parsed) = instruction(CompleteStr("lui $0 #70000\n")).unwrap();
+        assert_eq!(
+            parsed.to_bytes(&SymbolTable::new()),
+            Err(AssemblerError::ImmediateOutOfRange {
+                value: 70000,
+                bits: 16
+            })
+        );
+        // Signed immediates stop at 32767.
+        let (_rest, parsed) = instruction(CompleteStr("muli $0 #40000 $0\n")).unwrap();
+        assert_eq!(
+            parsed.to_bytes(&SymbolTable::new()),
+            Err(AssemblerError::ImmediateOutOfRange {
+                value: 40000,
+                bits: 16
+            })
+        );
+        // Addresses can't be negative.
+        for source in ["beq $0 $1 #-1\n", "spawn $0 #-1\n", "setvec $0 #-1\n"] {
+            let (_rest, parsed) = instruction(CompleteStr(source)).unwrap();
+            assert_eq!(
+                parsed.to_bytes(&SymbolTable::new()),
+                Err(AssemblerError::ImmediateOutOfRange {
+                    value: -1,
+                    bits: 16
+                })
+            );
+        }
+        let (_rest, parsed) = instruction(CompleteStr("bltq $0 $1 #65535\n")).unwrap();
+        assert_eq!(
+            parsed.to_bytes(&SymbolTable::new()),
+            Ok(vec![26, 0, 1, 255, 255])
+        );
+    }
 }
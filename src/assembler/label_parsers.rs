@@ -0,0 +1,68 @@
+use crate::assembler::Token;
+use nom::types::CompleteStr;
+use nom::*;
+
+named!(
+    pub identifier<CompleteStr, CompleteStr>,
+    recognize!(
+        pair!(
+            one_of!("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz_"),
+            take_while!(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        )
+    )
+);
+
+named!(
+    pub label_declaration<CompleteStr, Token>,
+    do_parse!(
+        name: identifier >>
+        char!(':') >>
+        (
+            Token::LabelDeclaration { name: name.to_string() }
+        )
+    )
+);
+
+named!(
+    pub label_usage<CompleteStr, Token>,
+    do_parse!(
+        char!('@') >>
+        name: identifier >>
+        (
+            Token::LabelUsage { name: name.to_string() }
+        )
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_label_declaration() {
+        let result = label_declaration(CompleteStr("loop_2:"));
+        let (rest, token) = result.unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(
+            token,
+            Token::LabelDeclaration {
+                name: "loop_2".to_string()
+            }
+        );
+        assert_eq!(label_declaration(CompleteStr("loop")).is_ok(), false);
+        assert_eq!(label_declaration(CompleteStr("2loop:")).is_ok(), false);
+    }
+
+    #[test]
+    fn test_parse_label_usage() {
+        let (rest, token) = label_usage(CompleteStr("@end ; done")).unwrap();
+        assert_eq!(rest, CompleteStr(" ; done"));
+        assert_eq!(
+            token,
+            Token::LabelUsage {
+                name: "end".to_string()
+            }
+        );
+        assert_eq!(label_usage(CompleteStr("end")).is_ok(), false);
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+
+use crate::assembler::program_parsers::EncodedInstruction;
+use crate::assembler::source::{Location, SourceLine};
+use crate::linker::Section;
+
+// Bytes shown on each row of a listing; longer encodings wrap.
+const BYTES_PER_ROW: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListingRow {
+    pub offset: Option<u32>,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+/// Every source line next to the address and bytes it assembled to. Lines
+/// produced by a macro are shown below the call, marked with a `+`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Listing {
+    pub rows: Vec<ListingRow>,
+}
+
+// The line that the outermost macro call was made from.
+fn call_site(location: &Location) -> &Location {
+    match &location.expansion {
+        Some(expansion) => call_site(&expansion.call_site),
+        None => location,
+    }
+}
+
+impl Listing {
+    /// Builds a listing from the source as written (after includes), the
+    /// same source after macro expansion and the encoded instructions.
+    pub fn new(
+        source: &[SourceLine],
+        expanded: &[SourceLine],
+        instructions: &[EncodedInstruction],
+    ) -> Listing {
+        let mut rows = vec![];
+        let mut expanded = expanded.iter().peekable();
+        let mut instructions = instructions.iter().peekable();
+
+        for line in source {
+            let mut texts = vec![];
+            while let Some(next) = expanded.peek() {
+                if next.location == line.location {
+                    texts.push((line.text.clone(), &next.location));
+                } else if next.location.expansion.is_some()
+                    && call_site(&next.location) == &line.location
+                {
+                    texts.push((format!("+ {}", next.text.trim()), &next.location));
+                } else {
+                    break;
+                }
+                expanded.next();
+            }
+            // Lines that produced nothing themselves, like comments or macro
+            // calls, are listed on their own.
+            if texts
+                .first()
+                .is_none_or(|(_, location)| *location != &line.location)
+            {
+                rows.push(ListingRow {
+                    offset: None,
+                    bytes: vec![],
+                    text: line.text.clone(),
+                });
+            }
+
+            for (text, location) in texts {
+                let first = rows.len();
+                while let Some(instruction) = instructions.peek() {
+                    if &instruction.location != location {
+                        break;
+                    }
+                    // A label on a line of its own still shows its address.
+                    if instruction.bytes.is_empty() && instruction.label.is_some() {
+                        rows.push(ListingRow {
+                            offset: Some(instruction.offset),
+                            bytes: vec![],
+                            text: String::new(),
+                        });
+                    }
+                    for (i, chunk) in instruction.bytes.chunks(BYTES_PER_ROW).enumerate() {
+                        rows.push(ListingRow {
+                            offset: Some(instruction.offset + (i * BYTES_PER_ROW) as u32),
+                            bytes: chunk.to_vec(),
+                            text: String::new(),
+                        });
+                    }
+                    instructions.next();
+                }
+                if rows.len() == first {
+                    rows.push(ListingRow {
+                        offset: None,
+                        bytes: vec![],
+                        text: String::new(),
+                    });
+                }
+                rows[first].text = text;
+            }
+        }
+        Listing { rows }
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in &self.rows {
+            let offset = match row.offset {
+                Some(offset) => format!("{:5}", offset),
+                None => " ".repeat(5),
+            };
+            let bytes: Vec<String> = row.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let line = format!(
+                "{}  {:width$}  {}",
+                offset,
+                bytes.join(" "),
+                row.text,
+                width = BYTES_PER_ROW * 3 - 1
+            );
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapLabel {
+    pub name: String,
+    pub address: u32,
+    pub section: String,
+}
+
+/// The layout of a program: where each section starts and how large it is,
+/// and the address of every label.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MapFile {
+    pub sections: Vec<Section>,
+    pub labels: Vec<MapLabel>,
+}
+
+impl MapFile {
+    pub fn new(sections: Vec<Section>, mut labels: Vec<MapLabel>) -> MapFile {
+        labels.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+        MapFile { sections, labels }
+    }
+}
+
+impl fmt::Display for MapFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Sections")?;
+        writeln!(f, "{:>7}  {:>7}  name", "base", "size")?;
+        for section in &self.sections {
+            writeln!(
+                f,
+                "{:7}  {:7}  {}",
+                section.base, section.size, section.module
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Labels")?;
+        writeln!(f, "{:>7}  {:<20}  section", "address", "name")?;
+        for label in &self.labels {
+            let line = format!("{:7}  {:<20}  {}", label.address, label.name, label.section);
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_listing() {
+        let source = "; start\n\
+                      .macro twice r\n\
+                      \tadd \\r \\r \\r\n\
+                      \tadd \\r \\r \\r\n\
+                      .endm\n\
+                      top:\n\
+                      \tli $0 #70000\n\
+                      \ttwice $0\n\
+                      \tjmp @top\n";
+        let assembly = Assembler::new().assemble_detailed(source).unwrap();
+        let listing = assembly.listing.to_string();
+        assert_eq!(
+            listing.lines().collect::<Vec<_>>(),
+            vec![
+                "                    ; start",
+                "                    .macro twice r",
+                "                    \tadd \\r \\r \\r",
+                "                    \tadd \\r \\r \\r",
+                "                    .endm",
+                "    0               top:",
+                "    0  01 00 11 70  \tli $0 #70000",
+                "    4  10 00 00 01",
+                "                    \ttwice $0",
+                "    8  02 00 00 00  + add $0 $0 $0",
+                "   12  02 00 00 00  + add $0 $0 $0",
+                "   16  01 1f 00 00  \tjmp @top",
+                "   20  06 1f",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_map_file() {
+        let source = "start: load $0 #1\nloop: add $0 $0 $0\n\tjmpc @loop\nend: hlt\n";
+        let assembly = Assembler::new().assemble_detailed(source).unwrap();
+        let map = assembly.map.to_string();
+        assert_eq!(
+            map.lines().collect::<Vec<_>>(),
+            vec![
+                "Sections",
+                "   base     size  name",
+                "      0       15  <input>",
+                "",
+                "Labels",
+                "address  name                  section",
+                "      0  start                 <input>",
+                "      4  loop                  <input>",
+                "     14  end                   <input>",
+            ]
+        );
+    }
+}
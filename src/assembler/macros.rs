@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use nom::types::CompleteStr;
+use nom::*;
+
+use crate::assembler::instruction_parsers::line_end;
+use crate::assembler::label_parsers::{identifier, label_declaration};
+use crate::assembler::source::{comment_start, Location, SourceLine};
+use crate::assembler::{AssemblerError, Diagnostic, Token};
+
+/// Expansions nested deeper than this are assumed to be runaway recursion.
+pub const MAX_EXPANSION_DEPTH: usize = 64;
+
+#[derive(Debug, PartialEq)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<SourceLine>,
+}
+
+#[derive(Debug, PartialEq)]
+struct MacroCall {
+    label: Option<String>,
+    name: String,
+    args: Vec<String>,
+}
+
+named!(param_separator<CompleteStr, CompleteStr>,
+    alt!(delimited!(space0, tag!(","), space0) | space1)
+);
+
+named!(macro_start<CompleteStr, (CompleteStr, Vec<CompleteStr>)>,
+    do_parse!(
+        space0 >>
+        tag!(".macro") >>
+        space1 >>
+        name: identifier >>
+        params: opt!(preceded!(space1, separated_list!(param_separator, identifier))) >>
+        line_end >>
+        (name, params.unwrap_or_default())
+    )
+);
+
+named!(macro_end<CompleteStr, ()>,
+    do_parse!(
+        space0 >>
+        tag!(".endm") >>
+        line_end >>
+        ()
+    )
+);
+
+named!(call_head<CompleteStr, (Option<Token>, CompleteStr)>,
+    do_parse!(
+        space0 >>
+        label: opt!(terminated!(label_declaration, space0)) >>
+        name: identifier >>
+        (label, name)
+    )
+);
+
+/// Splits `text` wherever `separates` holds for a character that is neither
+/// inside a character literal nor inside parentheses.
+fn split_top_level<F>(text: &str, separates: F) -> Vec<&str>
+where
+    F: Fn(char) -> bool,
+{
+    let mut pieces = vec![];
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            _ if quoted => {}
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ if depth == 0 && separates(c) => {
+                pieces.push(&text[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    pieces.push(&text[start..]);
+    pieces
+}
+
+// Arguments are separated like parameters, by a comma or by spaces.
+fn split_arguments(text: &str) -> Vec<String> {
+    let mut args = vec![];
+    for field in split_top_level(text, |c| c == ',') {
+        let field = field.trim();
+        if field.is_empty() {
+            args.push(String::new());
+            continue;
+        }
+        let words = split_top_level(field, char::is_whitespace);
+        args.extend(
+            words
+                .into_iter()
+                .filter(|word| !word.is_empty())
+                .map(str::to_string),
+        );
+    }
+    args
+}
+
+fn parse_call(text: &str) -> Option<MacroCall> {
+    let (rest, (label, name)) = call_head(CompleteStr(text)).ok()?;
+    let rest = &rest.0[..comment_start(rest.0).unwrap_or(rest.0.len())];
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim();
+    let args = if rest.is_empty() {
+        vec![]
+    } else {
+        split_arguments(rest)
+    };
+    let label = match label {
+        Some(Token::LabelDeclaration { name }) => Some(name),
+        _ => None,
+    };
+    Some(MacroCall {
+        label,
+        name: name.to_string(),
+        args,
+    })
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Calls `f` with every identifier outside character literals, along with
+/// the character preceding it, and splices in whatever it returns.
+fn map_identifiers<F>(text: &str, mut f: F) -> Result<String, AssemblerError>
+where
+    F: FnMut(Option<char>, &str) -> Result<Option<String>, AssemblerError>,
+{
+    let mut result = String::new();
+    let mut chars = text.char_indices().peekable();
+    let mut previous = None;
+    let mut quoted = false;
+    while let Some((start, c)) = chars.next() {
+        if quoted || c == '\'' {
+            if c == '\\' && quoted {
+                result.push(c);
+                if let Some((_, escaped)) = chars.next() {
+                    result.push(escaped);
+                }
+                continue;
+            }
+            if c == '\'' {
+                quoted = !quoted;
+            }
+            result.push(c);
+            previous = Some(c);
+            continue;
+        }
+        let starts_identifier =
+            (c.is_ascii_alphabetic() || c == '_') && !previous.is_some_and(is_identifier_char);
+        if !starts_identifier {
+            result.push(c);
+            previous = Some(c);
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        while let Some(&(i, next)) = chars.peek() {
+            if !is_identifier_char(next) {
+                break;
+            }
+            end = i + next.len_utf8();
+            chars.next();
+        }
+        let word = &text[start..end];
+        match f(previous, word)? {
+            Some(replacement) => result.push_str(&replacement),
+            None => result.push_str(word),
+        }
+        previous = word.chars().last();
+    }
+    Ok(result)
+}
+
+named!(declared_label<CompleteStr, Token>,
+    preceded!(space0, label_declaration)
+);
+
+fn local_labels(body: &[SourceLine]) -> Vec<String> {
+    body.iter()
+        .filter_map(|line| match declared_label(CompleteStr(&line.text)) {
+            Ok((_, Token::LabelDeclaration { name })) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Renames labels declared inside a macro body so that every expansion gets
+/// its own copy, then substitutes `\param` references with the arguments.
+fn expand_text(
+    text: &str,
+    mac: &Macro,
+    locals: &[String],
+    args: &[String],
+    expansion: usize,
+) -> Result<String, AssemblerError> {
+    let renamed = map_identifiers(text, |previous, word| {
+        if previous != Some('\\') && locals.iter().any(|local| local == word) {
+            Ok(Some(format!("{}.{}", word, expansion)))
+        } else {
+            Ok(None)
+        }
+    })?;
+
+    let substituted = map_identifiers(&renamed, |previous, word| {
+        if previous != Some('\\') {
+            return Ok(None);
+        }
+        match mac.params.iter().position(|param| param == word) {
+            Some(i) => Ok(Some(args[i].clone())),
+            None => Err(AssemblerError::UnknownMacroParameter {
+                name: word.to_string(),
+            }),
+        }
+    })?;
+
+    Ok(strip_backslashes(&substituted))
+}
+
+// Drops the `\` that introduced each parameter reference; escapes inside
+// character literals are left alone.
+fn strip_backslashes(text: &str) -> String {
+    let mut result = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        if quoted {
+            if c == '\'' && !escaped {
+                quoted = false;
+            }
+            escaped = c == '\\' && !escaped;
+            result.push(c);
+        } else if c == '\'' {
+            quoted = true;
+            result.push(c);
+        } else if c != '\\' {
+            result.push(c);
+        }
+    }
+    result
+}
+
+#[derive(Debug, Default)]
+pub struct MacroExpander {
+    macros: HashMap<String, Rc<Macro>>,
+    expansions: usize,
+}
+
+impl MacroExpander {
+    pub fn new() -> MacroExpander {
+        MacroExpander {
+            macros: HashMap::new(),
+            expansions: 0,
+        }
+    }
+
+    /// Collects `.macro` definitions and replaces every call with the macro
+    /// body. A macro has to be defined before it is used.
+    pub fn expand(&mut self, lines: Vec<SourceLine>) -> Result<Vec<SourceLine>, Vec<Diagnostic>> {
+        let mut output = vec![];
+        let mut errors = vec![];
+        let mut lines = lines.into_iter();
+
+        while let Some(line) = lines.next() {
+            if let Ok((_, (name, params))) = macro_start(CompleteStr(&line.text)) {
+                let mac = Macro {
+                    name: name.to_string(),
+                    params: params.iter().map(|param| param.to_string()).collect(),
+                    body: vec![],
+                };
+                self.define(mac, &line.location, &mut lines, &mut errors);
+            } else if macro_end(CompleteStr(&line.text)).is_ok() {
+                errors.push(Diagnostic::new(
+                    line.location,
+                    AssemblerError::UnexpectedEndm,
+                ));
+            } else {
+                let location = line.location.clone();
+                if let Err(error) = self.expand_line(line, 0, &mut output, &mut errors) {
+                    errors.push(Diagnostic::new(location, error));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(output)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn define<I>(
+        &mut self,
+        mut mac: Macro,
+        location: &Location,
+        lines: &mut I,
+        errors: &mut Vec<Diagnostic>,
+    ) where
+        I: Iterator<Item = SourceLine>,
+    {
+        loop {
+            match lines.next() {
+                None => {
+                    errors.push(Diagnostic::new(
+                        location.clone(),
+                        AssemblerError::UnterminatedMacro { name: mac.name },
+                    ));
+                    return;
+                }
+                Some(line) => {
+                    if macro_end(CompleteStr(&line.text)).is_ok() {
+                        break;
+                    }
+                    if macro_start(CompleteStr(&line.text)).is_ok() {
+                        errors.push(Diagnostic::new(
+                            line.location,
+                            AssemblerError::NestedMacroDefinition,
+                        ));
+                        continue;
+                    }
+                    mac.body.push(line);
+                }
+            }
+        }
+
+        if self.macros.contains_key(&mac.name) {
+            errors.push(Diagnostic::new(
+                location.clone(),
+                AssemblerError::DuplicateMacro { name: mac.name },
+            ));
+        } else {
+            self.macros.insert(mac.name.clone(), Rc::new(mac));
+        }
+    }
+
+    // Errors that stop the whole expansion, like runaway recursion, are
+    // returned; everything else is collected in `errors`.
+    fn expand_line(
+        &mut self,
+        line: SourceLine,
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+        errors: &mut Vec<Diagnostic>,
+    ) -> Result<(), AssemblerError> {
+        let call = match parse_call(&line.text) {
+            Some(call) if self.macros.contains_key(&call.name) => call,
+            _ => {
+                output.push(line);
+                return Ok(());
+            }
+        };
+        let mac = Rc::clone(&self.macros[&call.name]);
+
+        if depth >= MAX_EXPANSION_DEPTH {
+            return Err(AssemblerError::MacroRecursionLimit { name: call.name });
+        }
+        if call.args.len() != mac.params.len() {
+            errors.push(Diagnostic::new(
+                line.location,
+                AssemblerError::MacroArgumentCount {
+                    name: call.name,
+                    expected: mac.params.len(),
+                    found: call.args.len(),
+                },
+            ));
+            return Ok(());
+        }
+
+        if let Some(label) = call.label {
+            output.push(SourceLine {
+                text: format!("{}:", label),
+                location: line.location.clone(),
+            });
+        }
+
+        self.expansions += 1;
+        let expansion = self.expansions;
+        let locals = local_labels(&mac.body);
+        for body_line in &mac.body {
+            let location = body_line.location.expanded(&mac.name, &line.location);
+            match expand_text(&body_line.text, &mac, &locals, &call.args, expansion) {
+                Ok(text) => {
+                    self.expand_line(SourceLine { text, location }, depth + 1, output, errors)?
+                }
+                Err(error) => errors.push(Diagnostic::new(location, error)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::source::source_lines;
+
+    fn expand(source: &str) -> Result<Vec<String>, Vec<Diagnostic>> {
+        let lines = MacroExpander::new().expand(source_lines(source))?;
+        Ok(lines.into_iter().map(|line| line.text).collect())
+    }
+
+    #[test]
+    fn test_expand_parameters() {
+        let result = expand(
+            ".macro mov from, to   ; copy a register\n\
+             \tadd \\from $31 \\to\n\
+             .endm\n\
+             start: mov $1, $2\n\
+             hlt",
+        );
+        assert_eq!(
+            result,
+            Ok(vec![
+                "start:".to_string(),
+                "\tadd $1 $31 $2".to_string(),
+                "hlt".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_expand_argument_separators() {
+        let source = ".macro pair a b\n\tload \\a \\b\n.endm\n\
+                      pair $1 #2\n\
+                      pair $1 , #','\n\
+                      pair $1   #(2 + 3) ; two arguments\n\
+                      pair $1, #' '\n";
+        assert_eq!(
+            expand(source),
+            Ok(vec![
+                "\tload $1 #2".to_string(),
+                "\tload $1 #','".to_string(),
+                "\tload $1 #(2 + 3)".to_string(),
+                "\tload $1 #' '".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_expand_local_labels() {
+        let source = ".macro spin counter\n\
+                      again: load $30 @again\n\
+                      jmpc $30 ; '\\n' is left alone\n\
+                      .endm\n\
+                      spin $1\n\
+                      spin $2\n";
+        let lines = expand(source).unwrap();
+        assert_eq!(lines[0], "again.1: load $30 @again.1");
+        assert_eq!(lines[1], "jmpc $30 ; '\\n' is left alone");
+        assert_eq!(lines[2], "again.2: load $30 @again.2");
+    }
+
+    #[test]
+    fn test_expand_nested_locations() {
+        let source = ".macro inc r\n\
+                      add \\r $30 \\r\n\
+                      .endm\n\
+                      .macro inc2 r\n\
+                      inc \\r\n\
+                      inc \\r\n\
+                      .endm\n\
+                      inc2 $4\n";
+        let lines = MacroExpander::new().expand(source_lines(source)).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].text, "add $4 $30 $4");
+        assert_eq!(
+            lines[1].location.to_string(),
+            "line 2, in macro `inc` expanded at line 6, in macro `inc2` expanded at line 8"
+        );
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let errors = expand(".macro f a\nhlt\n.endm\nf\nf $1, $2\n").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                Diagnostic::new(
+                    Location::new(4),
+                    AssemblerError::MacroArgumentCount {
+                        name: "f".to_string(),
+                        expected: 1,
+                        found: 0
+                    }
+                ),
+                Diagnostic::new(
+                    Location::new(5),
+                    AssemblerError::MacroArgumentCount {
+                        name: "f".to_string(),
+                        expected: 1,
+                        found: 2
+                    }
+                ),
+            ]
+        );
+
+        let errors = expand(".macro f\nload $0 #\\x\n.endm\nf\n").unwrap_err();
+        assert_eq!(
+            errors[0].error,
+            AssemblerError::UnknownMacroParameter {
+                name: "x".to_string()
+            }
+        );
+        assert_eq!(
+            errors[0].location,
+            Location::new(2).expanded("f", &Location::new(4))
+        );
+
+        let errors = expand(".macro f\nhlt\n").unwrap_err();
+        assert_eq!(
+            errors[0].error,
+            AssemblerError::UnterminatedMacro {
+                name: "f".to_string()
+            }
+        );
+        assert_eq!(
+            expand("hlt\n.endm\n").unwrap_err()[0].error,
+            AssemblerError::UnexpectedEndm
+        );
+    }
+
+    #[test]
+    fn test_expand_recursion_limit() {
+        let errors = expand(".macro forever\nforever\nforever\n.endm\nhlt\nforever\n").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![Diagnostic::new(
+                Location::new(6),
+                AssemblerError::MacroRecursionLimit {
+                    name: "forever".to_string()
+                }
+            )]
+        );
+    }
+}
@@ -1,14 +1,427 @@
-use crate::instruction::Opcode;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
+use crate::assembler::expressions::Expression;
+use crate::assembler::includes::IncludeExpander;
+use crate::assembler::listing::{Listing, MapFile, MapLabel};
+use crate::assembler::macros::MacroExpander;
+use crate::assembler::optimizer::Optimization;
+use crate::assembler::program_parsers::{parse_lines, Program};
+use crate::assembler::pseudo::PseudoOp;
+use crate::assembler::source::{source_lines, Location, SourceLine};
+use crate::debug_info::DebugInfo;
+use crate::instruction::{Encoding, Opcode};
+use crate::linker::object::ObjectFile;
+use crate::linker::Section;
+use crate::vm::custom::CustomInstructions;
+
+pub mod directive_parsers;
+pub mod expressions;
+pub mod includes;
 pub mod instruction_parsers;
+pub mod label_parsers;
+pub mod listing;
+pub mod macros;
 pub mod opcode_parsers;
 pub mod operand_parsers;
+pub mod optimizer;
 pub mod program_parsers;
+pub mod pseudo;
 pub mod register_parsers;
+pub mod source;
+pub mod symbols;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
-    Op { code: Opcode },
-    Register { number: u8 },
-    IntegerOperand { value: i32 },
+    Op {
+        code: Opcode,
+    },
+    PseudoOp {
+        code: PseudoOp,
+    },
+    /// A mnemonic that isn't built in, until it is looked up among the
+    /// custom instructions.
+    CustomOp {
+        mnemonic: String,
+    },
+    Register {
+        number: u8,
+    },
+    IntegerOperand {
+        value: i64,
+    },
+    LabelDeclaration {
+        name: String,
+    },
+    LabelUsage {
+        name: String,
+    },
+    Directive {
+        name: String,
+    },
+    ConstantDeclaration {
+        name: String,
+    },
+    Expression {
+        expression: Expression,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AssemblerError {
+    ParseError,
+    NonOpcodeInOpcodeField,
+    OpcodeInOperandField,
+    ImmediateOutOfRange {
+        value: i64,
+        bits: u8,
+    },
+    UndefinedLabel {
+        name: String,
+    },
+    DuplicateLabel {
+        name: String,
+    },
+    UnterminatedMacro {
+        name: String,
+    },
+    UnexpectedEndm,
+    NestedMacroDefinition,
+    DuplicateMacro {
+        name: String,
+    },
+    UnknownMacroParameter {
+        name: String,
+    },
+    MacroArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    MacroRecursionLimit {
+        name: String,
+    },
+    IncludeNotFound {
+        path: String,
+    },
+    IncludeCycle {
+        path: String,
+    },
+    IncludeReadFailed {
+        path: String,
+        reason: String,
+    },
+    UndefinedSymbol {
+        name: String,
+    },
+    ConstantRedefined {
+        name: String,
+    },
+    ExpressionOverflow,
+    DivisionByZero,
+    InvalidOperands {
+        mnemonic: String,
+    },
+    UnknownInstruction {
+        mnemonic: String,
+    },
+    RegisterOutOfRange {
+        number: u8,
+    },
+    ScratchRegisterOperand {
+        mnemonic: String,
+    },
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerError::ParseError => write!(f, "unable to parse line"),
+            AssemblerError::NonOpcodeInOpcodeField => write!(f, "non-opcode found in opcode field"),
+            AssemblerError::OpcodeInOperandField => write!(f, "opcode found in operand field"),
+            AssemblerError::ImmediateOutOfRange { value, bits } => {
+                write!(
+                    f,
+                    "value {} does not fit in a {}-bit immediate",
+                    value, bits
+                )
+            }
+            AssemblerError::UndefinedLabel { name } => write!(f, "undefined label `{}`", name),
+            AssemblerError::DuplicateLabel { name } => {
+                write!(f, "label `{}` is already declared", name)
+            }
+            AssemblerError::UnterminatedMacro { name } => {
+                write!(f, "macro `{}` has no matching `.endm`", name)
+            }
+            AssemblerError::UnexpectedEndm => write!(f, "`.endm` outside of a macro definition"),
+            AssemblerError::NestedMacroDefinition => {
+                write!(f, "macros cannot be defined inside other macros")
+            }
+            AssemblerError::DuplicateMacro { name } => {
+                write!(f, "macro `{}` is already defined", name)
+            }
+            AssemblerError::UnknownMacroParameter { name } => {
+                write!(f, "unknown macro parameter `{}`", name)
+            }
+            AssemblerError::MacroArgumentCount {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "macro `{}` takes {} argument(s) but {} were given",
+                name, expected, found
+            ),
+            AssemblerError::MacroRecursionLimit { name } => write!(
+                f,
+                "expansion of macro `{}` nests more than {} levels deep",
+                name,
+                macros::MAX_EXPANSION_DEPTH
+            ),
+            AssemblerError::IncludeNotFound { path } => {
+                write!(f, "could not find included file `{}`", path)
+            }
+            AssemblerError::IncludeCycle { path } => {
+                write!(f, "`{}` is already being included", path)
+            }
+            AssemblerError::IncludeReadFailed { path, reason } => {
+                write!(f, "could not read `{}`: {}", path, reason)
+            }
+            AssemblerError::UndefinedSymbol { name } => write!(f, "undefined symbol `{}`", name),
+            AssemblerError::ConstantRedefined { name } => {
+                write!(f, "constant `{}` is already defined", name)
+            }
+            AssemblerError::ExpressionOverflow => {
+                write!(f, "expression overflows 32 bits")
+            }
+            AssemblerError::DivisionByZero => write!(f, "division by zero in expression"),
+            AssemblerError::InvalidOperands { mnemonic } => {
+                write!(f, "invalid operands for `{}`", mnemonic)
+            }
+            AssemblerError::UnknownInstruction { mnemonic } => {
+                write!(f, "unknown instruction `{}`", mnemonic)
+            }
+            AssemblerError::RegisterOutOfRange { number } => {
+                write!(f, "register ${} does not fit in a 5-bit field", number)
+            }
+            AssemblerError::ScratchRegisterOperand { mnemonic } => write!(
+                f,
+                "`{}` cannot use ${}, the assembler's scratch register",
+                mnemonic,
+                pseudo::SCRATCH_REGISTER
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+/// An error along with the source line it was found on.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub location: Location,
+    pub error: AssemblerError,
+}
+
+impl Diagnostic {
+    pub fn new(location: Location, error: AssemblerError) -> Diagnostic {
+        Diagnostic { location, error }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.error)
+    }
+}
+
+/// A program along with its listing, map file, debug info and whatever the
+/// optimizer changed.
+#[derive(Debug, PartialEq)]
+pub struct Assembly {
+    pub bytes: Vec<u8>,
+    pub listing: Listing,
+    pub map: MapFile,
+    pub debug_info: DebugInfo,
+    pub optimizations: Vec<Optimization>,
+}
+
+#[derive(Debug, Default)]
+pub struct Assembler {
+    include_paths: Vec<PathBuf>,
+    optimize: bool,
+    custom: Rc<CustomInstructions>,
+    encoding: Encoding,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler {
+            include_paths: vec![],
+            optimize: false,
+            custom: Rc::new(CustomInstructions::new()),
+            encoding: Encoding::Variable,
+        }
+    }
+
+    /// Runs the peephole optimizer before encoding. Off by default.
+    pub fn optimize(mut self, optimize: bool) -> Assembler {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Adds a directory to search for `.include`d files that are not found
+    /// next to the file including them.
+    pub fn include_path<P: Into<PathBuf>>(mut self, path: P) -> Assembler {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    /// Accepts the mnemonics of instructions an embedder has added to the
+    /// VM.
+    pub fn custom_instructions(mut self, custom: Rc<CustomInstructions>) -> Assembler {
+        self.custom = custom;
+        self
+    }
+
+    /// Encodes programs in `encoding`, starting them with the header that
+    /// names it. Object files always use the variable-length encoding.
+    pub fn encoding(mut self, encoding: Encoding) -> Assembler {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        let lines = IncludeExpander::new(&self.include_paths).expand(source_lines(source))?;
+        self.assemble_lines(lines)
+    }
+
+    pub fn assemble_file<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        let lines = IncludeExpander::new(&self.include_paths).expand_file(path.as_ref())?;
+        self.assemble_lines(lines)
+    }
+
+    /// Assembles a single module into a relocatable object file.
+    pub fn assemble_object(&self, source: &str) -> Result<ObjectFile, Vec<Diagnostic>> {
+        let lines = IncludeExpander::new(&self.include_paths).expand(source_lines(source))?;
+        self.parse(lines)?.to_object()
+    }
+
+    pub fn assemble_file_object<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<ObjectFile, Vec<Diagnostic>> {
+        let lines = IncludeExpander::new(&self.include_paths).expand_file(path.as_ref())?;
+        self.parse(lines)?.to_object()
+    }
+
+    /// Assembles a program and also produces a listing, a map file and the
+    /// debug info that maps bytecode offsets back to source lines.
+    pub fn assemble_detailed(&self, source: &str) -> Result<Assembly, Vec<Diagnostic>> {
+        let lines = IncludeExpander::new(&self.include_paths).expand(source_lines(source))?;
+        self.assemble_detailed_lines(lines, "<input>")
+    }
+
+    pub fn assemble_file_detailed<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Assembly, Vec<Diagnostic>> {
+        let path = path.as_ref();
+        let lines = IncludeExpander::new(&self.include_paths).expand_file(path)?;
+        self.assemble_detailed_lines(lines, &path.display().to_string())
+    }
+
+    fn assemble_detailed_lines(
+        &self,
+        lines: Vec<SourceLine>,
+        section: &str,
+    ) -> Result<Assembly, Vec<Diagnostic>> {
+        let expanded = MacroExpander::new().expand(lines.clone())?;
+        let program = parse_lines(&expanded)?.resolve_custom_instructions(&self.custom)?;
+        let (program, optimizations) = self.optimized(program)?;
+        let encoded = program.encode(self.encoding)?;
+        let bytes = encoded.bytes();
+        let labels = encoded
+            .symbols
+            .iter()
+            .map(|symbol| MapLabel {
+                name: symbol.name.clone(),
+                address: symbol.offset,
+                section: section.to_string(),
+            })
+            .collect();
+        let sections = vec![Section {
+            module: section.to_string(),
+            base: 0,
+            size: (bytes.len() - self.encoding.header().len()) as u32,
+        }];
+        Ok(Assembly {
+            listing: Listing::new(&lines, &expanded, &encoded.instructions),
+            map: MapFile::new(sections, labels),
+            debug_info: DebugInfo::new(&expanded, &encoded.instructions),
+            optimizations,
+            bytes,
+        })
+    }
+
+    fn assemble_lines(&self, lines: Vec<SourceLine>) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        Ok(self.parse(lines)?.encode(self.encoding)?.bytes())
+    }
+
+    fn parse(&self, lines: Vec<SourceLine>) -> Result<Program, Vec<Diagnostic>> {
+        let lines = MacroExpander::new().expand(lines)?;
+        let program = parse_lines(&lines)?.resolve_custom_instructions(&self.custom)?;
+        Ok(self.optimized(program)?.0)
+    }
+
+    fn optimized(&self, program: Program) -> Result<(Program, Vec<Optimization>), Vec<Diagnostic>> {
+        if self.optimize {
+            program.optimize()
+        } else {
+            Ok((program, vec![]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::ScratchDir;
+    use std::fs;
+
+    #[test]
+    fn test_assemble_file_with_library() {
+        let dir = ScratchDir::new("assembler");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::write(
+            dir.join("shared/moves.asm"),
+            ".macro mov from, to\n\tadd \\from $31 \\to\n.endm\n\
+             .macro bad\n\tload $0 #99999999999\n.endm\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("main.asm"),
+            ".include \"moves.asm\"\nmov $1, $2\nhlt\n",
+        )
+        .unwrap();
+        fs::write(dir.join("broken.asm"), ".include \"moves.asm\"\nbad\n").unwrap();
+
+        let assembler = Assembler::new().include_path(dir.join("shared"));
+        assert_eq!(
+            assembler.assemble_file(dir.join("main.asm")),
+            Ok(vec![2, 1, 31, 2, 0])
+        );
+
+        let errors = assembler.assemble_file(dir.join("broken.asm")).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            format!(
+                "{}:5, in macro `bad` expanded at {}:2: \
+                 value 99999999999 does not fit in a 32-bit immediate",
+                dir.join("shared").join("moves.asm").display(),
+                dir.join("broken.asm").display()
+            )
+        );
+    }
 }
@@ -1,44 +1,25 @@
+use crate::assembler::pseudo::PseudoOp;
 use crate::assembler::Token;
 use crate::instruction::Opcode;
 use nom::types::CompleteStr;
 use nom::*;
 
-impl<'a> From<CompleteStr<'a>> for Opcode {
-    fn from(v: CompleteStr<'a>) -> Self {
-        match v {
-            CompleteStr("load") => Opcode::LOAD,
-            CompleteStr("add") => Opcode::ADD,
-            CompleteStr("sub") => Opcode::SUB,
-            CompleteStr("mul") => Opcode::MUL,
-            CompleteStr("div") => Opcode::DIV,
-            CompleteStr("hlt") => Opcode::HLT,
-            CompleteStr("jmp") => Opcode::JMP,
-            CompleteStr("jmpf") => Opcode::JMPF,
-            CompleteStr("jmpb") => Opcode::JMPB,
-            CompleteStr("eq") => Opcode::EQ,
-            CompleteStr("neq") => Opcode::NEQ,
-            CompleteStr("gtq") => Opcode::GTQ,
-            CompleteStr("gt") => Opcode::GT,
-            CompleteStr("ltq") => Opcode::LTQ,
-            CompleteStr("lt") => Opcode::LT,
-            CompleteStr("jmpc") => Opcode::JMPC,
-            CompleteStr("nop") => Opcode::NOP,
-            _ => Opcode::IGL,
-        }
-    }
-}
-
 named!(pub opcode<CompleteStr, Token>,
   do_parse!(
       opcode: alpha1 >>
       (
-        {
-            Token::Op{code: Opcode::from(opcode)}
+        match PseudoOp::from_mnemonic(&opcode) {
+            Some(code) => Token::PseudoOp{code},
+            None => match Opcode::from_mnemonic(&opcode) {
+                Opcode::IGL => Token::CustomOp{mnemonic: opcode.0.to_string()},
+                code => Token::Op{code},
+            },
         }
       )
   )
 );
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -52,7 +33,23 @@ mod tests {
         assert_eq!(rest, CompleteStr(""));
 
         let result = opcode(CompleteStr("aold"));
-        let (rest, token) = result.unwrap();
-        assert_eq!(token, Token::Op { code: Opcode::IGL });
+        let (_rest, token) = result.unwrap();
+        assert_eq!(
+            token,
+            Token::CustomOp {
+                mnemonic: "aold".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_opcode_pseudo() {
+        let (_rest, token) = opcode(CompleteStr("mov")).unwrap();
+        assert_eq!(
+            token,
+            Token::PseudoOp {
+                code: PseudoOp::MOV
+            }
+        );
     }
 }
@@ -1,21 +1,95 @@
+use crate::assembler::expressions::{expression, Expression};
+use crate::assembler::label_parsers::identifier;
 use crate::assembler::Token;
 use nom::types::CompleteStr;
 use nom::*;
 
+named!(hex_literal<CompleteStr, i64>,
+    map_res!(
+        preceded!(tag_no_case!("0x"), hex_digit),
+        |digits: CompleteStr| i64::from_str_radix(digits.0, 16)
+    )
+);
+
+named!(binary_literal<CompleteStr, i64>,
+    map_res!(
+        preceded!(tag_no_case!("0b"), is_a!("01")),
+        |digits: CompleteStr| i64::from_str_radix(digits.0, 2)
+    )
+);
+
+named!(decimal_literal<CompleteStr, i64>,
+    map_res!(digit, |digits: CompleteStr| digits.0.parse::<i64>())
+);
+
+named!(escaped_char<CompleteStr, i64>,
+    preceded!(
+        char!('\\'),
+        alt!(
+            char!('n') => { |_| '\n' as i64 } |
+            char!('t') => { |_| '\t' as i64 } |
+            char!('r') => { |_| '\r' as i64 } |
+            char!('0') => { |_| 0 } |
+            char!('\\') => { |_| '\\' as i64 } |
+            char!('\'') => { |_| '\'' as i64 }
+        )
+    )
+);
+
+named!(char_literal<CompleteStr, i64>,
+    delimited!(
+        char!('\''),
+        alt!(escaped_char | none_of!("\\'") => { |c: char| c as i64 }),
+        char!('\'')
+    )
+);
+
+// Accepts `-5`, `0xFF`, `0b1010`, `'a'` and plain decimals. Range checking is
+// left to the encoder, since what fits depends on the instruction.
+named!(
+    pub integer_literal<CompleteStr, i64>,
+    do_parse!(
+        sign: opt!(char!('-')) >>
+        magnitude: alt_complete!(
+            hex_literal |
+            binary_literal |
+            decimal_literal |
+            char_literal
+        ) >>
+        (
+            if sign.is_some() { -magnitude } else { magnitude }
+        )
+    )
+);
+
+named!(parenthesized<CompleteStr, Expression>,
+    delimited!(
+        terminated!(char!('('), space0),
+        expression,
+        preceded!(space0, char!(')'))
+    )
+);
+
+// `#5`, `#(SIZE * 4 + 1)` or `#SIZE`. Expressions are resolved once every
+// constant and label is known.
 named!(
     pub integer_operand<CompleteStr, Token>,
-    ws!(
-        do_parse!(
-            tag!("#") >>
-            number: digit >>
-            (
-                Token::IntegerOperand{value: number.parse::<i32>().unwrap()}
-            )
+    preceded!(
+        tag!("#"),
+        alt_complete!(
+            integer_literal => { |value| Token::IntegerOperand{value} } |
+            parenthesized => { |expression| Token::Expression{expression} } |
+            identifier => {
+                |name: CompleteStr| Token::Expression {
+                    expression: Expression::Symbol(name.to_string())
+                }
+            }
         )
     )
 );
 
-pub mod tests {
+#[cfg(test)]
+mod tests {
     use super::*;
 
     #[test]
@@ -29,4 +103,51 @@ pub mod tests {
         let result = integer_operand(CompleteStr("#"));
         assert_eq!(result.is_ok(), false);
     }
+
+    #[test]
+    fn test_parse_negative_operand() {
+        let (_rest, token) = integer_operand(CompleteStr("#-5")).unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: -5 });
+        let (_rest, token) = integer_operand(CompleteStr("#-0x10")).unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: -16 });
+    }
+
+    #[test]
+    fn test_parse_radix_operands() {
+        let (_rest, token) = integer_operand(CompleteStr("#0xFF")).unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: 255 });
+        let (_rest, token) = integer_operand(CompleteStr("#0b1010")).unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: 10 });
+        let (_rest, token) = integer_operand(CompleteStr("#0")).unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: 0 });
+    }
+
+    #[test]
+    fn test_parse_expression_operand() {
+        let (rest, token) = integer_operand(CompleteStr("#( BUF_SIZE * 4 + 1 ) ; c")).unwrap();
+        assert_eq!(rest, CompleteStr(" ; c"));
+        match token {
+            Token::Expression { expression } => {
+                assert_eq!(expression.to_string(), "((BUF_SIZE * 4) + 1)")
+            }
+            t => panic!("unexpected token {:?}", t),
+        }
+        let (_rest, token) = integer_operand(CompleteStr("#SIZE")).unwrap();
+        assert_eq!(
+            token,
+            Token::Expression {
+                expression: Expression::Symbol("SIZE".to_string())
+            }
+        );
+        assert_eq!(integer_operand(CompleteStr("#(1 +)")).is_ok(), false);
+    }
+
+    #[test]
+    fn test_parse_char_operand() {
+        let (_rest, token) = integer_operand(CompleteStr("#'a'")).unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: 97 });
+        let (_rest, token) = integer_operand(CompleteStr("#'\\n'")).unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: 10 });
+        assert_eq!(integer_operand(CompleteStr("#''")).is_ok(), false);
+    }
 }
@@ -0,0 +1,556 @@
+use std::fmt;
+
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::pseudo::SCRATCH_REGISTER;
+use crate::assembler::source::Location;
+use crate::assembler::Token;
+use crate::instruction::Opcode;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptimizationKind {
+    RemovedNop,
+    RemovedSelfMove {
+        register: u8,
+    },
+    FoldedConstant {
+        register: u8,
+        value: i32,
+    },
+    RemovedDeadLoad {
+        register: u8,
+    },
+    RemovedJumpToNext,
+    /// Nothing was changed, since moving code would break a jump whose
+    /// target is not a label.
+    SkippedComputedJump,
+}
+
+impl fmt::Display for OptimizationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptimizationKind::RemovedNop => write!(f, "removed `nop`"),
+            OptimizationKind::RemovedSelfMove { register } => {
+                write!(f, "removed move of ${} to itself", register)
+            }
+            OptimizationKind::FoldedConstant { register, value } => {
+                write!(f, "folded constant: `load ${} #{}`", register, value)
+            }
+            OptimizationKind::RemovedDeadLoad { register } => {
+                write!(f, "removed `load` into ${}, which is never read", register)
+            }
+            OptimizationKind::RemovedJumpToNext => {
+                write!(f, "removed jump to the next instruction")
+            }
+            OptimizationKind::SkippedComputedJump => write!(
+                f,
+                "not optimizing: jump target is not a label, so code cannot be moved"
+            ),
+        }
+    }
+}
+
+/// A change made by the optimizer, at the line it was made to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Optimization {
+    pub location: Location,
+    pub kind: OptimizationKind,
+}
+
+impl fmt::Display for Optimization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+fn opcode(instruction: &AssemblerInstruction) -> Option<Opcode> {
+    match instruction.opcode {
+        Some(Token::Op { code }) => Some(code),
+        _ => None,
+    }
+}
+
+fn register(operand: &Option<Token>) -> Option<u8> {
+    match operand {
+        Some(Token::Register { number }) => Some(*number),
+        _ => None,
+    }
+}
+
+fn integer(operand: &Option<Token>) -> Option<i64> {
+    match operand {
+        Some(Token::IntegerOperand { value }) => Some(*value),
+        _ => None,
+    }
+}
+
+fn is_jump(code: Opcode) -> bool {
+    matches!(
+        code,
+        Opcode::JMP | Opcode::JMPB | Opcode::JMPF | Opcode::JMPC
+    ) || code.is_branch()
+}
+
+// Whether control never carries on to the next instruction.
+fn stops(code: Opcode) -> bool {
+    matches!(code, Opcode::HLT | Opcode::IRET)
+}
+
+// Registers read and written by an instruction.
+fn reads_and_writes(instruction: &AssemblerInstruction) -> (Vec<u8>, Vec<u8>) {
+    let (a, b, c) = (
+        register(&instruction.operand1),
+        register(&instruction.operand2),
+        register(&instruction.operand3),
+    );
+    let collect = |registers: &[Option<u8>]| registers.iter().flatten().cloned().collect();
+    match opcode(instruction) {
+        Some(Opcode::LOAD) => (vec![], collect(&[a])),
+        Some(Opcode::ADD) | Some(Opcode::SUB) | Some(Opcode::MUL) | Some(Opcode::DIV) => {
+            (collect(&[a, b]), collect(&[c]))
+        }
+        Some(Opcode::LUI) => (collect(&[a]), collect(&[a])),
+        Some(Opcode::ADDI) | Some(Opcode::SUBI) | Some(Opcode::MULI) => {
+            (collect(&[a]), collect(&[c]))
+        }
+        Some(code) if code.is_branch() => (collect(&[a, b]), vec![]),
+        Some(code) if is_jump(code) => (collect(&[a]), vec![]),
+        Some(Opcode::EQ) | Some(Opcode::NEQ) | Some(Opcode::GT) | Some(Opcode::LT)
+        | Some(Opcode::GTQ) | Some(Opcode::LTQ) => (collect(&[a, b]), vec![]),
+        Some(Opcode::CMPI) => (collect(&[a]), vec![]),
+        // A custom instruction may use any register, whatever its operands.
+        Some(Opcode::CUSTOM(_)) => ((0..32).collect(), vec![]),
+        // The new thread gets a copy of every register.
+        Some(Opcode::SPAWN) => ((0..32).collect(), collect(&[a])),
+        Some(Opcode::SEND) | Some(Opcode::SETVEC) | Some(Opcode::TIMER) => {
+            (collect(&[a, b]), vec![])
+        }
+        Some(Opcode::RECV) => (vec![], collect(&[a])),
+        _ => (vec![], vec![]),
+    }
+}
+
+/// Rewrites a program whose pseudo-instructions and constants have already
+/// been resolved. Labels are kept symbolic until encoding, so removing code
+/// moves them along with it.
+///
+/// The scratch register is treated as dead wherever control may come from
+/// elsewhere, since the assembler clobbers it freely. Every other register
+/// is assumed to be live there.
+pub struct Optimizer {
+    instructions: Vec<AssemblerInstruction>,
+    optimizations: Vec<Optimization>,
+}
+
+impl Optimizer {
+    pub fn new(instructions: Vec<AssemblerInstruction>) -> Optimizer {
+        Optimizer {
+            instructions,
+            optimizations: vec![],
+        }
+    }
+
+    /// Applies every pass until none of them changes anything.
+    pub fn run(mut self) -> (Vec<AssemblerInstruction>, Vec<Optimization>) {
+        if let Some(location) = self.computed_jump() {
+            self.optimizations.push(Optimization {
+                location,
+                kind: OptimizationKind::SkippedComputedJump,
+            });
+            return (self.instructions, self.optimizations);
+        }
+        loop {
+            let changed = self.remove_nops()
+                | self.propagate_constants()
+                | self.remove_jumps_to_next()
+                | self.remove_dead_loads();
+            if !changed {
+                return (self.instructions, self.optimizations);
+            }
+        }
+    }
+
+    fn record(&mut self, index: usize, kind: OptimizationKind) {
+        self.optimizations.push(Optimization {
+            location: self.instructions[index].location.clone(),
+            kind,
+        });
+    }
+
+    // Removes an instruction and returns the index of the one after it. An
+    // instruction that carries a label leaves the label behind, so that it
+    // points at whatever follows.
+    fn remove(&mut self, index: usize, kind: OptimizationKind) -> usize {
+        self.record(index, kind);
+        if self.instructions[index].label.is_some() {
+            let instruction = &mut self.instructions[index];
+            instruction.opcode = None;
+            instruction.operand1 = None;
+            instruction.operand2 = None;
+            instruction.operand3 = None;
+            return index + 1;
+        }
+        self.instructions.remove(index);
+        index
+    }
+
+    // The first jump that does not go to a label loaded right before it.
+    // Relative jumps and jumps to computed addresses would land somewhere
+    // else once code has been removed.
+    fn computed_jump(&self) -> Option<Location> {
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            let code = match opcode(instruction) {
+                // A branch names its target itself.
+                Some(code) if code.is_branch() => match instruction.operand3 {
+                    Some(Token::LabelUsage { .. }) | Some(Token::Expression { .. }) => continue,
+                    _ => return Some(instruction.location.clone()),
+                },
+                Some(code) if is_jump(code) => code,
+                // So do a spawn and an interrupt handler.
+                Some(Opcode::SPAWN) | Some(Opcode::SETVEC) => match instruction.operand2 {
+                    Some(Token::LabelUsage { .. }) | Some(Token::Expression { .. }) => continue,
+                    _ => return Some(instruction.location.clone()),
+                },
+                _ => continue,
+            };
+            let loads_label = i > 0 && instruction.label.is_none() && {
+                let previous = &self.instructions[i - 1];
+                opcode(previous) == Some(Opcode::LOAD)
+                    && register(&previous.operand1) == register(&instruction.operand1)
+                    && matches!(
+                        previous.operand2,
+                        Some(Token::LabelUsage { .. }) | Some(Token::Expression { .. })
+                    )
+            };
+            if code == Opcode::JMPB || code == Opcode::JMPF || !loads_label {
+                return Some(instruction.location.clone());
+            }
+        }
+        None
+    }
+
+    fn remove_nops(&mut self) -> bool {
+        let mut changed = false;
+        let mut i = 0;
+        while i < self.instructions.len() {
+            if opcode(&self.instructions[i]) == Some(Opcode::NOP) {
+                i = self.remove(i, OptimizationKind::RemovedNop);
+                changed = true;
+            } else {
+                i += 1;
+            }
+        }
+        changed
+    }
+
+    // Tracks the registers holding known values through each basic block,
+    // removing arithmetic that leaves a register unchanged and replacing
+    // arithmetic on constants with a `load` of the result.
+    fn propagate_constants(&mut self) -> bool {
+        let mut changed = false;
+        let mut known: [Option<i32>; 32] = [None; 32];
+        let value = |known: &[Option<i32>; 32], r: Option<u8>| {
+            r.and_then(|r| known.get(usize::from(r)).cloned().flatten())
+        };
+        let mut i = 0;
+        while i < self.instructions.len() {
+            let instruction = &self.instructions[i];
+            if instruction.label.is_some() {
+                known = [None; 32];
+            }
+            let code = match opcode(instruction) {
+                Some(code) => code,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let (a, b, c) = (
+                register(&instruction.operand1),
+                register(&instruction.operand2),
+                register(&instruction.operand3),
+            );
+            let immediate = integer(&instruction.operand2);
+
+            match (code, a, c) {
+                (Opcode::LOAD, Some(a), _) => {
+                    known[usize::from(a)] = immediate.map(|value| value as i32);
+                }
+                (Opcode::LUI, Some(a), _) => {
+                    known[usize::from(a)] = match (value(&known, Some(a)), immediate) {
+                        (Some(lower), Some(upper)) => {
+                            Some((((upper as u32) << 16) | (lower as u32 & 0xFFFF)) as i32)
+                        }
+                        _ => None,
+                    };
+                }
+                (Opcode::ADD, _, Some(c))
+                | (Opcode::SUB, _, Some(c))
+                | (Opcode::MUL, _, Some(c)) => {
+                    let identity = if code == Opcode::MUL { 1 } else { 0 };
+                    if (a == Some(c) && value(&known, b) == Some(identity))
+                        || (code != Opcode::SUB
+                            && b == Some(c)
+                            && value(&known, a) == Some(identity))
+                    {
+                        i = self.remove(i, OptimizationKind::RemovedSelfMove { register: c });
+                        changed = true;
+                        continue;
+                    }
+
+                    let result = match (value(&known, a), value(&known, b)) {
+                        (Some(x), Some(y)) => Some(match code {
+                            Opcode::ADD => x.wrapping_add(y),
+                            Opcode::SUB => x.wrapping_sub(y),
+                            _ => x.wrapping_mul(y),
+                        }),
+                        _ => None,
+                    };
+                    known[usize::from(c)] = result;
+                    // Only results that fit in a single `load` are folded.
+                    if let Some(result) = result.filter(|r| (0..=0xFFFF).contains(r)) {
+                        let instruction = &mut self.instructions[i];
+                        instruction.opcode = Some(Token::Op { code: Opcode::LOAD });
+                        instruction.operand1 = Some(Token::Register { number: c });
+                        instruction.operand2 = Some(Token::IntegerOperand {
+                            value: i64::from(result),
+                        });
+                        instruction.operand3 = None;
+                        self.record(
+                            i,
+                            OptimizationKind::FoldedConstant {
+                                register: c,
+                                value: result,
+                            },
+                        );
+                        changed = true;
+                    }
+                }
+                (Opcode::DIV, _, Some(c))
+                | (Opcode::SPAWN, Some(c), _)
+                | (Opcode::RECV, Some(c), _) => known[usize::from(c)] = None,
+                (Opcode::ADDI, _, Some(c))
+                | (Opcode::SUBI, _, Some(c))
+                | (Opcode::MULI, _, Some(c)) => {
+                    known[usize::from(c)] = match (value(&known, a), immediate) {
+                        (Some(x), Some(y)) => Some(match code {
+                            Opcode::ADDI => x.wrapping_add(y as i32),
+                            Opcode::SUBI => x.wrapping_sub(y as i32),
+                            _ => x.wrapping_mul(y as i32),
+                        }),
+                        _ => None,
+                    };
+                }
+                (code, _, _) if is_jump(code) || stops(code) => known = [None; 32],
+                (Opcode::CUSTOM(_), _, _) => known = [None; 32],
+                _ => {}
+            }
+            i += 1;
+        }
+        changed
+    }
+
+    // Whether the value written to `target` by instruction `index` is
+    // overwritten before anything reads it.
+    fn is_dead(&self, index: usize, target: u8) -> bool {
+        for instruction in &self.instructions[index + 1..] {
+            if instruction.label.is_some() {
+                return target == SCRATCH_REGISTER;
+            }
+            let (reads, writes) = reads_and_writes(instruction);
+            if reads.contains(&target) {
+                return false;
+            }
+            if writes.contains(&target) {
+                return true;
+            }
+            match opcode(instruction) {
+                Some(code) if is_jump(code) || stops(code) => {
+                    return target == SCRATCH_REGISTER;
+                }
+                _ => {}
+            }
+        }
+        target == SCRATCH_REGISTER
+    }
+
+    fn remove_dead_loads(&mut self) -> bool {
+        let mut changed = false;
+        let mut i = 0;
+        while i < self.instructions.len() {
+            let instruction = &self.instructions[i];
+            if opcode(instruction) == Some(Opcode::LOAD) {
+                if let Some(target) = register(&instruction.operand1) {
+                    if self.is_dead(i, target) {
+                        i = self.remove(i, OptimizationKind::RemovedDeadLoad { register: target });
+                        changed = true;
+                        continue;
+                    }
+                }
+            }
+            i += 1;
+        }
+        changed
+    }
+
+    // A jump to a label on the instruction right after it does nothing,
+    // whether or not it is taken.
+    fn remove_jumps_to_next(&mut self) -> bool {
+        let mut changed = false;
+        let mut i = 1;
+        while i < self.instructions.len() {
+            let jumps = matches!(
+                opcode(&self.instructions[i]),
+                Some(Opcode::JMP) | Some(Opcode::JMPC)
+            );
+            let target = match &self.instructions[i - 1].operand2 {
+                Some(Token::LabelUsage { name }) if jumps => Some(name.clone()),
+                _ => None,
+            };
+            let to_next = target.is_some_and(|target| {
+                self.instructions[i + 1..]
+                    .iter()
+                    .scan(false, |done, instruction| {
+                        if *done {
+                            return None;
+                        }
+                        *done = instruction.opcode.is_some();
+                        Some(instruction)
+                    })
+                    .any(|instruction| instruction.label_name() == Some(target.as_str()))
+            });
+            if to_next {
+                i = self.remove(i, OptimizationKind::RemovedJumpToNext);
+                changed = true;
+            } else {
+                i += 1;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::program;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+    use nom::types::CompleteStr;
+
+    fn optimize(source: &str) -> (Vec<u8>, Vec<OptimizationKind>) {
+        let (_, parsed) = program(CompleteStr(source)).unwrap();
+        let (optimized, optimizations) = parsed.optimize().unwrap();
+        (
+            optimized.to_bytes().unwrap(),
+            optimizations.into_iter().map(|o| o.kind).collect(),
+        )
+    }
+
+    #[test]
+    fn test_remove_nops_and_self_moves() {
+        let (bytes, optimizations) = optimize("nop\nmov $1 $1\nloop: nop\nmov $2 $3\nhlt\n");
+        assert_eq!(bytes, vec![1, 31, 0, 0, 2, 2, 31, 3, 0]);
+        assert_eq!(
+            optimizations,
+            vec![
+                OptimizationKind::RemovedNop,
+                OptimizationKind::RemovedNop,
+                OptimizationKind::RemovedSelfMove { register: 1 },
+                OptimizationKind::RemovedDeadLoad { register: 31 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let (bytes, optimizations) = optimize("load $0 #3\ninc $0\ninc $0\nadd $0 $0 $1\nhlt\n");
+        assert_eq!(bytes, vec![1, 0, 0, 5, 1, 1, 0, 10, 0]);
+        assert_eq!(
+            optimizations,
+            vec![
+                OptimizationKind::FoldedConstant {
+                    register: 0,
+                    value: 4
+                },
+                OptimizationKind::FoldedConstant {
+                    register: 0,
+                    value: 5
+                },
+                OptimizationKind::FoldedConstant {
+                    register: 1,
+                    value: 10
+                },
+                OptimizationKind::RemovedDeadLoad { register: 0 },
+                OptimizationKind::RemovedDeadLoad { register: 31 },
+                OptimizationKind::RemovedDeadLoad { register: 0 },
+                OptimizationKind::RemovedDeadLoad { register: 31 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_follow_removed_code() {
+        let (bytes, optimizations) =
+            optimize("jmp @next\nnext: nop\nloop: load $0 #1\nlt $0 $1\njmpc @loop\n");
+        assert_eq!(bytes, vec![1, 0, 0, 1, 13, 0, 1, 1, 31, 0, 0, 9, 31]);
+        assert_eq!(
+            optimizations,
+            vec![
+                OptimizationKind::RemovedNop,
+                OptimizationKind::RemovedJumpToNext,
+                OptimizationKind::RemovedDeadLoad { register: 31 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_computed_jumps_are_left_alone() {
+        let (bytes, optimizations) = optimize("mov $1 $1\nload $0 #5\njmp $0\n");
+        assert_eq!(bytes, vec![1, 31, 0, 0, 2, 1, 31, 1, 1, 0, 0, 5, 6, 0]);
+        assert_eq!(optimizations, vec![OptimizationKind::SkippedComputedJump]);
+
+        let (_, optimizations) = optimize("nop\nbeq $0 $1 #0\n");
+        assert_eq!(optimizations, vec![OptimizationKind::SkippedComputedJump]);
+        let (_, optimizations) = optimize("nop\nspawn $0 #0\n");
+        assert_eq!(optimizations, vec![OptimizationKind::SkippedComputedJump]);
+        let (_, optimizations) = optimize("nop\nsetvec $0 #0\n");
+        assert_eq!(optimizations, vec![OptimizationKind::SkippedComputedJump]);
+    }
+
+    #[test]
+    fn test_messages_are_kept() {
+        let (bytes, optimizations) = optimize("load $31 #1\nsend $0 $31\nrecv $31\nhlt\n");
+        assert_eq!(bytes, vec![1, 31, 0, 1, 29, 0, 31, 30, 31, 0]);
+        assert!(optimizations.is_empty());
+    }
+
+    #[test]
+    fn test_branches_to_labels() {
+        let (bytes, optimizations) =
+            optimize("loop: nop\naddi $0 #2 $0\nmuli $0 #3 $1\nbneq $0 $1 @loop\nhlt\n");
+        assert_eq!(
+            bytes,
+            vec![17, 0, 0, 2, 0, 19, 0, 0, 3, 1, 22, 0, 1, 0, 0, 0]
+        );
+        assert_eq!(optimizations, vec![OptimizationKind::RemovedNop]);
+    }
+
+    #[test]
+    fn test_compared_registers_are_live() {
+        let source = "load $0 #5\ncmpi $0 #5\nload $0 #6\njmpc @yes\n\
+                      load $1 #1\nhlt\nyes: load $1 #2\nhlt\n";
+        let run = |optimize: bool| {
+            let mut vm = VM::new();
+            let program = Assembler::new()
+                .optimize(optimize)
+                .assemble(source)
+                .unwrap();
+            vm.load(program, None).unwrap();
+            vm.run().unwrap();
+            (*vm.registers(), vm.conditional())
+        };
+        let expected = run(false);
+        assert_eq!(expected.0[1], 2);
+        assert_eq!(run(true), expected);
+    }
+}
@@ -1,44 +1,403 @@
+use std::collections::HashMap;
+
 use nom::types::CompleteStr;
 use nom::*;
 
-use crate::assembler::instruction_parsers::{instruction, AssemblerInstruction};
+use crate::assembler::instruction_parsers::{comment, instruction, AssemblerInstruction};
+use crate::assembler::optimizer::{Optimization, Optimizer};
+use crate::assembler::pseudo;
+use crate::assembler::source::{Location, SourceLine};
+use crate::assembler::symbols::SymbolTable;
+use crate::assembler::{Assembler, AssemblerError, Diagnostic, Token};
+use crate::instruction::{Encoding, Opcode};
+use crate::linker::object::{ObjectFile, ObjectSymbol, Relocation};
+use crate::vm::custom::CustomInstructions;
+
+/// An instruction after assembly, along with its address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodedInstruction {
+    pub offset: u32,
+    pub bytes: Vec<u8>,
+    pub label: Option<String>,
+    pub location: Location,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Encoded {
+    pub instructions: Vec<EncodedInstruction>,
+    pub symbols: SymbolTable,
+    pub encoding: Encoding,
+}
+
+impl Encoded {
+    /// The bytecode, starting with the header its encoding needs. Offsets
+    /// count from after the header.
+    pub fn bytes(&self) -> Vec<u8> {
+        let code = self
+            .instructions
+            .iter()
+            .flat_map(|instruction| instruction.bytes.iter().cloned());
+        self.encoding.header().iter().cloned().chain(code).collect()
+    }
+}
 
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Program {
-    instructions: Vec<AssemblerInstruction>,
+    pub instructions: Vec<AssemblerInstruction>,
 }
 
 impl Program {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut program = vec![];
+    /// Replaces pseudo-instructions and jumps to immediate targets with the
+    /// real instructions they stand for.
+    pub fn expand_pseudo_instructions(&self) -> Result<Program, Vec<Diagnostic>> {
+        let mut instructions = vec![];
+        let mut errors = vec![];
+        for instruction in &self.instructions {
+            match pseudo::expand(instruction) {
+                Ok(mut expanded) => instructions.append(&mut expanded),
+                Err(error) => errors.push(Diagnostic::new(instruction.location.clone(), error)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(Program { instructions })
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Turns the mnemonics of instructions in `custom` into their opcodes,
+    /// checking their operands. Mnemonics that aren't there are left for
+    /// encoding to report.
+    pub fn resolve_custom_instructions(
+        &self,
+        custom: &CustomInstructions,
+    ) -> Result<Program, Vec<Diagnostic>> {
+        let mut instructions = self.instructions.clone();
+        let mut errors = vec![];
+        for instruction in &mut instructions {
+            let found = match &instruction.opcode {
+                Some(Token::CustomOp { mnemonic }) => custom.find(mnemonic),
+                _ => None,
+            };
+            if let Some(found) = found {
+                if instruction.operand_kinds() != found.operands {
+                    let error = AssemblerError::InvalidOperands {
+                        mnemonic: found.mnemonic.clone(),
+                    };
+                    errors.push(Diagnostic::new(instruction.location.clone(), error));
+                }
+                instruction.opcode = Some(Token::Op {
+                    code: Opcode::CUSTOM(found.byte),
+                });
+            }
+        }
+        if errors.is_empty() {
+            Ok(Program { instructions })
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Removes `.equ` and `.set` directives, substituting the constants they
+    /// define into later operands. Operands that no longer refer to any
+    /// symbol are folded to plain integers, so only expressions that depend
+    /// on labels are left for the encoder.
+    pub fn resolve_constants(&self) -> Result<Program, Vec<Diagnostic>> {
+        let mut constants = HashMap::new();
+        let mut redefinable = vec![];
+        let mut instructions = vec![];
+        let mut errors = vec![];
+
+        for instruction in &self.instructions {
+            let mut resolved = instruction.clone();
+            if let (
+                Some(Token::Directive { name: directive }),
+                Some(Token::ConstantDeclaration { name }),
+                Some(Token::Expression { expression }),
+            ) = (
+                &instruction.directive,
+                &instruction.operand1,
+                &instruction.operand2,
+            ) {
+                let is_set = directive == "set";
+                if constants.contains_key(name) && !(is_set && redefinable.contains(name)) {
+                    errors.push(Diagnostic::new(
+                        instruction.location.clone(),
+                        AssemblerError::ConstantRedefined { name: name.clone() },
+                    ));
+                } else {
+                    if is_set {
+                        redefinable.push(name.clone());
+                    }
+                    let value = expression.inline(&constants);
+                    constants.insert(name.clone(), value);
+                }
+                resolved.directive = None;
+                resolved.operand1 = None;
+                resolved.operand2 = None;
+            }
+
+            for operand in [
+                &mut resolved.operand1,
+                &mut resolved.operand2,
+                &mut resolved.operand3,
+            ] {
+                if let Some(Token::Expression { expression }) = operand {
+                    let inlined = expression.inline(&constants);
+                    if inlined.references_symbols() {
+                        *operand = Some(Token::Expression {
+                            expression: inlined,
+                        });
+                        continue;
+                    }
+                    match inlined.evaluate(&SymbolTable::new()) {
+                        Ok(value) => *operand = Some(Token::IntegerOperand { value }),
+                        Err(error) => {
+                            errors.push(Diagnostic::new(instruction.location.clone(), error))
+                        }
+                    }
+                }
+            }
+            instructions.push(resolved);
+        }
+
+        if errors.is_empty() {
+            Ok(Program { instructions })
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Resolves pseudo-instructions and constants, then runs the peephole
+    /// optimizer over the result. Returns the optimized program along with
+    /// what was changed.
+    pub fn optimize(&self) -> Result<(Program, Vec<Optimization>), Vec<Diagnostic>> {
+        let resolved = self.expand_pseudo_instructions()?.resolve_constants()?;
+        let (instructions, optimizations) = Optimizer::new(resolved.instructions).run();
+        Ok((Program { instructions }, optimizations))
+    }
+
+    /// First pass: assigns every label the byte offset of the instruction
+    /// that follows it in `encoding`. Constants have to be resolved
+    /// beforehand, since they decide how large a `load` is.
+    pub fn symbols(&self, encoding: Encoding) -> Result<SymbolTable, Vec<Diagnostic>> {
+        let mut symbols = SymbolTable::new();
+        let mut errors = vec![];
+        let mut offset = 0;
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes());
+            if let Some(name) = instruction.label_name() {
+                if !symbols.add_symbol(name, offset as u32) {
+                    errors.push(Diagnostic::new(
+                        instruction.location.clone(),
+                        AssemblerError::DuplicateLabel {
+                            name: name.to_string(),
+                        },
+                    ));
+                }
+            }
+            offset += instruction.size_in(encoding);
+        }
+        if errors.is_empty() {
+            Ok(symbols)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Runs every pass and encodes each instruction in `encoding`, keeping
+    /// track of where it ended up.
+    pub fn encode(&self, encoding: Encoding) -> Result<Encoded, Vec<Diagnostic>> {
+        let resolved = self.expand_pseudo_instructions()?.resolve_constants()?;
+        let symbols = resolved.symbols(encoding)?;
+        let mut instructions = vec![];
+        let mut errors = vec![];
+        let mut offset = 0;
+        for instruction in &resolved.instructions {
+            let encoded = match encoding {
+                Encoding::Variable => instruction.to_bytes(&symbols),
+                Encoding::Fixed => instruction.to_words(&symbols),
+            };
+            match encoded {
+                Ok(bytes) => {
+                    let size = bytes.len() as u32;
+                    instructions.push(EncodedInstruction {
+                        offset,
+                        bytes,
+                        label: instruction.label_name().map(String::from),
+                        location: instruction.location.clone(),
+                    });
+                    offset += size;
+                }
+                Err(error) => errors.push(Diagnostic::new(instruction.location.clone(), error)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(Encoded {
+                instructions,
+                symbols,
+                encoding,
+            })
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        Ok(self.encode(Encoding::Variable)?.bytes())
+    }
+
+    /// Assembles the program as one module of a larger one. Operands that
+    /// depend on labels are encoded as zero and recorded as relocations, so
+    /// the linker can fill them in once it has placed every module. A label
+    /// that is not defined here has to be declared with `.extern`.
+    pub fn to_object(&self) -> Result<ObjectFile, Vec<Diagnostic>> {
+        let resolved = self.expand_pseudo_instructions()?.resolve_constants()?;
+        let symbols = resolved.symbols(Encoding::Variable)?;
+        let mut errors = vec![];
+
+        let mut exports = vec![];
+        let mut imports: Vec<String> = vec![];
+        for instruction in &resolved.instructions {
+            if let (Some(Token::Directive { name: directive }), Some(Token::LabelUsage { name })) =
+                (&instruction.directive, &instruction.operand1)
+            {
+                let error = match directive.as_str() {
+                    "global" if symbols.symbol_value(name).is_none() => {
+                        Some(AssemblerError::UndefinedLabel { name: name.clone() })
+                    }
+                    "global" => {
+                        exports.push(name.clone());
+                        None
+                    }
+                    _ if symbols.symbol_value(name).is_some() => {
+                        Some(AssemblerError::DuplicateLabel { name: name.clone() })
+                    }
+                    _ => {
+                        if !imports.contains(name) {
+                            imports.push(name.clone());
+                        }
+                        None
+                    }
+                };
+                if let Some(error) = error {
+                    errors.push(Diagnostic::new(instruction.location.clone(), error));
+                }
+            }
+        }
+
+        let mut code = vec![];
+        let mut relocations = vec![];
+        for instruction in &resolved.instructions {
+            for (field, expression) in instruction.relocations() {
+                for name in expression.symbols() {
+                    if symbols.symbol_value(name).is_none() && !imports.iter().any(|i| i == name) {
+                        errors.push(Diagnostic::new(
+                            instruction.location.clone(),
+                            AssemblerError::UndefinedSymbol {
+                                name: name.to_string(),
+                            },
+                        ));
+                    }
+                }
+                relocations.push(Relocation {
+                    offset: (code.len() + field) as u32,
+                    expression,
+                    signed: instruction.sign_extends(),
+                });
+            }
+            match instruction.with_placeholders().to_bytes(&symbols) {
+                Ok(mut bytes) => code.append(&mut bytes),
+                Err(error) => errors.push(Diagnostic::new(instruction.location.clone(), error)),
+            }
         }
-        program
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(ObjectFile {
+            code,
+            symbols: symbols
+                .iter()
+                .map(|symbol| ObjectSymbol {
+                    name: symbol.name.clone(),
+                    offset: symbol.offset,
+                    exported: exports.contains(&symbol.name),
+                })
+                .collect(),
+            imports,
+            relocations,
+        })
     }
 }
 
-pub fn assemble(code: String) -> Vec<u8> {
-    let result = program(CompleteStr(&code));
-    if !result.is_ok() {
-        // TODO: Replace with good error handling
-        std::process::exit(1);
+/// Parses lines one at a time so that every instruction keeps the location
+/// it was expanded from.
+pub fn parse_lines(lines: &[SourceLine]) -> Result<Program, Vec<Diagnostic>> {
+    let mut instructions = vec![];
+    let mut errors = vec![];
+    for line in lines {
+        let text = CompleteStr(&line.text);
+        if text.trim().is_empty() || blank_line(text).is_ok() {
+            continue;
+        }
+        match instruction(text) {
+            Ok((rest, mut parsed)) if rest.is_empty() => {
+                parsed.location = line.location.clone();
+                instructions.push(parsed);
+            }
+            _ => errors.push(Diagnostic::new(
+                line.location.clone(),
+                AssemblerError::ParseError,
+            )),
+        }
+    }
+    if errors.is_empty() {
+        Ok(Program { instructions })
+    } else {
+        Err(errors)
     }
-    let (_rest, program) = result.unwrap();
-    program.to_bytes()
 }
 
+pub fn assemble(code: String) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    Assembler::new().assemble(&code)
+}
+
+// A line with nothing but whitespace and possibly a comment.
+named!(blank_line<CompleteStr, ()>,
+    alt_complete!(
+        do_parse!(space0 >> opt!(comment) >> line_ending >> ()) |
+        do_parse!(space1 >> opt!(comment) >> eof!() >> ()) |
+        do_parse!(comment >> eof!() >> ())
+    )
+);
+
 named!(
     pub program<CompleteStr, Program>,
     do_parse!(
-        instructions: many1!(instruction) >>
+        lines: many0!(
+            alt_complete!(
+                blank_line => { |_| None } |
+                instruction => { Some }
+            )
+        ) >>
+        eof!() >>
         (
             Program {
-                instructions: instructions,
+                // Each alternative consumes exactly one line.
+                instructions: lines
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(i, line)| line.map(|ins| AssemblerInstruction {
+                        location: Location::new(i + 1),
+                        ..ins
+                    }))
+                    .collect(),
             }
         )
     )
 );
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -51,11 +410,194 @@ mod tests {
         assert_eq!(1, program.instructions.len());
     }
 
+    #[test]
+    fn test_parse_annotated_program() {
+        let source = "; Computes the 21st fibonacci number into $1\r\n\
+                      \r\n\
+                      \tload $0 #0      ; previous\r\n\
+                      \tload $1 #1      ; current\r\n\
+                      \tload $2 #0\r\n\
+                      \tload $3 #0      // always zero\r\n\
+                      \tload $4 #0      ; iteration count\r\n\
+                      \tload $5 #1      ; increment\r\n\
+                      \tload $6 #20     ; number of iterations\r\n\
+                      \r\n\
+                      ; loop body, starts at byte 28\r\n\
+                      \tadd $1 $3 $2    ; mov $1 => $2\r\n\
+                      \tadd $0 $1 $1\r\n\
+                      \tadd $2 $3 $0    ; mov $2 => $0\r\n\
+                      \tadd $4 $5 $4    ; inc $4\r\n\
+                      \tlt $4 $6\r\n\
+                      \tload $7 #28\r\n\
+                      \tjmpc $7\r\n\
+                      \thlt\t\t\t; done\r\n\
+                      \r\n\
+                      ; trailing comment without a newline";
+        let (rest, program) = program(CompleteStr(source)).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(program.instructions.len(), 15);
+        assert_eq!(
+            program.to_bytes(),
+            Ok(vec![
+                1, 0, 0, 0, 1, 1, 0, 1, 1, 2, 0, 0, 1, 3, 0, 0, 1, 4, 0, 0, 1, 5, 0, 1, //
+                1, 6, 0, 20, 2, 1, 3, 2, 2, 0, 1, 1, 2, 2, 3, 0, 2, 4, 5, 4, //
+                13, 4, 6, 1, 7, 0, 28, 9, 7, 0, //
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_empty_program() {
+        let (_rest, program) = program(CompleteStr("\n  ; nothing here\n\n")).unwrap();
+        assert_eq!(program.instructions.len(), 0);
+    }
+
+    #[test]
+    fn test_assemble_reports_line() {
+        assert_eq!(
+            assemble("load $0 #1\n\n  add $0 $0 $ ; bad register\nhlt".to_string()),
+            Err(vec![Diagnostic::new(
+                Location::new(3),
+                AssemblerError::ParseError
+            )])
+        );
+    }
+
+    #[test]
+    fn test_assemble_labels() {
+        let bytes = assemble(
+            "start: load $0 @end\n\
+             \tjmp $0\n\
+             end:\n\
+             \thlt\n"
+                .to_string(),
+        );
+        assert_eq!(bytes, Ok(vec![1, 0, 0, 6, 6, 0, 0]));
+
+        let errors = assemble("a: hlt\na: hlt\nload $0 @b\n".to_string()).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![Diagnostic::new(
+                Location::new(2),
+                AssemblerError::DuplicateLabel {
+                    name: "a".to_string()
+                }
+            )]
+        );
+        let errors = assemble("load $0 @b\n".to_string()).unwrap_err();
+        assert_eq!(
+            errors[0].error,
+            AssemblerError::UndefinedLabel {
+                name: "b".to_string()
+            }
+        );
+
+        // A `load` of a label isn't split in two, so it can't be negative.
+        let errors = assemble("x: load $0 #(x - 1)\n".to_string()).unwrap_err();
+        assert_eq!(
+            errors[0].error,
+            AssemblerError::ImmediateOutOfRange {
+                value: -1,
+                bits: 16
+            }
+        );
+        let bytes = assemble("x: addi $0 #(x - 1) $0\n".to_string());
+        assert_eq!(bytes, Ok(vec![17, 0, 255, 255, 0]));
+    }
+
+    #[test]
+    fn test_assemble_macro_error_location() {
+        let errors = assemble(
+            ".macro clr r\n\
+             \tload \\r #0\n\
+             \tlui \\r #0x10000   ; out of range\n\
+             .endm\n\
+             \n\
+             clr $1\n"
+                .to_string(),
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            "line 3, in macro `clr` expanded at line 6: \
+             value 65536 does not fit in a 16-bit immediate"
+        );
+    }
+
+    #[test]
+    fn test_assemble_constants() {
+        let bytes = assemble(
+            ".equ BUF_SIZE 16\n\
+             .equ WORDS, BUF_SIZE / 4\n\
+             .set step 1\n\
+             \tload $0 #(BUF_SIZE * 4 + 1)\n\
+             \tload $1 #WORDS\n\
+             \tload $2 #step\n\
+             .set step step + 1\n\
+             \tload $3 #step\n\
+             start:\n\
+             \tload $4 #(BUF_SIZE << 12)   ; does not fit, becomes load + lui\n\
+             end:\n\
+             \tload $5 #(end - start)\n"
+                .to_string(),
+        );
+        assert_eq!(
+            bytes,
+            Ok(vec![
+                1, 0, 0, 65, //
+                1, 1, 0, 4, //
+                1, 2, 0, 1, //
+                1, 3, 0, 2, //
+                1, 4, 0, 0, 16, 4, 0, 1, //
+                1, 5, 0, 8, //
+            ])
+        );
+    }
+
+    #[test]
+    fn test_assemble_constant_errors() {
+        let errors = assemble(
+            ".equ A 1\n\
+             .equ A 2\n\
+             load $0 #(A * MISSING)\n\
+             load $0 #(0xFFFF * 0x10000 * 2)\n\
+             load $0 #(1 / (A - 1))\n"
+                .to_string(),
+        )
+        .unwrap_err();
+        let errors: Vec<(usize, AssemblerError)> = errors
+            .into_iter()
+            .map(|diagnostic| (diagnostic.location.line, diagnostic.error))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    2,
+                    AssemblerError::ConstantRedefined {
+                        name: "A".to_string()
+                    }
+                ),
+                (4, AssemblerError::ExpressionOverflow),
+                (5, AssemblerError::DivisionByZero),
+            ]
+        );
+
+        let errors = assemble("load $0 #(A * MISSING)\n".to_string()).unwrap_err();
+        assert_eq!(
+            errors[0].error,
+            AssemblerError::UndefinedSymbol {
+                name: "A".to_string()
+            }
+        );
+    }
+
     #[test]
     fn test_program_to_bytes() {
         let result = program(CompleteStr("load $0 #100\nload $1 #500\n"));
         let (_rest, program) = result.unwrap();
-        assert_eq!(program.to_bytes(), [1, 0, 0, 100, 1, 1, 1, 244]);
+        assert_eq!(program.to_bytes(), Ok(vec![1, 0, 0, 100, 1, 1, 1, 244]));
     }
 
     #[test]
@@ -66,13 +608,68 @@ mod tests {
         let (_rest, program) = result.unwrap();
         assert_eq!(
             program.to_bytes(),
-            [
+            Ok(vec![
                 1, 0, 0, 100, //
                 1, 1, 1, 244, //
                 5, 0, 1, 3, //
                 15, 0, 1, //
                 0, //
-            ]
+            ])
         )
     }
+
+    #[test]
+    fn test_program_to_object() {
+        let (_rest, program) = program(CompleteStr(
+            ".global start
+.extern print
+start: load $0 @print
+loop: jmpc @loop
+",
+        ))
+        .unwrap();
+        let object = program.to_object().unwrap();
+        assert_eq!(object.code, vec![1, 0, 0, 0, 1, 31, 0, 0, 9, 31]);
+        assert_eq!(object.imports, vec!["print".to_string()]);
+        assert_eq!(
+            object
+                .exports()
+                .map(|s| s.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["start"]
+        );
+        assert_eq!(
+            object
+                .relocations
+                .iter()
+                .map(|r| (r.offset, r.expression.to_string()))
+                .collect::<Vec<_>>(),
+            vec![(2, "print".to_string()), (6, "loop".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_program_to_object_errors() {
+        let (_rest, program) = program(CompleteStr(
+            ".global missing
+.extern here
+here: load $0 @typo
+",
+        ))
+        .unwrap();
+        let errors: Vec<String> = program
+            .to_object()
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 1: undefined label `missing`",
+                "line 2: label `here` is already declared",
+                "line 3: undefined symbol `typo`",
+            ]
+        );
+    }
 }
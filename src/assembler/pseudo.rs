@@ -0,0 +1,274 @@
+use crate::assembler::expressions::{BinaryOp, Expression};
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::{AssemblerError, Token};
+use crate::instruction::Opcode;
+
+/// Register the assembler may clobber when expanding pseudo-instructions.
+pub const SCRATCH_REGISTER: u8 = 31;
+
+/// Instructions the assembler accepts but the VM does not implement. Each one
+/// is expanded into a short sequence of real opcodes.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum PseudoOp {
+    MOV,
+    INC,
+    DEC,
+    CLR,
+    LI,
+}
+
+impl PseudoOp {
+    pub fn from_mnemonic(mnemonic: &str) -> Option<PseudoOp> {
+        match mnemonic {
+            "mov" => Some(PseudoOp::MOV),
+            "inc" => Some(PseudoOp::INC),
+            "dec" => Some(PseudoOp::DEC),
+            "clr" => Some(PseudoOp::CLR),
+            "li" => Some(PseudoOp::LI),
+            _ => None,
+        }
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            PseudoOp::MOV => "mov",
+            PseudoOp::INC => "inc",
+            PseudoOp::DEC => "dec",
+            PseudoOp::CLR => "clr",
+            PseudoOp::LI => "li",
+        }
+    }
+}
+
+fn op(code: Opcode, operands: Vec<Token>) -> AssemblerInstruction {
+    let mut operands = operands.into_iter();
+    AssemblerInstruction {
+        opcode: Some(Token::Op { code }),
+        operand1: operands.next(),
+        operand2: operands.next(),
+        operand3: operands.next(),
+        ..Default::default()
+    }
+}
+
+fn register(number: u8) -> Token {
+    Token::Register { number }
+}
+
+fn integer(value: i64) -> Token {
+    Token::IntegerOperand { value }
+}
+
+fn scratch() -> Token {
+    register(SCRATCH_REGISTER)
+}
+
+// Splits an immediate into the operands of a `load` of its lower half and a
+// `lui` of its upper half.
+fn split_immediate(token: &Token) -> Result<(Token, Token), AssemblerError> {
+    let expression = match token {
+        Token::IntegerOperand { value } => {
+            if *value < i64::from(i32::MIN) || *value > i64::from(u32::MAX) {
+                return Err(AssemblerError::ImmediateOutOfRange {
+                    value: *value,
+                    bits: 32,
+                });
+            }
+            let bits = *value as u32;
+            return Ok((
+                integer(i64::from(bits & 0xFFFF)),
+                integer(i64::from(bits >> 16)),
+            ));
+        }
+        Token::LabelUsage { name } => Expression::Symbol(name.clone()),
+        Token::Expression { expression } => expression.clone(),
+        _ => return Err(AssemblerError::OpcodeInOperandField),
+    };
+    let mask = |expression| Token::Expression {
+        expression: Expression::Binary(
+            BinaryOp::And,
+            Box::new(expression),
+            Box::new(Expression::Number(0xFFFF)),
+        ),
+    };
+    let upper = Expression::Binary(
+        BinaryOp::Shr,
+        Box::new(expression.clone()),
+        Box::new(Expression::Number(16)),
+    );
+    Ok((mask(expression), mask(upper)))
+}
+
+/// Expands a pseudo-instruction, or a jump to an immediate target, into real
+/// instructions. Anything else is returned unchanged. The first instruction
+/// of the expansion keeps the label and every one keeps the location.
+pub fn expand(
+    instruction: &AssemblerInstruction,
+) -> Result<Vec<AssemblerInstruction>, AssemblerError> {
+    let operands = (
+        &instruction.operand1,
+        &instruction.operand2,
+        &instruction.operand3,
+    );
+    let mut expanded = match (&instruction.opcode, operands) {
+        (Some(Token::PseudoOp { code }), _)
+            if instruction.operands().any(|operand| *operand == scratch()) =>
+        {
+            return Err(AssemblerError::ScratchRegisterOperand {
+                mnemonic: code.mnemonic().to_string(),
+            })
+        }
+        (Some(Token::PseudoOp { code }), operands) => match (code, operands) {
+            (
+                PseudoOp::MOV,
+                (Some(from @ Token::Register { .. }), Some(to @ Token::Register { .. }), None),
+            ) => {
+                vec![
+                    op(Opcode::LOAD, vec![scratch(), integer(0)]),
+                    op(Opcode::ADD, vec![from.clone(), scratch(), to.clone()]),
+                ]
+            }
+            (PseudoOp::INC, (Some(r @ Token::Register { .. }), None, None)) => vec![
+                op(Opcode::LOAD, vec![scratch(), integer(1)]),
+                op(Opcode::ADD, vec![r.clone(), scratch(), r.clone()]),
+            ],
+            (PseudoOp::DEC, (Some(r @ Token::Register { .. }), None, None)) => vec![
+                op(Opcode::LOAD, vec![scratch(), integer(1)]),
+                op(Opcode::SUB, vec![r.clone(), scratch(), r.clone()]),
+            ],
+            (PseudoOp::CLR, (Some(r @ Token::Register { .. }), None, None)) => {
+                vec![op(Opcode::LOAD, vec![r.clone(), integer(0)])]
+            }
+            (PseudoOp::LI, (Some(r @ Token::Register { .. }), Some(immediate), None)) => {
+                let (lower, upper) = split_immediate(immediate)?;
+                vec![
+                    op(Opcode::LOAD, vec![r.clone(), lower]),
+                    op(Opcode::LUI, vec![r.clone(), upper]),
+                ]
+            }
+            (code, _) => {
+                return Err(AssemblerError::InvalidOperands {
+                    mnemonic: code.mnemonic().to_string(),
+                })
+            }
+        },
+        (
+            Some(Token::Op {
+                code: code @ (Opcode::JMP | Opcode::JMPC),
+            }),
+            (Some(target), None, None),
+        ) if !matches!(target, Token::Register { .. }) => {
+            vec![
+                op(Opcode::LOAD, vec![scratch(), target.clone()]),
+                op(*code, vec![scratch()]),
+            ]
+        }
+        _ => return Ok(vec![instruction.clone()]),
+    };
+
+    for expanded_instruction in expanded.iter_mut() {
+        expanded_instruction.location = instruction.location.clone();
+    }
+    expanded[0].label = instruction.label.clone();
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::instruction_parsers::instruction;
+    use crate::assembler::symbols::SymbolTable;
+    use nom::types::CompleteStr;
+
+    fn expand_bytes(source: &str, symbols: &SymbolTable) -> Vec<u8> {
+        let (_rest, parsed) = instruction(CompleteStr(source)).unwrap();
+        expand(&parsed)
+            .unwrap()
+            .iter()
+            .flat_map(|ins| ins.to_bytes(symbols).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_expand_register_pseudo_ops() {
+        let symbols = SymbolTable::new();
+        assert_eq!(
+            expand_bytes("mov $1 $2", &symbols),
+            vec![1, 31, 0, 0, 2, 1, 31, 2]
+        );
+        assert_eq!(
+            expand_bytes("inc $4", &symbols),
+            vec![1, 31, 0, 1, 2, 4, 31, 4]
+        );
+        assert_eq!(
+            expand_bytes("dec $4", &symbols),
+            vec![1, 31, 0, 1, 3, 4, 31, 4]
+        );
+        assert_eq!(expand_bytes("clr $4", &symbols), vec![1, 4, 0, 0]);
+    }
+
+    #[test]
+    fn test_expand_li() {
+        let mut symbols = SymbolTable::new();
+        assert_eq!(
+            expand_bytes("li $2 #-2", &symbols),
+            vec![1, 2, 0xFF, 0xFE, 16, 2, 0xFF, 0xFF]
+        );
+        symbols.add_symbol("far", 0x1234);
+        assert_eq!(
+            expand_bytes("li $2 @far", &symbols),
+            vec![1, 2, 0x12, 0x34, 16, 2, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_expand_immediate_jumps() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol("loop", 28);
+        assert_eq!(
+            expand_bytes("jmpc @loop", &symbols),
+            vec![1, 31, 0, 28, 9, 31]
+        );
+        assert_eq!(expand_bytes("jmp #4", &symbols), vec![1, 31, 0, 4, 6, 31]);
+        assert_eq!(expand_bytes("jmp $3", &symbols), vec![6, 3]);
+    }
+
+    #[test]
+    fn test_expand_keeps_label() {
+        let (_rest, parsed) = instruction(CompleteStr("top: inc $1")).unwrap();
+        let expanded = expand(&parsed).unwrap();
+        assert_eq!(expanded[0].label_name(), Some("top"));
+        assert_eq!(expanded[1].label, None);
+    }
+
+    #[test]
+    fn test_expand_scratch_register() {
+        for (source, mnemonic) in [
+            ("mov $31 $1", "mov"),
+            ("mov $1 $31", "mov"),
+            ("inc $31", "inc"),
+            ("dec $31", "dec"),
+            ("clr $31", "clr"),
+            ("li $31 #7", "li"),
+        ] {
+            let (_rest, parsed) = instruction(CompleteStr(source)).unwrap();
+            assert_eq!(
+                expand(&parsed),
+                Err(AssemblerError::ScratchRegisterOperand {
+                    mnemonic: mnemonic.to_string()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_expand_invalid_operands() {
+        let (_rest, parsed) = instruction(CompleteStr("mov $1 #2")).unwrap();
+        assert_eq!(
+            expand(&parsed),
+            Err(AssemblerError::InvalidOperands {
+                mnemonic: "mov".to_string()
+            })
+        );
+    }
+}
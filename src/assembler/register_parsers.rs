@@ -1,22 +1,23 @@
 use crate::assembler::Token;
+use crate::vm::REGISTER_COUNT;
 use nom::types::CompleteStr;
 use nom::*;
 
 named!(
     pub register <CompleteStr, Token>,
-    ws!(
-        do_parse!(
-            tag!("$") >>
-            number: digit >>
-            (
-                Token::Register {
-                    number: number.parse::<u8>().unwrap()
-                }
-            )
+    do_parse!(
+        tag!("$") >>
+        number: map_res!(digit, |digits: CompleteStr| match digits.0.parse::<u8>() {
+            Ok(number) if usize::from(number) < REGISTER_COUNT => Ok(number),
+            _ => Err("no such register"),
+        }) >>
+        (
+            Token::Register { number }
         )
     )
 );
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -33,5 +34,8 @@ mod tests {
         let result = register(CompleteStr("$23"));
         let (_rest, token) = result.unwrap();
         assert_eq!(token, Token::Register { number: 23 });
+        assert_eq!(register(CompleteStr("$31")).is_ok(), true);
+        assert_eq!(register(CompleteStr("$32")).is_ok(), false);
+        assert_eq!(register(CompleteStr("$300")).is_ok(), false);
     }
 }
@@ -0,0 +1,161 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Where a line of assembly came from. Lines produced by a macro expansion
+/// point at the line in the macro body and remember the call site. Source
+/// that was not read from a file has no `file`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Location {
+    pub file: Option<Rc<PathBuf>>,
+    pub line: usize,
+    pub expansion: Option<Box<Expansion>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expansion {
+    pub macro_name: String,
+    pub call_site: Location,
+}
+
+impl Location {
+    pub fn new(line: usize) -> Location {
+        Location {
+            file: None,
+            line,
+            expansion: None,
+        }
+    }
+
+    pub fn in_file(file: &Rc<PathBuf>, line: usize) -> Location {
+        Location {
+            file: Some(Rc::clone(file)),
+            line,
+            expansion: None,
+        }
+    }
+
+    pub fn expanded(&self, macro_name: &str, call_site: &Location) -> Location {
+        Location {
+            file: self.file.clone(),
+            line: self.line,
+            expansion: Some(Box::new(Expansion {
+                macro_name: macro_name.to_string(),
+                call_site: call_site.clone(),
+            })),
+        }
+    }
+
+    /// The file and line without the expansion chain, e.g. `lib.asm:4`.
+    pub fn position(&self) -> String {
+        match (&self.file, self.line) {
+            (Some(file), 0) => file.display().to_string(),
+            (Some(file), line) => format!("{}:{}", file.display(), line),
+            (None, line) => format!("line {}", line),
+        }
+    }
+
+    /// The macro calls that produced this line, e.g.
+    /// `, in macro `inc` expanded at line 2`. Empty outside of macros.
+    pub fn expansions(&self) -> String {
+        let mut description = String::new();
+        let mut expansion = &self.expansion;
+        while let Some(e) = expansion {
+            description.push_str(&format!(
+                ", in macro `{}` expanded at {}",
+                e.macro_name,
+                e.call_site.position()
+            ));
+            expansion = &e.call_site.expansion;
+        }
+        description
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.position(), self.expansions())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub text: String,
+    pub location: Location,
+}
+
+pub fn source_lines(source: &str) -> Vec<SourceLine> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| SourceLine {
+            text: text.to_string(),
+            location: Location::new(i + 1),
+        })
+        .collect()
+}
+
+pub fn file_lines(source: &str, file: &Rc<PathBuf>) -> Vec<SourceLine> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| SourceLine {
+            text: text.to_string(),
+            location: Location::in_file(file, i + 1),
+        })
+        .collect()
+}
+
+/// Returns the index of the first `;` or `//` that is not inside a character
+/// literal.
+pub fn comment_start(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut quoted = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if quoted => i += 1,
+            b'\'' => quoted = !quoted,
+            b';' if !quoted => return Some(i),
+            b'/' if !quoted && bytes.get(i + 1) == Some(&b'/') => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_lines() {
+        let lines = source_lines("load $0 #1\r\n\r\nhlt");
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].text, "load $0 #1");
+        assert_eq!(lines[2].location, Location::new(3));
+    }
+
+    #[test]
+    fn test_display_expanded_location() {
+        let outer = Location::new(2).expanded("twice", &Location::new(20));
+        let inner = Location::new(7).expanded("inc", &outer);
+        assert_eq!(
+            inner.to_string(),
+            "line 7, in macro `inc` expanded at line 2, in macro `twice` expanded at line 20"
+        );
+    }
+
+    #[test]
+    fn test_display_file_location() {
+        let lib = Rc::new(PathBuf::from("lib/math.asm"));
+        let main = Rc::new(PathBuf::from("main.asm"));
+        let location = Location::in_file(&lib, 3).expanded("mul3", &Location::in_file(&main, 9));
+        assert_eq!(
+            location.to_string(),
+            "lib/math.asm:3, in macro `mul3` expanded at main.asm:9"
+        );
+        assert_eq!(Location::in_file(&main, 0).to_string(), "main.asm");
+    }
+}
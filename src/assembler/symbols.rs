@@ -0,0 +1,53 @@
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: u32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable { symbols: vec![] }
+    }
+
+    /// Returns false if a symbol with this name already exists.
+    pub fn add_symbol(&mut self, name: &str, offset: u32) -> bool {
+        if self.symbol_value(name).is_some() {
+            return false;
+        }
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            offset,
+        });
+        return true;
+    }
+
+    pub fn symbol_value(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.offset)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_table() {
+        let mut table = SymbolTable::new();
+        assert_eq!(table.add_symbol("loop", 12), true);
+        assert_eq!(table.add_symbol("loop", 16), false);
+        assert_eq!(table.symbol_value("loop"), Some(12));
+        assert_eq!(table.symbol_value("end"), None);
+    }
+}
@@ -0,0 +1,489 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::assembler::listing::MapLabel;
+use crate::disassembler::{decode_with, Decoded, DecodedItem, Disassembler, Operand};
+use crate::instruction::{Encoding, Opcode};
+use crate::vm::custom::CustomInstructions;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    /// Execution continues with the next block.
+    FallThrough,
+    Jump,
+    /// The jump taken by a `jmpc` or a branch whose condition holds.
+    Branch,
+}
+
+/// An edge between the blocks at indices `from` and `to`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// A run of instructions that is only ever entered at its first one and
+/// only ever left after its last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    pub instructions: Vec<DecodedItem>,
+    pub labels: Vec<String>,
+    /// Whether the program can stop after the block by running or jumping
+    /// off its end.
+    pub exits: bool,
+}
+
+impl BasicBlock {
+    /// The block's labels, or its offset if it has none.
+    pub fn name(&self) -> String {
+        if self.labels.is_empty() {
+            format!("{}", self.start)
+        } else {
+            self.labels.join(", ")
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+    /// Offsets of jumps whose target could not be worked out, because the
+    /// register was not set by a `load` earlier in the same block of code.
+    pub unresolved: Vec<usize>,
+    /// Indices of the blocks where a thread or an interrupt handler starts:
+    /// the first block, and every one that a `spawn` or `setvec` points at.
+    pub entries: Vec<usize>,
+}
+
+fn is_jump(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::JMP | Opcode::JMPB | Opcode::JMPF | Opcode::JMPC
+    ) || opcode.is_branch()
+}
+
+// Whether the jump only happens when a condition holds.
+fn is_conditional(opcode: Opcode) -> bool {
+    opcode == Opcode::JMPC || opcode.is_branch()
+}
+
+// Whether the instruction leaves the program, or at least the code it is
+// in, without jumping.
+fn stops(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::HLT | Opcode::IRET)
+}
+
+// Whether execution can't simply carry on after the item.
+fn ends_block(item: &DecodedItem) -> bool {
+    match &item.decoded {
+        Decoded::Instruction { opcode, .. } => is_jump(*opcode) || stops(*opcode),
+        Decoded::Byte(_) => true,
+    }
+}
+
+// Where each jump goes, if its register was loaded with a constant since
+// the last jump or leader.
+fn jump_targets(items: &[DecodedItem], leaders: &BTreeSet<usize>) -> Vec<Option<i64>> {
+    let mut known: [Option<i32>; 32] = [None; 32];
+    let mut targets = vec![];
+    for item in items {
+        if leaders.contains(&item.offset) {
+            known = [None; 32];
+        }
+        let (opcode, operands) = match &item.decoded {
+            Decoded::Instruction { opcode, operands } => (*opcode, operands.as_slice()),
+            Decoded::Byte(_) => {
+                targets.push(None);
+                known = [None; 32];
+                continue;
+            }
+        };
+        let value = |register: &u8| known.get(usize::from(*register)).cloned().flatten();
+        let next = (item.offset + item.length) as i64;
+        let target = match (opcode, operands) {
+            (Opcode::JMP, [Operand::Register(r)]) | (Opcode::JMPC, [Operand::Register(r)]) => {
+                value(r).map(i64::from)
+            }
+            (Opcode::JMPB, [Operand::Register(r)]) => value(r).map(|v| next - i64::from(v)),
+            (Opcode::JMPF, [Operand::Register(r)]) => value(r).map(|v| next + i64::from(v)),
+            (_, [_, _, Operand::Immediate(target)]) if opcode.is_branch() => {
+                Some(i64::from(*target))
+            }
+            (Opcode::SPAWN | Opcode::SETVEC, [_, Operand::Immediate(target)]) => {
+                Some(i64::from(*target))
+            }
+            _ => None,
+        };
+        targets.push(target);
+
+        let written = match (opcode, operands) {
+            (Opcode::LOAD, [Operand::Register(r), Operand::Immediate(v)]) => {
+                Some((*r, Some(i32::from(*v))))
+            }
+            (Opcode::LUI, [Operand::Register(r), Operand::Immediate(upper)]) => Some((
+                *r,
+                value(r).map(|lower| ((u32::from(*upper) << 16) | (lower as u32 & 0xFFFF)) as i32),
+            )),
+            (_, [_, _, Operand::Register(r)])
+            | (Opcode::SPAWN, [Operand::Register(r), _])
+            | (Opcode::RECV, [Operand::Register(r)]) => Some((*r, None)),
+            _ => None,
+        };
+        if let Some((register, value)) = written {
+            if let Some(slot) = known.get_mut(usize::from(register)) {
+                *slot = value;
+            }
+        }
+        if ends_block(item) {
+            known = [None; 32];
+        }
+    }
+    targets
+}
+
+impl ControlFlowGraph {
+    /// The graph of a program in the encoding its header names. Offsets
+    /// count from after the header.
+    pub fn new(bytecode: &[u8]) -> ControlFlowGraph {
+        let (encoding, program) = Encoding::detect(bytecode);
+        let items = decode_with(program, encoding, &CustomInstructions::new());
+        let starts: BTreeSet<usize> = items.iter().map(|item| item.offset).collect();
+
+        // Registers may hold anything where a jump lands, so forgetting
+        // them there can only lose targets, and this settles once no new
+        // leaders turn up.
+        let mut leaders = BTreeSet::new();
+        let targets = loop {
+            let targets = jump_targets(&items, &leaders);
+            let mut found = BTreeSet::new();
+            found.insert(0);
+            for (item, target) in items.iter().zip(&targets) {
+                if let Some(target) = target {
+                    if *target >= 0 && starts.contains(&(*target as usize)) {
+                        found.insert(*target as usize);
+                    }
+                }
+                if ends_block(item) {
+                    found.insert(item.offset + item.length);
+                }
+            }
+            if found.is_subset(&leaders) {
+                break targets;
+            }
+            leaders.extend(found);
+        };
+
+        let mut blocks: Vec<BasicBlock> = vec![];
+        for item in &items {
+            if leaders.contains(&item.offset) || blocks.is_empty() {
+                blocks.push(BasicBlock {
+                    start: item.offset,
+                    end: item.offset,
+                    instructions: vec![],
+                    labels: vec![],
+                    exits: false,
+                });
+            }
+            let block = blocks.last_mut().unwrap();
+            block.end = item.offset + item.length;
+            block.instructions.push(item.clone());
+        }
+
+        let block_at = |offset: i64| blocks.iter().position(|block| block.start as i64 == offset);
+        let mut edges = vec![];
+        let mut unresolved = vec![];
+        let mut exits = vec![];
+        let mut index = 0;
+        for (i, block) in blocks.iter().enumerate() {
+            let last = block.instructions.last().unwrap();
+            index += block.instructions.len();
+            let target = targets[index - 1];
+            let opcode = match &last.decoded {
+                Decoded::Instruction { opcode, .. } => Some(*opcode),
+                Decoded::Byte(_) => None,
+            };
+            if opcode.is_some_and(is_jump) {
+                match target.and_then(block_at) {
+                    Some(to) => edges.push(Edge {
+                        from: i,
+                        to,
+                        kind: if opcode.is_some_and(is_conditional) {
+                            EdgeKind::Branch
+                        } else {
+                            EdgeKind::Jump
+                        },
+                    }),
+                    // A jump to the very end stops the program.
+                    None if target == Some(program.len() as i64) => exits.push(i),
+                    None => unresolved.push(last.offset),
+                }
+            }
+            let falls_through = match opcode {
+                Some(code) if is_conditional(code) => true,
+                Some(code) => !is_jump(code) && !stops(code),
+                None => false,
+            };
+            if falls_through && i + 1 < blocks.len() {
+                edges.push(Edge {
+                    from: i,
+                    to: i + 1,
+                    kind: EdgeKind::FallThrough,
+                });
+            } else if falls_through {
+                exits.push(i);
+            }
+        }
+
+        let mut entries = vec![];
+        if !blocks.is_empty() {
+            entries.push(0);
+        }
+        for (item, target) in items.iter().zip(&targets) {
+            if let Decoded::Instruction {
+                opcode: Opcode::SPAWN | Opcode::SETVEC,
+                ..
+            } = item.decoded
+            {
+                if let Some(entry) = target.and_then(block_at) {
+                    if !entries.contains(&entry) {
+                        entries.push(entry);
+                    }
+                }
+            }
+        }
+
+        for i in exits {
+            blocks[i].exits = true;
+        }
+
+        ControlFlowGraph {
+            blocks,
+            edges,
+            unresolved,
+            entries,
+        }
+    }
+
+    /// Names blocks after the labels that point at them.
+    pub fn with_labels(mut self, labels: &[MapLabel]) -> ControlFlowGraph {
+        for label in labels {
+            let start = label.address as usize;
+            if let Some(block) = self.blocks.iter_mut().find(|block| block.start == start) {
+                block.labels.push(label.name.clone());
+            }
+        }
+        self
+    }
+
+    pub fn successors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.from == block)
+    }
+
+    pub fn predecessors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.to == block)
+    }
+
+    /// The graph in Graphviz format, with each block's disassembly.
+    pub fn to_dot(&self) -> String {
+        let disassembler = Disassembler::new().resugar(true);
+        let mut dot = String::from("digraph cfg {\n");
+        dot.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        for block in &self.blocks {
+            let mut text = format!("{}:\\l", block.name());
+            for line in disassembler.lines(&block.instructions) {
+                text.push_str(&format!("{:5}  {}\\l", line.offset, line.text));
+            }
+            dot.push_str(&format!(
+                "    b{} [label=\"{}\"];\n",
+                block.start,
+                text.replace('"', "\\\"")
+            ));
+        }
+        for edge in &self.edges {
+            let attributes = match edge.kind {
+                EdgeKind::FallThrough => " [style=dashed]",
+                EdgeKind::Jump => "",
+                EdgeKind::Branch => " [label=\"taken\"]",
+            };
+            dot.push_str(&format!(
+                "    b{} -> b{}{};\n",
+                self.blocks[edge.from].start, self.blocks[edge.to].start, attributes
+            ));
+        }
+        if !self.unresolved.is_empty() {
+            dot.push_str("    unknown [label=\"?\", shape=circle];\n");
+            for offset in &self.unresolved {
+                if let Some(block) = self.blocks.iter().find(|block| block.end > *offset) {
+                    dot.push_str(&format!(
+                        "    b{} -> unknown [style=dotted];\n",
+                        block.start
+                    ));
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+impl fmt::Display for ControlFlowGraph {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let disassembler = Disassembler::new().resugar(true);
+        for (i, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "{} ({}..{}):", block.name(), block.start, block.end)?;
+            for line in disassembler.lines(&block.instructions) {
+                writeln!(f, "    {:5}  {}", line.offset, line.text)?;
+            }
+            for edge in self.successors(i) {
+                let kind = match edge.kind {
+                    EdgeKind::FallThrough => "falls through to",
+                    EdgeKind::Jump => "jumps to",
+                    EdgeKind::Branch => "branches to",
+                };
+                writeln!(f, "    {} {}", kind, self.blocks[edge.to].name())?;
+            }
+            if self
+                .unresolved
+                .iter()
+                .any(|offset| (block.start..block.end).contains(offset))
+            {
+                writeln!(f, "    jumps to an unknown address")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn fibonacci() -> ControlFlowGraph {
+        let assembly = Assembler::new()
+            .assemble_detailed(
+                "start: clr $0\n\tli $1 #1\n\tli $6 #20\n\
+                 loop: mov $1 $2\n\tadd $0 $1 $1\n\tmov $2 $0\n\tinc $4\n\
+                 \tlt $4 $6\n\tjmpc @loop\n\
+                 \thlt\n",
+            )
+            .unwrap();
+        ControlFlowGraph::new(&assembly.bytes).with_labels(&assembly.map.labels)
+    }
+
+    #[test]
+    fn test_blocks_and_edges() {
+        let cfg = fibonacci();
+        let blocks: Vec<(String, usize, usize)> = cfg
+            .blocks
+            .iter()
+            .map(|block| (block.name(), block.start, block.end))
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                ("start".to_string(), 0, 20),
+                ("loop".to_string(), 20, 57),
+                ("57".to_string(), 57, 58),
+            ]
+        );
+        assert_eq!(
+            cfg.edges,
+            vec![
+                Edge {
+                    from: 0,
+                    to: 1,
+                    kind: EdgeKind::FallThrough
+                },
+                Edge {
+                    from: 1,
+                    to: 1,
+                    kind: EdgeKind::Branch
+                },
+                Edge {
+                    from: 1,
+                    to: 2,
+                    kind: EdgeKind::FallThrough
+                },
+            ]
+        );
+        assert_eq!(cfg.predecessors(1).count(), 2);
+        assert!(cfg.unresolved.is_empty());
+    }
+
+    #[test]
+    fn test_relative_and_unknown_jumps() {
+        let program = vec![
+            1, 0, 0, 4, // LOAD $0 #4
+            8, 0, //       JMPF $0, to the second LOAD
+            6, 1, //       JMP $1, to an unknown address
+            0, 0, //       HLT, HLT
+            1, 0, 0, 8, // LOAD $0 #8
+            7, 0, //       JMPB $0, back to the first HLT
+        ];
+        let cfg = ControlFlowGraph::new(&program);
+        let starts: Vec<usize> = cfg.blocks.iter().map(|block| block.start).collect();
+        assert_eq!(starts, vec![0, 6, 8, 9, 10]);
+        let edges: Vec<(usize, usize)> = cfg.edges.iter().map(|e| (e.from, e.to)).collect();
+        assert_eq!(edges, vec![(0, 4), (4, 2)]);
+        assert_eq!(cfg.unresolved, vec![6]);
+        assert_eq!(cfg.entries, vec![0]);
+    }
+
+    #[test]
+    fn test_jump_target_reached_from_two_paths() {
+        let assembly = Assembler::new()
+            .assemble_detailed("\tload $0 @fine\nt:\tjmp $0\nfine:\tload $0 #1\n\tjmp @t\n")
+            .unwrap();
+        let cfg = ControlFlowGraph::new(&assembly.bytes);
+        let starts: Vec<usize> = cfg.blocks.iter().map(|block| block.start).collect();
+        assert_eq!(starts, vec![0, 4, 6]);
+        let edges: Vec<(usize, usize)> = cfg.edges.iter().map(|e| (e.from, e.to)).collect();
+        assert_eq!(edges, vec![(0, 1), (2, 1)]);
+        assert_eq!(cfg.unresolved, vec![4]);
+    }
+
+    #[test]
+    fn test_spawned_threads() {
+        let program = vec![
+            27, 1, 0, 6,  // SPAWN $1 #6
+            0,  //          HLT
+            0,  //          HLT
+            28, //         YIELD
+        ];
+        let cfg = ControlFlowGraph::new(&program);
+        let starts: Vec<usize> = cfg.blocks.iter().map(|block| block.start).collect();
+        assert_eq!(starts, vec![0, 5, 6]);
+        assert!(cfg.edges.is_empty());
+        assert_eq!(cfg.entries, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_interrupt_handlers() {
+        let program = vec![
+            34, 0, 0, 5,  // SETVEC $0 #5
+            0,  //          HLT
+            33, //          IRET, which doesn't fall through
+            28, //          YIELD
+        ];
+        let cfg = ControlFlowGraph::new(&program);
+        let starts: Vec<usize> = cfg.blocks.iter().map(|block| block.start).collect();
+        assert_eq!(starts, vec![0, 5, 6]);
+        assert!(cfg.edges.is_empty());
+        assert_eq!(cfg.entries, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_dot() {
+        let dot = fibonacci().to_dot();
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains("    b20 [label=\"loop:\\l   20  mov $1 $2\\l   28  add $0 $1 $1\\l"));
+        assert!(dot.contains("    b20 -> b20 [label=\"taken\"];\n"));
+        assert!(dot.contains("    b0 -> b20 [style=dashed];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+}
@@ -0,0 +1,747 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::aot::translate;
+use crate::assembler::{Assembler, Assembly};
+use crate::cfg::ControlFlowGraph;
+use crate::debug_info::DebugInfo;
+use crate::instruction::Encoding;
+use crate::linker::object::ObjectFile;
+use crate::linker::Linker;
+use crate::lint::lint;
+use crate::verifier::Problem;
+use crate::vm::{Engine, LoadMode, JIT_SUPPORTED, VM};
+
+pub const USAGE: &str = "\
+usage: asmvm                                  start the REPL
+       asmvm asm [-c] [-O] [--explain-opt] [--fixed] [-I dir]... [-o out]
+                 [--listing file] [--map file] [--debug-info file] file.asm
+                                              assemble a program, or an object file with -c;
+                                              --fixed encodes every instruction in one word
+       asmvm link [-o out] [--map file] file.o...
+                                              link object files into a program
+       asmvm run [-O] [--engine name] [--trace] [--verify] [-I dir]... file
+                                              run a program, assembling it first if it
+                                              ends in .asm; the engine is interpreter,
+                                              closures or jit
+       asmvm cfg [-O] [--dot] [-I dir]... [-o out] file
+                                              show a program's control-flow graph
+       asmvm lint [-I dir]... file            warn about suspicious register use and
+                                              control flow
+       asmvm bench [-O] [--engine name] [-n runs] [-I dir]... file
+                                              time how long a program takes to run; the
+                                              decoding engine decodes every instruction
+                                              each time it runs, as a baseline
+       asmvm aot [-O] [-I dir]... [-o out] file
+                                              translate a program to C";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Repl,
+    Assemble {
+        source: PathBuf,
+        output: PathBuf,
+        object: bool,
+        include_paths: Vec<PathBuf>,
+        listing: Option<PathBuf>,
+        map: Option<PathBuf>,
+        debug_info: Option<PathBuf>,
+        optimize: bool,
+        explain_optimizations: bool,
+        encoding: Encoding,
+    },
+    Link {
+        objects: Vec<PathBuf>,
+        output: PathBuf,
+        map: Option<PathBuf>,
+    },
+    Run {
+        program: PathBuf,
+        include_paths: Vec<PathBuf>,
+        optimize: bool,
+        trace: bool,
+        verify: bool,
+        engine: Engine,
+    },
+    Cfg {
+        program: PathBuf,
+        include_paths: Vec<PathBuf>,
+        optimize: bool,
+        output: Option<PathBuf>,
+        dot: bool,
+    },
+    Lint {
+        program: PathBuf,
+        include_paths: Vec<PathBuf>,
+    },
+    Aot {
+        program: PathBuf,
+        output: PathBuf,
+        include_paths: Vec<PathBuf>,
+        optimize: bool,
+    },
+    Bench {
+        program: PathBuf,
+        include_paths: Vec<PathBuf>,
+        optimize: bool,
+        runs: u32,
+        engine: Engine,
+    },
+}
+
+/// Parses the arguments following the program name.
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (command, rest) = match args.split_first() {
+        None => return Ok(Command::Repl),
+        Some((command, rest)) => (command.as_str(), rest),
+    };
+
+    let mut output = None;
+    let mut listing = None;
+    let mut map = None;
+    let mut debug_info = None;
+    let mut object = false;
+    let mut trace = false;
+    let mut verify = false;
+    let mut engine = Engine::Interpreter;
+    let mut dot = false;
+    let mut optimize = false;
+    let mut explain_optimizations = false;
+    let mut encoding = Encoding::Variable;
+    let mut runs = None;
+    let mut include_paths = vec![];
+    let mut inputs = vec![];
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-o" | "-I" | "--listing" | "--map" | "--debug-info" => {
+                let value = match rest.next() {
+                    Some(value) => PathBuf::from(value),
+                    None => return Err(format!("`{}` needs an argument", arg)),
+                };
+                match arg.as_str() {
+                    "-o" => output = Some(value),
+                    "-I" => include_paths.push(value),
+                    "--listing" => listing = Some(value),
+                    "--map" => map = Some(value),
+                    _ => debug_info = Some(value),
+                }
+            }
+            "-n" if command == "bench" => {
+                runs = match rest.next().map(|value| value.parse::<u32>()) {
+                    Some(Ok(value)) if value > 0 => Some(value),
+                    Some(_) => return Err("`-n` needs a positive number of runs".to_string()),
+                    None => return Err("`-n` needs an argument".to_string()),
+                }
+            }
+            "-c" if command == "asm" => object = true,
+            "--trace" if command == "run" => trace = true,
+            "--verify" if command == "run" => verify = true,
+            "--engine" if matches!(command, "run" | "bench") => {
+                engine = match rest.next().map(String::as_str) {
+                    Some("interpreter") => Engine::Interpreter,
+                    Some("closures") => Engine::Closures,
+                    Some("decoding") => Engine::Decoding,
+                    Some("jit") if JIT_SUPPORTED => Engine::Jit,
+                    Some("jit") => {
+                        return Err("this build has no JIT; build with `--features jit`".to_string())
+                    }
+                    Some(other) => return Err(format!("unknown engine `{}`", other)),
+                    None => return Err("`--engine` needs an argument".to_string()),
+                }
+            }
+            "--dot" if command == "cfg" => dot = true,
+            "-O" if matches!(command, "asm" | "run" | "cfg" | "bench" | "aot") => optimize = true,
+            "--explain-opt" if command == "asm" => explain_optimizations = true,
+            "--fixed" if command == "asm" => encoding = Encoding::Fixed,
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+
+    match command {
+        "asm" => {
+            if inputs.len() != 1 {
+                return Err("`asm` takes exactly one source file".to_string());
+            }
+            if object && (listing.is_some() || map.is_some() || debug_info.is_some()) {
+                return Err(
+                    "listings, map files and debug info cannot be produced with `-c`".to_string(),
+                );
+            }
+            if object && explain_optimizations {
+                return Err("`--explain-opt` cannot be used with `-c`".to_string());
+            }
+            if object && encoding == Encoding::Fixed {
+                return Err("object files cannot use `--fixed`".to_string());
+            }
+            let source = inputs.remove(0);
+            let extension = if object { "o" } else { "bin" };
+            Ok(Command::Assemble {
+                output: output.unwrap_or_else(|| source.with_extension(extension)),
+                source,
+                object,
+                include_paths,
+                listing,
+                map,
+                debug_info,
+                optimize: optimize || explain_optimizations,
+                explain_optimizations,
+                encoding,
+            })
+        }
+        "lint" | "bench" if output.is_some() => Err("unknown option `-o`".to_string()),
+        "link" | "run" | "cfg" | "lint" | "bench" | "aot" if debug_info.is_some() => {
+            Err("unknown option `--debug-info`".to_string())
+        }
+        "link" | "cfg" | "lint" | "bench" | "aot" if listing.is_some() => {
+            Err("unknown option `--listing`".to_string())
+        }
+        "cfg" | "lint" | "bench" | "aot" if map.is_some() => {
+            Err("unknown option `--map`".to_string())
+        }
+        "aot" if inputs.len() != 1 => Err("`aot` takes exactly one program".to_string()),
+        "aot" => {
+            let program = inputs.remove(0);
+            Ok(Command::Aot {
+                output: output.unwrap_or_else(|| program.with_extension("c")),
+                program,
+                include_paths,
+                optimize,
+            })
+        }
+        "bench" if inputs.len() != 1 => Err("`bench` takes exactly one program".to_string()),
+        "bench" => Ok(Command::Bench {
+            program: inputs.remove(0),
+            include_paths,
+            optimize,
+            runs: runs.unwrap_or(10),
+            engine,
+        }),
+        "lint" if inputs.len() != 1 => Err("`lint` takes exactly one program".to_string()),
+        "lint" => Ok(Command::Lint {
+            program: inputs.remove(0),
+            include_paths,
+        }),
+        "cfg" if inputs.len() != 1 => Err("`cfg` takes exactly one program".to_string()),
+        "cfg" => Ok(Command::Cfg {
+            program: inputs.remove(0),
+            include_paths,
+            optimize,
+            output,
+            dot,
+        }),
+        "link" if !include_paths.is_empty() => Err("unknown option `-I`".to_string()),
+        "link" if inputs.is_empty() => Err("`link` needs at least one object file".to_string()),
+        "link" => Ok(Command::Link {
+            objects: inputs,
+            output: output.unwrap_or_else(|| PathBuf::from("a.bin")),
+            map,
+        }),
+        "run" if output.is_some() => Err("unknown option `-o`".to_string()),
+        "run" if listing.is_some() => Err("unknown option `--listing`".to_string()),
+        "run" if map.is_some() => Err("unknown option `--map`".to_string()),
+        "run" if inputs.len() != 1 => Err("`run` takes exactly one program".to_string()),
+        "run" => Ok(Command::Run {
+            program: inputs.remove(0),
+            include_paths,
+            optimize,
+            trace,
+            verify,
+            engine,
+        }),
+        _ => Err(format!("unknown command `{}`", command)),
+    }
+}
+
+fn to_strings<E: ToString>(errors: Vec<E>) -> Vec<String> {
+    errors.iter().map(ToString::to_string).collect()
+}
+
+// Verifier problems, pointing at source lines when there is debug info.
+fn describe_problems(problems: Vec<Problem>, debug_info: Option<&DebugInfo>) -> Vec<String> {
+    problems
+        .iter()
+        .map(
+            |problem| match debug_info.and_then(|info| info.lookup(problem.offset)) {
+                Some(entry) => format!(
+                    "{}: {} (byte {})\n    {}",
+                    entry, problem.error, problem.offset, entry.text
+                ),
+                None => problem.to_string(),
+            },
+        )
+        .collect()
+}
+
+// Reads a program, assembling it along with its debug info and labels if it
+// is a source file.
+fn load_program(
+    path: &Path,
+    include_paths: Vec<PathBuf>,
+    optimize: bool,
+) -> Result<(Vec<u8>, Option<Assembly>), Vec<String>> {
+    if path.extension().is_some_and(|extension| extension == "asm") {
+        let assembly = include_paths
+            .into_iter()
+            .fold(Assembler::new(), Assembler::include_path)
+            .optimize(optimize)
+            .assemble_file_detailed(path)
+            .map_err(to_strings)?;
+        return Ok((assembly.bytes.clone(), Some(assembly)));
+    }
+    let bytes =
+        fs::read(path).map_err(|e| vec![format!("could not read `{}`: {}", path.display(), e)])?;
+    Ok((bytes, None))
+}
+
+fn write(path: &Path, bytes: &[u8]) -> Result<(), Vec<String>> {
+    fs::write(path, bytes).map_err(|e| vec![format!("could not write `{}`: {}", path.display(), e)])
+}
+
+/// Runs a command other than `Repl`, returning the messages to report if it
+/// fails.
+pub fn run(command: Command) -> Result<(), Vec<String>> {
+    match command {
+        Command::Repl => Ok(()),
+        Command::Assemble {
+            source,
+            output,
+            object,
+            include_paths,
+            listing,
+            map,
+            debug_info,
+            optimize,
+            explain_optimizations,
+            encoding,
+        } => {
+            let assembler = include_paths
+                .into_iter()
+                .fold(Assembler::new(), Assembler::include_path)
+                .optimize(optimize)
+                .encoding(encoding);
+            if object {
+                let object = assembler
+                    .assemble_file_object(&source)
+                    .map_err(to_strings)?;
+                return write(&output, &object.to_bytes());
+            }
+            if listing.is_none() && map.is_none() && debug_info.is_none() && !explain_optimizations
+            {
+                let bytes = assembler.assemble_file(&source).map_err(to_strings)?;
+                return write(&output, &bytes);
+            }
+            let assembly = assembler
+                .assemble_file_detailed(&source)
+                .map_err(to_strings)?;
+            if explain_optimizations {
+                for optimization in &assembly.optimizations {
+                    println!("{}", optimization);
+                }
+            }
+            if let Some(listing) = listing {
+                write(&listing, assembly.listing.to_string().as_bytes())?;
+            }
+            if let Some(map) = map {
+                write(&map, assembly.map.to_string().as_bytes())?;
+            }
+            if let Some(debug_info) = debug_info {
+                write(&debug_info, assembly.debug_info.to_string().as_bytes())?;
+            }
+            write(&output, &assembly.bytes)
+        }
+        Command::Link {
+            objects,
+            output,
+            map,
+        } => {
+            let mut linker = Linker::new();
+            for path in objects {
+                let name = path.display().to_string();
+                let bytes = fs::read(&path)
+                    .map_err(|e| vec![format!("could not read `{}`: {}", name, e)])?;
+                let object =
+                    ObjectFile::from_bytes(&bytes).map_err(|e| vec![format!("{}: {}", name, e)])?;
+                linker = linker.object(name, object);
+            }
+            let program = linker.link().map_err(to_strings)?;
+            if let Some(map) = map {
+                write(&map, linker.map().to_string().as_bytes())?;
+            }
+            write(&output, &program)
+        }
+        Command::Run {
+            program,
+            include_paths,
+            optimize,
+            trace,
+            verify,
+            engine,
+        } => {
+            let mut vm = VM::new();
+            vm.set_engine(engine);
+            if verify {
+                vm.set_load_mode(LoadMode::Verified);
+            }
+            // Source files are assembled with debug info so that faults and
+            // traces can point at the line responsible.
+            let (bytes, assembly) = load_program(&program, include_paths, optimize)?;
+            let debug_info = assembly.map(|assembly| assembly.debug_info);
+            vm.load(bytes, debug_info.clone())
+                .map_err(|problems| describe_problems(problems, debug_info.as_ref()))?;
+            if trace {
+                vm.set_tracer(|entry| eprintln!("{}", entry));
+            }
+            vm.run().map_err(|fault| vec![fault.to_string()])
+        }
+        Command::Cfg {
+            program,
+            include_paths,
+            optimize,
+            output,
+            dot,
+        } => {
+            let (bytes, assembly) = load_program(&program, include_paths, optimize)?;
+            let mut cfg = ControlFlowGraph::new(&bytes);
+            if let Some(assembly) = assembly {
+                cfg = cfg.with_labels(&assembly.map.labels);
+            }
+            let text = if dot { cfg.to_dot() } else { cfg.to_string() };
+            match output {
+                Some(output) => write(&output, text.as_bytes()),
+                None => {
+                    print!("{}", text);
+                    Ok(())
+                }
+            }
+        }
+        Command::Lint {
+            program,
+            include_paths,
+        } => {
+            let (bytes, assembly) = load_program(&program, include_paths, false)?;
+            let debug_info = assembly.map(|assembly| assembly.debug_info);
+            for warning in lint(&bytes, debug_info.as_ref()) {
+                println!("{}", warning);
+            }
+            Ok(())
+        }
+        Command::Aot {
+            program,
+            output,
+            include_paths,
+            optimize,
+        } => {
+            let (bytes, assembly) = load_program(&program, include_paths, optimize)?;
+            let c = translate(&bytes).map_err(|unsupported| {
+                let source = assembly
+                    .as_ref()
+                    .and_then(|assembly| assembly.debug_info.lookup(unsupported.offset));
+                vec![match source {
+                    Some(entry) => format!("{}: {}\n    {}", entry, unsupported, entry.text),
+                    None => format!("byte {}: {}", unsupported.offset, unsupported),
+                }]
+            })?;
+            write(&output, c.as_bytes())
+        }
+        Command::Bench {
+            program,
+            include_paths,
+            optimize,
+            runs,
+            engine,
+        } => {
+            let (bytes, _) = load_program(&program, include_paths, optimize)?;
+            let mut vm = VM::new();
+            vm.set_engine(engine);
+            let mut total = Duration::from_secs(0);
+            let mut fastest = None;
+            for _ in 0..runs {
+                vm.load(bytes.clone(), None).unwrap();
+                let start = Instant::now();
+                vm.run().map_err(|fault| vec![fault.to_string()])?;
+                let elapsed = start.elapsed();
+                total += elapsed;
+                fastest = Some(fastest.map_or(elapsed, |fastest: Duration| fastest.min(elapsed)));
+            }
+            println!(
+                "{}: {} runs, {:.2} ms per run, fastest {:.2} ms",
+                program.display(),
+                runs,
+                milliseconds(total) / f64::from(runs),
+                milliseconds(fastest.unwrap_or(total))
+            );
+            Ok(())
+        }
+    }
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::ScratchDir;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(&[]), Ok(Command::Repl));
+        assert_eq!(
+            parse_args(&args("asm -c -I lib main.asm")),
+            Ok(Command::Assemble {
+                source: PathBuf::from("main.asm"),
+                output: PathBuf::from("main.o"),
+                object: true,
+                include_paths: vec![PathBuf::from("lib")],
+                listing: None,
+                map: None,
+                debug_info: None,
+                optimize: false,
+                explain_optimizations: false,
+                encoding: Encoding::Variable,
+            })
+        );
+        assert_eq!(
+            parse_args(&args("asm main.asm --listing main.lst")),
+            Ok(Command::Assemble {
+                source: PathBuf::from("main.asm"),
+                output: PathBuf::from("main.bin"),
+                object: false,
+                include_paths: vec![],
+                listing: Some(PathBuf::from("main.lst")),
+                map: None,
+                debug_info: None,
+                optimize: false,
+                explain_optimizations: false,
+                encoding: Encoding::Variable,
+            })
+        );
+        assert_eq!(
+            parse_args(&args("link a.o b.o -o prog.bin")),
+            Ok(Command::Link {
+                objects: vec![PathBuf::from("a.o"), PathBuf::from("b.o")],
+                output: PathBuf::from("prog.bin"),
+                map: None,
+            })
+        );
+        assert_eq!(
+            parse_args(&args("run --trace prog.asm")),
+            Ok(Command::Run {
+                program: PathBuf::from("prog.asm"),
+                include_paths: vec![],
+                optimize: false,
+                trace: true,
+                verify: false,
+                engine: Engine::Interpreter,
+            })
+        );
+        assert_eq!(
+            parse_args(&args("asm --explain-opt main.asm")),
+            Ok(Command::Assemble {
+                source: PathBuf::from("main.asm"),
+                output: PathBuf::from("main.bin"),
+                object: false,
+                include_paths: vec![],
+                listing: None,
+                map: None,
+                debug_info: None,
+                optimize: true,
+                explain_optimizations: true,
+                encoding: Encoding::Variable,
+            })
+        );
+        assert_eq!(
+            parse_args(&args("lint -I lib prog.asm")),
+            Ok(Command::Lint {
+                program: PathBuf::from("prog.asm"),
+                include_paths: vec![PathBuf::from("lib")],
+            })
+        );
+        assert_eq!(
+            parse_args(&args("bench -O -n 3 fib.asm")),
+            Ok(Command::Bench {
+                program: PathBuf::from("fib.asm"),
+                include_paths: vec![],
+                optimize: true,
+                runs: 3,
+                engine: Engine::Interpreter,
+            })
+        );
+        assert_eq!(
+            parse_args(&args("aot prog.bin")),
+            Ok(Command::Aot {
+                program: PathBuf::from("prog.bin"),
+                output: PathBuf::from("prog.c"),
+                include_paths: vec![],
+                optimize: false,
+            })
+        );
+        assert_eq!(
+            parse_args(&args("run --engine jit prog.bin")).is_ok(),
+            JIT_SUPPORTED
+        );
+        assert_eq!(
+            parse_args(&args("bench --engine closures fib.asm")),
+            Ok(Command::Bench {
+                program: PathBuf::from("fib.asm"),
+                include_paths: vec![],
+                optimize: false,
+                runs: 10,
+                engine: Engine::Closures,
+            })
+        );
+        assert!(matches!(
+            parse_args(&args("bench --engine decoding fib.asm")),
+            Ok(Command::Bench {
+                engine: Engine::Decoding,
+                ..
+            })
+        ));
+        assert_eq!(
+            parse_args(&args("run --engine fast prog.bin")),
+            Err("unknown engine `fast`".to_string())
+        );
+        assert_eq!(
+            parse_args(&args("bench -n 0 fib.asm")),
+            Err("`-n` needs a positive number of runs".to_string())
+        );
+        assert!(matches!(
+            parse_args(&args("asm --fixed main.asm")),
+            Ok(Command::Assemble {
+                encoding: Encoding::Fixed,
+                ..
+            })
+        ));
+        assert_eq!(
+            parse_args(&args("asm -c --fixed main.asm")),
+            Err("object files cannot use `--fixed`".to_string())
+        );
+        assert_eq!(
+            parse_args(&args("link -c a.o")),
+            Err("unknown option `-c`".to_string())
+        );
+        assert_eq!(
+            parse_args(&args("asm a.asm -o")),
+            Err("`-o` needs an argument".to_string())
+        );
+    }
+
+    #[test]
+    fn test_assemble_and_link_files() {
+        let dir = ScratchDir::new("cli");
+        fs::write(dir.join("a.asm"), ".extern f\njmp @f\n").unwrap();
+        fs::write(dir.join("b.asm"), ".global f\nf: hlt\n").unwrap();
+
+        for name in &["a", "b"] {
+            let command = parse_args(&[
+                "asm".to_string(),
+                "-c".to_string(),
+                dir.join(format!("{}.asm", name)).display().to_string(),
+            ])
+            .unwrap();
+            assert_eq!(run(command), Ok(()));
+        }
+        let command = Command::Link {
+            objects: vec![dir.join("a.o"), dir.join("b.o")],
+            output: dir.join("prog.bin"),
+            map: Some(dir.join("prog.map")),
+        };
+        assert_eq!(run(command), Ok(()));
+        let map = fs::read_to_string(dir.join("prog.map")).unwrap();
+        assert!(map.contains(&format!(
+            "      6  f                     {}",
+            dir.join("b.o").display()
+        )));
+        assert_eq!(
+            fs::read(dir.join("prog.bin")).unwrap(),
+            vec![1, 31, 0, 6, 6, 31, 0]
+        );
+
+        let command = Command::Link {
+            objects: vec![dir.join("a.o")],
+            output: dir.join("broken.bin"),
+            map: None,
+        };
+        assert_eq!(
+            run(command),
+            Err(vec![format!(
+                "{}: undefined symbol `f`",
+                dir.join("a.o").display()
+            )])
+        );
+    }
+
+    #[test]
+    fn test_run_reports_source_of_fault() {
+        let dir = ScratchDir::new("run");
+        let source = dir.join("div.asm");
+        fs::write(&source, "load $1 #1\ndiv $1 $0 $2\n").unwrap();
+        let command = Command::Run {
+            program: source.clone(),
+            include_paths: vec![],
+            optimize: false,
+            trace: false,
+            verify: false,
+            engine: Engine::Interpreter,
+        };
+        assert_eq!(
+            run(command),
+            Err(vec![format!(
+                "{}:2:1: division by zero (byte 4)\n    div $1 $0 $2",
+                source.display()
+            )])
+        );
+    }
+
+    #[test]
+    fn test_cfg() {
+        let dir = ScratchDir::new("cfg");
+        fs::write(dir.join("loop.asm"), "loop: inc $0\n\tjmp @loop\n").unwrap();
+        let command = parse_args(&[
+            "cfg".to_string(),
+            "--dot".to_string(),
+            dir.join("loop.asm").display().to_string(),
+            "-o".to_string(),
+            dir.join("loop.dot").display().to_string(),
+        ])
+        .unwrap();
+        assert_eq!(run(command), Ok(()));
+        assert_eq!(
+            fs::read_to_string(dir.join("loop.dot")).unwrap(),
+            "digraph cfg {\n    \
+             node [shape=box, fontname=\"monospace\"];\n    \
+             b0 [label=\"loop:\\l    0  inc $0\\l    8  jmp #0\\l\"];\n    \
+             b0 -> b0;\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_run_verified() {
+        let dir = ScratchDir::new("verify");
+        let program = dir.join("bad.bin");
+        fs::write(&program, vec![1, 0, 0, 50, 6, 0]).unwrap();
+        let command = Command::Run {
+            program,
+            include_paths: vec![],
+            optimize: false,
+            trace: false,
+            verify: true,
+            engine: Engine::Interpreter,
+        };
+        assert_eq!(
+            run(command),
+            Err(vec![
+                "byte 4: jump to 50 lies outside of the program".to_string()
+            ])
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::fmt;
+
+use crate::assembler::program_parsers::EncodedInstruction;
+use crate::assembler::source::{comment_start, Location, SourceLine};
+
+/// Where the bytes from `start` up to `end` came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugEntry {
+    pub start: u32,
+    pub end: u32,
+    pub location: Location,
+    /// Column of the instruction on its line, counting from 1.
+    pub column: usize,
+    /// The closest label at or before `start`.
+    pub label: Option<String>,
+    /// The source line, without surrounding whitespace or comments.
+    pub text: String,
+}
+
+impl DebugEntry {
+    /// The file, line and column, followed by any macro expansions, e.g.
+    /// `main.asm:4:5` or `line 4, column 5`.
+    pub fn position(&self) -> String {
+        let position = match &self.location.file {
+            Some(file) => format!("{}:{}:{}", file.display(), self.location.line, self.column),
+            None => format!("line {}, column {}", self.location.line, self.column),
+        };
+        format!("{}{}", position, self.location.expansions())
+    }
+}
+
+impl fmt::Display for DebugEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.position())?;
+        if let Some(label) = &self.label {
+            write!(f, " in `{}`", label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps bytecode offsets back to the source they were assembled from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugInfo {
+    pub entries: Vec<DebugEntry>,
+}
+
+// The column the instruction starts at, skipping indentation and a label.
+fn instruction_column(text: &str) -> usize {
+    let trimmed = text.trim_start();
+    let mut column = text.len() - trimmed.len();
+    if let Some(colon) = trimmed.find(':') {
+        let label = &trimmed[..colon];
+        if !label.is_empty()
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        {
+            let rest = &trimmed[colon + 1..];
+            column += colon + 1 + (rest.len() - rest.trim_start().len());
+        }
+    }
+    column + 1
+}
+
+// The line without its trailing comment.
+fn code(text: &str) -> &str {
+    text[..comment_start(text).unwrap_or(text.len())].trim()
+}
+
+impl DebugInfo {
+    /// Builds the table from the lines that were parsed, after macro
+    /// expansion, and the instructions they were encoded to.
+    pub fn new(lines: &[SourceLine], instructions: &[EncodedInstruction]) -> DebugInfo {
+        let mut entries: Vec<DebugEntry> = vec![];
+        let mut label = None;
+        let mut lines = lines.iter().peekable();
+        for instruction in instructions {
+            if instruction.label.is_some() {
+                label = instruction.label.clone();
+            }
+            if instruction.bytes.is_empty() {
+                continue;
+            }
+            while lines
+                .peek()
+                .is_some_and(|line| line.location != instruction.location)
+            {
+                lines.next();
+            }
+            let text = lines.peek().map_or("", |line| line.text.as_str());
+            let start = instruction.offset;
+            let end = start + instruction.bytes.len() as u32;
+
+            // Instructions a pseudo-instruction expanded to share one entry.
+            if let Some(last) = entries.last_mut() {
+                if last.end == start && last.location == instruction.location {
+                    last.end = end;
+                    continue;
+                }
+            }
+            entries.push(DebugEntry {
+                start,
+                end,
+                location: instruction.location.clone(),
+                column: instruction_column(text),
+                label: label.clone(),
+                text: code(text).to_string(),
+            });
+        }
+        DebugInfo { entries }
+    }
+
+    pub fn lookup(&self, pc: usize) -> Option<&DebugEntry> {
+        let pc = pc as u32;
+        let index = self.entries.partition_point(|entry| entry.end <= pc);
+        self.entries.get(index).filter(|entry| entry.start <= pc)
+    }
+}
+
+impl fmt::Display for DebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(
+                f,
+                "{:5}..{:<5}  {}  {}",
+                entry.start, entry.end, entry, entry.text
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_instruction_column() {
+        assert_eq!(instruction_column("hlt"), 1);
+        assert_eq!(instruction_column("\tload $0 #1"), 2);
+        assert_eq!(instruction_column("  loop:  add $0 $1 $0"), 10);
+        assert_eq!(code("  jmpc @loop ; again"), "jmpc @loop");
+        assert_eq!(code("load $0 #';' // semicolon"), "load $0 #';'");
+        assert_eq!(code("load $0 #'\\'' ; quote"), "load $0 #'\\''");
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let source = ".macro twice r\n\
+                      \tinc \\r\n\
+                      \tinc \\r\n\
+                      .endm\n\
+                      start: load $0 #1\n\
+                      loop:\n\
+                      \ttwice $0   ; +2\n\
+                      \tjmp @loop\n";
+        let info = Assembler::new()
+            .assemble_detailed(source)
+            .unwrap()
+            .debug_info;
+        let summary: Vec<(u32, u32, String, Option<&str>, &str)> = info
+            .entries
+            .iter()
+            .map(|e| {
+                (
+                    e.start,
+                    e.end,
+                    e.position(),
+                    e.label.as_deref(),
+                    e.text.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    0,
+                    4,
+                    "line 5, column 8".to_string(),
+                    Some("start"),
+                    "start: load $0 #1"
+                ),
+                (
+                    4,
+                    12,
+                    "line 2, column 2, in macro `twice` expanded at line 7".to_string(),
+                    Some("loop"),
+                    "inc $0"
+                ),
+                (
+                    12,
+                    20,
+                    "line 3, column 2, in macro `twice` expanded at line 7".to_string(),
+                    Some("loop"),
+                    "inc $0"
+                ),
+                (
+                    20,
+                    26,
+                    "line 8, column 2".to_string(),
+                    Some("loop"),
+                    "jmp @loop"
+                ),
+            ]
+        );
+        assert_eq!(info.lookup(13).map(|e| e.start), Some(12));
+        assert_eq!(info.lookup(25).map(|e| e.start), Some(20));
+        assert_eq!(info.lookup(26), None);
+    }
+}
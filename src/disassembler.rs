@@ -0,0 +1,423 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::assembler::pseudo::SCRATCH_REGISTER;
+use crate::instruction::{Encoding, Opcode, OperandKind, Word, WORD_SIZE};
+use crate::vm::custom::CustomInstructions;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Register(u8),
+    Immediate(u16),
+}
+
+/// A decoded instruction, or a byte that could not be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decoded {
+    Instruction {
+        opcode: Opcode,
+        operands: Vec<Operand>,
+    },
+    Byte(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedItem {
+    pub offset: usize,
+    pub length: usize,
+    pub decoded: Decoded,
+}
+
+/// One line of disassembly covering `length` bytes starting at `offset`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub offset: usize,
+    pub length: usize,
+    pub text: String,
+}
+
+/// Decodes the instruction at `offset`. An unknown opcode, or an
+/// instruction cut short by the end of the program, becomes a single byte.
+pub fn decode_at(program: &[u8], offset: usize) -> DecodedItem {
+    decode_at_with(
+        program,
+        offset,
+        Encoding::Variable,
+        &CustomInstructions::new(),
+    )
+}
+
+/// Decodes the instruction at `offset` in a program in `encoding`, which
+/// may be one of `custom`. In the fixed-width encoding, a word that can't be
+/// decoded becomes a byte at a time, like anything else.
+pub fn decode_at_with(
+    program: &[u8],
+    offset: usize,
+    encoding: Encoding,
+    custom: &CustomInstructions,
+) -> DecodedItem {
+    let (opcode, kinds) = match custom.get(program[offset]) {
+        Some(instruction) => (
+            Opcode::CUSTOM(instruction.byte),
+            instruction.operands.as_slice(),
+        ),
+        None => {
+            let opcode = Opcode::from(program[offset]);
+            (opcode, opcode.operands())
+        }
+    };
+    let length = match encoding {
+        Encoding::Variable => 1 + kinds.iter().map(|kind| kind.size()).sum::<usize>(),
+        Encoding::Fixed => WORD_SIZE,
+    };
+    if opcode == Opcode::IGL
+        || !offset.is_multiple_of(encoding.alignment())
+        || offset + length > program.len()
+    {
+        return DecodedItem {
+            offset,
+            length: 1,
+            decoded: Decoded::Byte(program[offset]),
+        };
+    }
+
+    if encoding == Encoding::Fixed {
+        let bytes = &program[offset..offset + WORD_SIZE];
+        let word = Word::from_bytes(
+            [bytes[0], bytes[1], bytes[2], bytes[3]],
+            kinds,
+            opcode.sign_extends(),
+        );
+        let mut registers = word.registers.iter();
+        let operands = kinds
+            .iter()
+            .map(|kind| match kind {
+                OperandKind::Register => Operand::Register(*registers.next().unwrap_or(&0)),
+                OperandKind::Immediate => Operand::Immediate(word.immediate),
+            })
+            .collect();
+        return DecodedItem {
+            offset,
+            length,
+            decoded: Decoded::Instruction { opcode, operands },
+        };
+    }
+
+    let mut position = offset + 1;
+    let mut operands = vec![];
+    for kind in kinds {
+        match kind {
+            OperandKind::Register => {
+                operands.push(Operand::Register(program[position]));
+                position += 1;
+            }
+            OperandKind::Immediate => {
+                let value = (u16::from(program[position]) << 8) | u16::from(program[position + 1]);
+                operands.push(Operand::Immediate(value));
+                position += 2;
+            }
+        }
+    }
+    DecodedItem {
+        offset,
+        length,
+        decoded: Decoded::Instruction { opcode, operands },
+    }
+}
+
+/// Decodes a whole program, one instruction after another.
+pub fn decode(program: &[u8]) -> Vec<DecodedItem> {
+    decode_with(program, Encoding::Variable, &CustomInstructions::new())
+}
+
+/// Decodes a whole program in `encoding` that may use the instructions in
+/// `custom`.
+pub fn decode_with(
+    program: &[u8],
+    encoding: Encoding,
+    custom: &CustomInstructions,
+) -> Vec<DecodedItem> {
+    let mut items = vec![];
+    let mut offset = 0;
+    while offset < program.len() {
+        let item = decode_at_with(program, offset, encoding, custom);
+        offset += item.length;
+        items.push(item);
+    }
+    return items;
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(number) => write!(f, "${}", number),
+            Operand::Immediate(value) => write!(f, "#{}", value),
+        }
+    }
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Decoded::Instruction { opcode, operands } => {
+                write!(f, "{}", opcode.mnemonic())?;
+                for operand in operands {
+                    match operand {
+                        Operand::Immediate(value) if opcode.sign_extends() => {
+                            write!(f, " #{}", *value as i16)?
+                        }
+                        _ => write!(f, " {}", operand)?,
+                    }
+                }
+                Ok(())
+            }
+            Decoded::Byte(byte) => write!(f, ".byte {}", byte),
+        }
+    }
+}
+
+// Recognises the sequences the assembler emits for pseudo-instructions.
+// Returns the sugared text and how many decoded items it replaces.
+fn resugar(items: &[DecodedItem]) -> Option<(String, usize)> {
+    use self::Decoded::Instruction;
+    use self::Operand::{Immediate, Register};
+    const S: u8 = SCRATCH_REGISTER;
+
+    let first = match items.first().map(|item| &item.decoded) {
+        Some(Instruction { opcode, operands }) => (*opcode, operands.as_slice()),
+        _ => return None,
+    };
+    let second = match items.get(1).map(|item| &item.decoded) {
+        Some(Instruction { opcode, operands }) => Some((*opcode, operands.as_slice())),
+        _ => None,
+    };
+
+    let pair = match (first, second) {
+        (
+            (Opcode::LOAD, [Register(S), Immediate(0)]),
+            Some((Opcode::ADD, [Register(a), Register(S), Register(b)])),
+        ) if *a != S && *b != S => Some(format!("mov ${} ${}", a, b)),
+        (
+            (Opcode::LOAD, [Register(S), Immediate(1)]),
+            Some((Opcode::ADD, [Register(a), Register(S), Register(b)])),
+        ) if a == b && *a != S => Some(format!("inc ${}", a)),
+        (
+            (Opcode::LOAD, [Register(S), Immediate(1)]),
+            Some((Opcode::SUB, [Register(a), Register(S), Register(b)])),
+        ) if a == b && *a != S => Some(format!("dec ${}", a)),
+        (
+            (Opcode::LOAD, [Register(r), Immediate(lower)]),
+            Some((Opcode::LUI, [Register(u), Immediate(upper)])),
+        ) if r == u => {
+            let value = ((u32::from(*upper) << 16) | u32::from(*lower)) as i32;
+            Some(format!("li ${} #{}", r, value))
+        }
+        (
+            (Opcode::LOAD, [Register(S), Immediate(target)]),
+            Some((jump @ Opcode::JMP, [Register(S)])),
+        )
+        | (
+            (Opcode::LOAD, [Register(S), Immediate(target)]),
+            Some((jump @ Opcode::JMPC, [Register(S)])),
+        ) => Some(format!("{} #{}", jump.mnemonic(), target)),
+        _ => None,
+    };
+    if let Some(text) = pair {
+        return Some((text, 2));
+    }
+
+    match first {
+        (Opcode::LOAD, [Register(r), Immediate(0)]) => Some((format!("clr ${}", r), 1)),
+        _ => None,
+    }
+}
+
+/// Turns bytecode back into assembly text.
+pub struct Disassembler {
+    resugar: bool,
+    custom: Rc<CustomInstructions>,
+}
+
+impl Disassembler {
+    pub fn new() -> Disassembler {
+        Disassembler {
+            resugar: false,
+            custom: Rc::new(CustomInstructions::new()),
+        }
+    }
+
+    /// Whether to show the expansions of pseudo-instructions as the
+    /// pseudo-instructions themselves.
+    pub fn resugar(self, resugar: bool) -> Disassembler {
+        Disassembler { resugar, ..self }
+    }
+
+    /// Decodes the instructions an embedder has added to the VM.
+    pub fn custom_instructions(self, custom: Rc<CustomInstructions>) -> Disassembler {
+        Disassembler { custom, ..self }
+    }
+
+    /// Disassembles bytecode in the encoding its header names. Offsets
+    /// count from after the header.
+    pub fn disassemble(&self, program: &[u8]) -> Vec<Line> {
+        let (encoding, code) = Encoding::detect(program);
+        self.lines(&decode_with(code, encoding, &self.custom))
+    }
+
+    /// The text of one decoded instruction, without resugaring.
+    pub fn text(&self, decoded: &Decoded) -> String {
+        match decoded {
+            Decoded::Instruction {
+                opcode: Opcode::CUSTOM(byte),
+                operands,
+            } => {
+                let mnemonic = self.custom.get(*byte).map_or("igl", |c| &c.mnemonic);
+                let mut text = mnemonic.to_string();
+                for operand in operands {
+                    text.push_str(&format!(" {}", operand));
+                }
+                text
+            }
+            _ => decoded.to_string(),
+        }
+    }
+
+    /// Disassembles instructions that have already been decoded.
+    pub fn lines(&self, items: &[DecodedItem]) -> Vec<Line> {
+        let mut lines = vec![];
+        let mut i = 0;
+        while i < items.len() {
+            let sugared = if self.resugar {
+                resugar(&items[i..])
+            } else {
+                None
+            };
+            let (text, count) = match sugared {
+                Some(sugared) => sugared,
+                None => (self.text(&items[i].decoded), 1),
+            };
+            let covered = &items[i..i + count];
+            lines.push(Line {
+                offset: covered[0].offset,
+                length: covered.iter().map(|item| item.length).sum(),
+                text,
+            });
+            i += count;
+        }
+        return lines;
+    }
+
+    /// The disassembly as a listing with one `offset: text` line each.
+    pub fn disassemble_to_string(&self, program: &[u8]) -> String {
+        self.disassemble(program)
+            .iter()
+            .map(|line| format!("{:04}: {}\n", line.offset, line.text))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::assemble;
+    use crate::assembler::Assembler;
+
+    fn texts(lines: &[Line]) -> Vec<&str> {
+        lines.iter().map(|line| line.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_disassemble_plain() {
+        let program =
+            assemble("load $0 #100\nadd $0 $1 $2\nlt $1 $2\njmpc $7\nhlt".to_string()).unwrap();
+        let lines = Disassembler::new().disassemble(&program);
+        assert_eq!(
+            texts(&lines),
+            vec!["load $0 #100", "add $0 $1 $2", "lt $1 $2", "jmpc $7", "hlt"]
+        );
+        assert_eq!(lines[1].offset, 4);
+        assert_eq!(lines[1].length, 4);
+    }
+
+    #[test]
+    fn test_disassemble_signed_immediates() {
+        let source = "addi $0 #-1 $1\nsubi $1 #300 $2\ncmpi $2 #-32768\nloop: bltq $1 $2 @loop";
+        let program = assemble(source.to_string()).unwrap();
+        let lines = Disassembler::new().disassemble(&program);
+        assert_eq!(
+            texts(&lines),
+            vec![
+                "addi $0 #-1 $1",
+                "subi $1 #300 $2",
+                "cmpi $2 #-32768",
+                "bltq $1 $2 #14"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_words() {
+        let source = "load $0 #500\naddi $0 #-1 $1\nloop: bltq $1 $2 @loop\nhlt";
+        let program = Assembler::new()
+            .encoding(Encoding::Fixed)
+            .assemble(source)
+            .unwrap();
+        assert_eq!(
+            Disassembler::new().disassemble_to_string(&program),
+            "0000: load $0 #500\n0004: addi $0 #-1 $1\n0008: bltq $1 $2 #8\n0012: hlt\n"
+        );
+        // A bad word is shown a byte at a time. The header comes first.
+        let mut program = program;
+        program[8] = 200;
+        let lines = Disassembler::new().disassemble(&program);
+        assert_eq!(
+            texts(&lines)[1..6],
+            [
+                ".byte 200",
+                ".byte 0",
+                ".byte 127",
+                ".byte 255",
+                "bltq $1 $2 #8"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_bad_bytes() {
+        let lines = Disassembler::new().disassemble(&[200, 0, 1, 0]);
+        assert_eq!(texts(&lines), vec![".byte 200", "hlt", ".byte 1", "hlt"]);
+        let lines = Disassembler::new().disassemble(&[1, 2, 0]);
+        assert_eq!(texts(&lines), vec![".byte 1", ".byte 2", "hlt"]);
+    }
+
+    #[test]
+    fn test_disassemble_resugar() {
+        let source = "mov $1 $2\ninc $4\ndec $5\nclr $6\nli $2 #-2\nloop: jmpc @loop\njmp #0\nhlt";
+        let program = assemble(source.to_string()).unwrap();
+
+        let plain = Disassembler::new().disassemble(&program);
+        assert_eq!(plain[0].text, "load $31 #0");
+        assert_eq!(plain[1].text, "add $1 $31 $2");
+
+        let sugared = Disassembler::new().resugar(true).disassemble(&program);
+        assert_eq!(
+            texts(&sugared),
+            vec![
+                "mov $1 $2",
+                "inc $4",
+                "dec $5",
+                "clr $6",
+                "li $2 #-2",
+                "jmpc #36",
+                "jmp #0",
+                "hlt"
+            ]
+        );
+        assert_eq!(sugared[5].offset, 36);
+        assert_eq!(sugared[5].length, 6);
+
+        // The sugared text assembles back to the same bytes.
+        let reassembled: String = texts(&sugared).join("\n");
+        assert_eq!(assemble(reassembled), Ok(program));
+    }
+}
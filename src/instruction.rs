@@ -1,50 +1,359 @@
+/// The kinds of operand an instruction takes, in the order they follow its
+/// opcode byte.
 #[derive(Debug, PartialEq, Copy, Clone)]
-pub enum Opcode {
-    HLT,
-    LOAD,
-    ADD,
-    SUB,
-    MUL,
-    DIV,
-    JMP,
-    JMPB,
-    JMPF,
-    JMPC,
-    EQ,
-    NEQ,
-    GT,
-    LT,
-    GTQ,
-    LTQ,
-    IGL,
-    NOP,
+pub enum OperandKind {
+    /// One byte holding a register number.
+    Register,
+    /// Two bytes holding a 16-bit value, high byte first.
+    Immediate,
 }
 
-impl From<u8> for Opcode {
-    fn from(v: u8) -> Self {
-        match v {
-            0 => return Opcode::HLT,
-            1 => return Opcode::LOAD,
-            2 => return Opcode::ADD,
-            3 => return Opcode::SUB,
-            4 => return Opcode::MUL,
-            5 => return Opcode::DIV,
-            6 => return Opcode::JMP,
-            7 => return Opcode::JMPB,
-            8 => return Opcode::JMPF,
-            9 => return Opcode::JMPC,
-            10 => return Opcode::EQ,
-            11 => return Opcode::NEQ,
-            12 => return Opcode::GT,
-            13 => return Opcode::LT,
-            14 => return Opcode::GTQ,
-            15 => return Opcode::LTQ,
-            255 => return Opcode::NOP,
-            _ => return Opcode::IGL,
+impl OperandKind {
+    /// The number of bytes the operand is encoded in.
+    pub fn size(self) -> usize {
+        match self {
+            OperandKind::Register => 1,
+            OperandKind::Immediate => 2,
         }
     }
 }
 
+/// Everything about an instruction that doesn't depend on what it does.
+#[derive(Debug, PartialEq)]
+pub struct OpcodeInfo {
+    pub opcode: Opcode,
+    pub byte: u8,
+    pub mnemonic: &'static str,
+    pub operands: &'static [OperandKind],
+    pub description: &'static str,
+}
+
+// Declares `Opcode`, `INSTRUCTION_SET` and the decoding of opcode bytes from
+// one list, so adding an instruction is one line here plus what it does in
+// the VM.
+macro_rules! instruction_set {
+    ($($opcode:ident = $byte:literal, $mnemonic:literal, [$($kind:ident),*], $description:literal;)*) => {
+        #[derive(Debug, PartialEq, Copy, Clone)]
+        pub enum Opcode {
+            $($opcode,)*
+            /// Any byte or mnemonic that isn't an instruction.
+            IGL,
+            /// An instruction an embedder has registered, by its byte in
+            /// `CUSTOM_OPCODES`. Only decoding with the registry yields it.
+            CUSTOM(u8),
+        }
+
+        /// Every instruction, in order of opcode byte.
+        pub const INSTRUCTION_SET: &[OpcodeInfo] = &[
+            $(OpcodeInfo {
+                opcode: Opcode::$opcode,
+                byte: $byte,
+                mnemonic: $mnemonic,
+                operands: &[$(OperandKind::$kind),*],
+                description: $description,
+            },)*
+        ];
+
+        impl From<u8> for Opcode {
+            fn from(v: u8) -> Self {
+                match v {
+                    $($byte => return Opcode::$opcode,)*
+                    _ => return Opcode::IGL,
+                }
+            }
+        }
+    };
+}
+
+instruction_set! {
+    HLT = 0, "hlt", [], "Halts execution";
+    LOAD = 1, "load", [Register, Immediate], "Loads value `i` into `r`";
+    ADD = 2, "add", [Register, Register, Register], "Adds `r1` and `r2` and outputs to `r3`";
+    SUB = 3, "sub", [Register, Register, Register], "Subtracts `r2` from `r1` and outputs to `r3`";
+    MUL = 4, "mul", [Register, Register, Register], "Multiplies `r1` by `r2` and outputs to `r3`";
+    DIV = 5, "div", [Register, Register, Register], "Divides `r1` by `r2` and outputs to `r3`";
+    JMP = 6, "jmp", [Register], "Jumps to byte `r` in program";
+    JMPB = 7, "jmpb", [Register], "Jumps back `r` bytes in program";
+    JMPF = 8, "jmpf", [Register], "Jumps forward `r` bytes in program";
+    JMPC = 9, "jmpc", [Register], "Jumps to byte `r` in program if condition is met";
+    EQ = 10, "eq", [Register, Register], "Checks if `r1` is equal to `r2`";
+    NEQ = 11, "neq", [Register, Register], "Checks if `r1` is not equal to `r2`";
+    GT = 12, "gt", [Register, Register], "Checks if `r1` > `r2`";
+    LT = 13, "lt", [Register, Register], "Checks if `r1` < `r2`";
+    GTQ = 14, "gtq", [Register, Register], "Checks if `r1` >= `r2`";
+    LTQ = 15, "ltq", [Register, Register], "Checks if `r1` <= `r2`";
+    LUI = 16, "lui", [Register, Immediate], "Loads `i` into the upper 16 bits of `r`";
+    ADDI = 17, "addi", [Register, Immediate, Register], "Adds `i` to `r1` and outputs to `r2`";
+    SUBI = 18, "subi", [Register, Immediate, Register], "Subtracts `i` from `r1` and outputs to `r2`";
+    MULI = 19, "muli", [Register, Immediate, Register], "Multiplies `r1` by `i` and outputs to `r2`";
+    CMPI = 20, "cmpi", [Register, Immediate], "Checks if `r` is equal to `i`";
+    BEQ = 21, "beq", [Register, Register, Immediate], "Jumps to byte `i` if `r1` is equal to `r2`";
+    BNEQ = 22, "bneq", [Register, Register, Immediate], "Jumps to byte `i` if `r1` is not equal to `r2`";
+    BGT = 23, "bgt", [Register, Register, Immediate], "Jumps to byte `i` if `r1` > `r2`";
+    BLT = 24, "blt", [Register, Register, Immediate], "Jumps to byte `i` if `r1` < `r2`";
+    BGTQ = 25, "bgtq", [Register, Register, Immediate], "Jumps to byte `i` if `r1` >= `r2`";
+    BLTQ = 26, "bltq", [Register, Register, Immediate], "Jumps to byte `i` if `r1` <= `r2`";
+    SPAWN = 27, "spawn", [Register, Immediate], "Starts a thread at byte `i` and puts its id in `r`";
+    YIELD = 28, "yield", [], "Lets the next thread run";
+    SEND = 29, "send", [Register, Register], "Sends `r2` to thread `r1`";
+    RECV = 30, "recv", [Register], "Waits for a message and puts it in `r`";
+    EI = 31, "ei", [], "Enables interrupts";
+    DI = 32, "di", [], "Disables interrupts";
+    IRET = 33, "iret", [], "Returns to where an interrupt was taken";
+    SETVEC = 34, "setvec", [Register, Immediate], "Sets the handler for interrupt `r` to byte `i`";
+    TIMER = 35, "timer", [Register], "Raises interrupt 0 every `r` instructions";
+    NOP = 255, "nop", [], "A no-op";
+}
+
+impl Opcode {
+    /// The instruction's entry in `INSTRUCTION_SET`, unless it is `IGL` or
+    /// custom.
+    pub fn info(self) -> Option<&'static OpcodeInfo> {
+        INSTRUCTION_SET.iter().find(|info| info.opcode == self)
+    }
+
+    /// The instruction with the given mnemonic, or `IGL` if there is none.
+    pub fn from_mnemonic(mnemonic: &str) -> Opcode {
+        INSTRUCTION_SET
+            .iter()
+            .find(|info| info.mnemonic == mnemonic)
+            .map_or(Opcode::IGL, |info| info.opcode)
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        self.info().map_or("igl", |info| info.mnemonic)
+    }
+
+    pub fn operands(self) -> &'static [OperandKind] {
+        self.info().map_or(&[], |info| info.operands)
+    }
+
+    /// Whether the instruction's immediate is a signed 16-bit value, rather
+    /// than an address or the bits of a register.
+    pub fn sign_extends(self) -> bool {
+        matches!(
+            self,
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI | Opcode::CMPI
+        )
+    }
+
+    /// Whether the instruction compares two registers and jumps to its
+    /// immediate if the comparison holds.
+    pub fn is_branch(self) -> bool {
+        matches!(
+            self,
+            Opcode::BEQ | Opcode::BNEQ | Opcode::BGT | Opcode::BLT | Opcode::BGTQ | Opcode::BLTQ
+        )
+    }
+
+    /// Whether the instruction's immediate is the address of code: the
+    /// target of a branch, or where a thread or interrupt handler starts.
+    pub fn takes_address(self) -> bool {
+        self.is_branch() || matches!(self, Opcode::SPAWN | Opcode::SETVEC)
+    }
+
+    /// The comparison made by a comparison or branch.
+    pub fn compare(self, left: i32, right: i32) -> bool {
+        match self {
+            Opcode::EQ | Opcode::BEQ => left == right,
+            Opcode::NEQ | Opcode::BNEQ => left != right,
+            Opcode::GT | Opcode::BGT => left > right,
+            Opcode::LT | Opcode::BLT => left < right,
+            Opcode::GTQ | Opcode::BGTQ => left >= right,
+            _ => left <= right,
+        }
+    }
+}
+
+/// How the instructions of a program are laid out.
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub enum Encoding {
+    /// Each instruction is its opcode byte followed by its operands, as
+    /// `OperandKind` describes. This is what bytecode without a header uses.
+    #[default]
+    Variable,
+    /// Each instruction is one `Word`, starting at a multiple of
+    /// `WORD_SIZE`, so the nth instruction is at byte `n * WORD_SIZE`.
+    Fixed,
+}
+
+/// Bytecode can start with these bytes and then one more naming its
+/// encoding: 0 for variable, 1 for fixed. Code offsets count from after
+/// the header. No instruction starts with `A`, so bytecode without a header
+/// is never mistaken for one with.
+pub const HEADER_MAGIC: &[u8; 3] = b"AVM";
+
+impl Encoding {
+    /// The header bytecode in this encoding starts with. Variable-length
+    /// bytecode goes without one, as it always has.
+    pub fn header(self) -> &'static [u8] {
+        match self {
+            Encoding::Variable => &[],
+            Encoding::Fixed => b"AVM\x01",
+        }
+    }
+
+    /// Splits bytecode into the encoding its header names and the code
+    /// after the header.
+    pub fn detect(bytecode: &[u8]) -> (Encoding, &[u8]) {
+        match bytecode.strip_prefix(&HEADER_MAGIC[..]) {
+            Some([0, code @ ..]) => (Encoding::Variable, code),
+            Some([1, code @ ..]) => (Encoding::Fixed, code),
+            _ => (Encoding::Variable, bytecode),
+        }
+    }
+
+    /// Every instruction starts at a multiple of this many bytes.
+    pub fn alignment(self) -> usize {
+        match self {
+            Encoding::Variable => 1,
+            Encoding::Fixed => WORD_SIZE,
+        }
+    }
+}
+
+/// The size of an instruction in the fixed-width encoding.
+pub const WORD_SIZE: usize = 4;
+
+/// An instruction in the fixed-width encoding: a big-endian 32-bit word
+/// holding, from the top, an 8-bit opcode, three 5-bit register fields and
+/// an immediate field in the low bits. Registers fill the register fields
+/// in the order the instruction names them. The immediate field takes the
+/// 16 bits below the opcode that registers don't need, up to 16: it is 14
+/// bits wide after two registers and 9 after three. Unused fields are zero.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Word {
+    pub opcode: u8,
+    pub registers: [u8; 3],
+    pub immediate: u16,
+}
+
+impl Word {
+    /// The width of the immediate field of an instruction taking `kinds`.
+    pub fn immediate_bits(kinds: &[OperandKind]) -> u32 {
+        let registers = kinds
+            .iter()
+            .filter(|kind| **kind == OperandKind::Register)
+            .count() as u32;
+        (24 - 5 * registers).min(16)
+    }
+
+    /// Whether every operand fits its field. With `signed`, the immediate
+    /// is a signed value, and has to fit once sign-extended.
+    pub fn fits(&self, kinds: &[OperandKind], signed: bool) -> bool {
+        let bits = Word::immediate_bits(kinds);
+        let (immediate, range) = if signed {
+            (
+                i32::from(self.immediate as i16),
+                -(1 << (bits - 1))..1 << (bits - 1),
+            )
+        } else {
+            (i32::from(self.immediate), 0..1 << bits)
+        };
+        self.registers.iter().all(|register| *register < 32) && range.contains(&immediate)
+    }
+
+    /// The word's bytes. Fields too large for their width lose their top
+    /// bits, so check `fits` first.
+    pub fn to_bytes(self, kinds: &[OperandKind]) -> [u8; WORD_SIZE] {
+        let bits = Word::immediate_bits(kinds);
+        let mut word = u32::from(self.opcode) << 24;
+        for (field, register) in self.registers.iter().enumerate() {
+            word |= (u32::from(*register) & 31) << (19 - 5 * field);
+        }
+        word |= u32::from(self.immediate) & ((1 << bits) - 1);
+        word.to_be_bytes()
+    }
+
+    /// Splits a word of an instruction taking `kinds` into its fields. With
+    /// `signed`, the immediate is sign-extended to 16 bits.
+    pub fn from_bytes(bytes: [u8; WORD_SIZE], kinds: &[OperandKind], signed: bool) -> Word {
+        let word = u32::from_be_bytes(bytes);
+        let registers = kinds
+            .iter()
+            .filter(|kind| **kind == OperandKind::Register)
+            .count();
+        let mut fields = [0; 3];
+        for (field, register) in fields.iter_mut().enumerate().take(registers) {
+            *register = (word >> (19 - 5 * field)) as u8 & 31;
+        }
+        // Shifting the field to the top of a u32 and back sign-extends it.
+        let unused = 32 - Word::immediate_bits(kinds);
+        let immediate = if signed {
+            ((word << unused) as i32 >> unused) as u16
+        } else {
+            ((word << unused) >> unused) as u16
+        };
+        Word {
+            opcode: bytes[0],
+            registers: fields,
+            immediate,
+        }
+    }
+}
+
+// How an instruction is written, naming its operands the way the
+// descriptions do.
+fn syntax(info: &OpcodeInfo) -> String {
+    let registers = info
+        .operands
+        .iter()
+        .filter(|kind| **kind == OperandKind::Register)
+        .count();
+    let mut register = 0;
+    let mut syntax = info.mnemonic.to_string();
+    for kind in info.operands {
+        syntax.push(' ');
+        match kind {
+            OperandKind::Register if registers == 1 => syntax.push('r'),
+            OperandKind::Register => {
+                register += 1;
+                syntax.push_str(&format!("r{}", register));
+            }
+            OperandKind::Immediate => syntax.push('i'),
+        }
+    }
+    syntax
+}
+
+/// The instruction table from the README, as Markdown.
+pub fn markdown_table() -> String {
+    let rows: Vec<[String; 3]> = INSTRUCTION_SET
+        .iter()
+        .map(|info| {
+            [
+                format!("`{}`", syntax(info)),
+                info.byte.to_string(),
+                info.description.to_string(),
+            ]
+        })
+        .collect();
+    let header = ["Instruction", "Opcode", "Description"];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let line = |cells: [&str; 3]| {
+        let mut line = String::from("|");
+        for (cell, width) in cells.iter().zip(widths.iter()) {
+            line.push_str(&format!(" {:width$} |", cell, width = width));
+        }
+        line + "\n"
+    };
+    let mut table = line(header);
+    for width in &widths {
+        table.push_str(&format!("|-{}", " ".repeat(width + 1)));
+    }
+    table.push_str("|\n");
+    for row in &rows {
+        table.push_str(&line([&row[0], &row[1], &row[2]]));
+    }
+    table
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Instruction {
     opcode: Opcode,
@@ -52,7 +361,7 @@ pub struct Instruction {
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode: opcode }
+        Instruction { opcode }
     }
 }
 
@@ -71,4 +380,95 @@ mod tests {
         let test_instruction = Instruction::new(Opcode::HLT);
         assert_eq!(test_instruction.opcode, Opcode::HLT);
     }
+
+    #[test]
+    fn test_instruction_set() {
+        for info in INSTRUCTION_SET {
+            assert_eq!(Opcode::from(info.byte), info.opcode);
+            assert_eq!(Opcode::from_mnemonic(info.mnemonic), info.opcode);
+            assert_eq!(info.opcode.info(), Some(info));
+        }
+        assert_eq!(Opcode::from(255), Opcode::NOP);
+        assert_eq!(Opcode::from(36), Opcode::IGL);
+        assert_eq!(Opcode::from_mnemonic("igl"), Opcode::IGL);
+        assert_eq!(Opcode::IGL.info(), None);
+        assert_eq!(Opcode::CUSTOM(128).info(), None);
+        for byte in crate::vm::custom::CUSTOM_OPCODES {
+            assert_eq!(Opcode::from(byte), Opcode::IGL);
+        }
+    }
+
+    #[test]
+    fn test_encoding_header() {
+        assert_eq!(Encoding::detect(&[0, 1]), (Encoding::Variable, &[0, 1][..]));
+        assert_eq!(
+            Encoding::detect(b"AVM\x01\x00"),
+            (Encoding::Fixed, &[0][..])
+        );
+        assert_eq!(Encoding::detect(b"AVM\x00"), (Encoding::Variable, &[][..]));
+        assert_eq!(
+            Encoding::detect(Encoding::Fixed.header()).0,
+            Encoding::Fixed
+        );
+        assert_eq!(Opcode::from(HEADER_MAGIC[0]), Opcode::IGL);
+    }
+
+    #[test]
+    fn test_words() {
+        use OperandKind::{Immediate, Register};
+        let add = Word {
+            opcode: 2,
+            registers: [1, 2, 31],
+            immediate: 0,
+        };
+        let kinds = [Register, Register, Register];
+        // 00000010 00001 00010 11111 000000000
+        assert_eq!(add.to_bytes(&kinds), [2, 0x08, 0xBE, 0]);
+        assert_eq!(Word::from_bytes(add.to_bytes(&kinds), &kinds, false), add);
+
+        let addi = Word {
+            opcode: 17,
+            registers: [3, 4, 0],
+            immediate: -2i16 as u16,
+        };
+        let kinds = [Register, Immediate, Register];
+        assert_eq!(Word::immediate_bits(&kinds), 14);
+        assert!(addi.fits(&kinds, true));
+        assert_eq!(Word::from_bytes(addi.to_bytes(&kinds), &kinds, true), addi);
+        assert!(!Word {
+            immediate: 8192,
+            ..addi
+        }
+        .fits(&kinds, true));
+        assert!(!Word {
+            immediate: 16384,
+            ..addi
+        }
+        .fits(&kinds, false));
+        assert!(!Word {
+            registers: [32, 0, 0],
+            ..addi
+        }
+        .fits(&kinds, true));
+
+        let load = Word {
+            opcode: 1,
+            registers: [5, 0, 0],
+            immediate: 65535,
+        };
+        let kinds = [Register, Immediate];
+        assert!(load.fits(&kinds, false));
+        assert_eq!(load.to_bytes(&kinds), [1, 0x28, 255, 255]);
+        assert_eq!(Word::from_bytes(load.to_bytes(&kinds), &kinds, false), load);
+    }
+
+    #[test]
+    fn test_readme_instruction_table() {
+        let table = markdown_table();
+        assert!(
+            include_str!("../README.md").contains(&table),
+            "the instruction table in README.md is out of date; it should read:\n{}",
+            table
+        );
+    }
 }
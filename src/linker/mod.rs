@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::assembler::instruction_parsers::{check_immediate, AssemblerInstruction};
+use crate::assembler::listing::{MapFile, MapLabel};
+use crate::assembler::symbols::SymbolTable;
+use crate::assembler::{AssemblerError, Token};
+use crate::linker::object::ObjectFile;
+
+pub mod object;
+
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    UndefinedSymbol {
+        module: String,
+        name: String,
+    },
+    DuplicateSymbol {
+        name: String,
+        first: String,
+        second: String,
+    },
+    BadRelocation {
+        module: String,
+        offset: u32,
+        error: AssemblerError,
+    },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::UndefinedSymbol { module, name } => {
+                write!(f, "{}: undefined symbol `{}`", module, name)
+            }
+            LinkError::DuplicateSymbol {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "symbol `{}` is exported by both {} and {}",
+                name, first, second
+            ),
+            LinkError::BadRelocation {
+                module,
+                offset,
+                error,
+            } => write!(f, "{}: relocation at byte {}: {}", module, offset, error),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// Where a module's code ends up in the linked program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub module: String,
+    pub base: u32,
+    pub size: u32,
+}
+
+/// Combines object files into a single program. Modules are laid out one
+/// after another in the order they were added, so execution starts at the
+/// beginning of the first one.
+#[derive(Debug, Default)]
+pub struct Linker {
+    modules: Vec<(String, ObjectFile)>,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker { modules: vec![] }
+    }
+
+    /// Adds a module. The name is only used in error messages.
+    pub fn object<S: Into<String>>(mut self, name: S, object: ObjectFile) -> Linker {
+        self.modules.push((name.into(), object));
+        self
+    }
+
+    pub fn sections(&self) -> Vec<Section> {
+        let mut base = 0;
+        self.modules
+            .iter()
+            .map(|(module, object)| {
+                let section = Section {
+                    module: module.clone(),
+                    base,
+                    size: object.code.len() as u32,
+                };
+                base += section.size;
+                section
+            })
+            .collect()
+    }
+
+    /// Where every module and every label, exported or not, is placed.
+    pub fn map(&self) -> MapFile {
+        let sections = self.sections();
+        let labels = self
+            .modules
+            .iter()
+            .zip(&sections)
+            .flat_map(|((module, object), section)| {
+                object.symbols.iter().map(move |symbol| MapLabel {
+                    name: symbol.name.clone(),
+                    address: section.base + symbol.offset,
+                    section: module.clone(),
+                })
+            })
+            .collect();
+        MapFile::new(sections, labels)
+    }
+
+    pub fn link(&self) -> Result<Vec<u8>, Vec<LinkError>> {
+        let sections = self.sections();
+        let mut errors = vec![];
+
+        // Exported symbols along with the module that defines them.
+        let mut globals: HashMap<&str, (u32, &str)> = HashMap::new();
+        for ((module, object), section) in self.modules.iter().zip(&sections) {
+            for symbol in object.exports() {
+                let address = section.base + symbol.offset;
+                if let Some((_, first)) = globals.get(symbol.name.as_str()) {
+                    errors.push(LinkError::DuplicateSymbol {
+                        name: symbol.name.clone(),
+                        first: first.to_string(),
+                        second: module.clone(),
+                    });
+                    continue;
+                }
+                globals.insert(&symbol.name, (address, module));
+            }
+        }
+
+        let mut program = vec![];
+        for ((module, object), section) in self.modules.iter().zip(&sections) {
+            // A module's own labels take precedence over imported ones.
+            let mut symbols = SymbolTable::new();
+            for symbol in &object.symbols {
+                symbols.add_symbol(&symbol.name, section.base + symbol.offset);
+            }
+            for import in &object.imports {
+                match globals.get(import.as_str()) {
+                    Some((address, _)) => {
+                        symbols.add_symbol(import, *address);
+                    }
+                    None => errors.push(LinkError::UndefinedSymbol {
+                        module: module.clone(),
+                        name: import.clone(),
+                    }),
+                }
+            }
+
+            let mut code = object.code.clone();
+            for relocation in &object.relocations {
+                let mut field = vec![];
+                let patched = relocation.expression.evaluate(&symbols).and_then(|value| {
+                    check_immediate(value, relocation.signed)?;
+                    AssemblerInstruction::extract_operand(
+                        &Token::IntegerOperand { value },
+                        &symbols,
+                        &mut field,
+                    )
+                });
+                match patched {
+                    Ok(()) => {
+                        let offset = relocation.offset as usize;
+                        code[offset..offset + 2].copy_from_slice(&field);
+                    }
+                    // Unresolved imports have already been reported.
+                    Err(AssemblerError::UndefinedSymbol { name }) => {
+                        if !object.imports.contains(&name) {
+                            errors.push(LinkError::UndefinedSymbol {
+                                module: module.clone(),
+                                name,
+                            })
+                        }
+                    }
+                    Err(error) => errors.push(LinkError::BadRelocation {
+                        module: module.clone(),
+                        offset: relocation.offset,
+                        error,
+                    }),
+                }
+            }
+            program.append(&mut code);
+        }
+
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn object(source: &str) -> ObjectFile {
+        Assembler::new().assemble_object(source).unwrap()
+    }
+
+    #[test]
+    fn test_link_modules() {
+        let main = object(".extern double\nload $0 #3\njmp @double\n");
+        let library = object(".global double\nhlt\ndouble: add $0 $0 $0\nhlt\n");
+        let linker = Linker::new()
+            .object("main.o", main)
+            .object("lib.o", library);
+        assert_eq!(
+            linker.sections(),
+            vec![
+                Section {
+                    module: "main.o".to_string(),
+                    base: 0,
+                    size: 10
+                },
+                Section {
+                    module: "lib.o".to_string(),
+                    base: 10,
+                    size: 6
+                },
+            ]
+        );
+        assert_eq!(
+            linker.link(),
+            Ok(vec![1, 0, 0, 3, 1, 31, 0, 11, 6, 31, 0, 2, 0, 0, 0, 0])
+        );
+        assert_eq!(
+            linker.map().labels,
+            vec![MapLabel {
+                name: "double".to_string(),
+                address: 11,
+                section: "lib.o".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_link_local_labels_are_relocated() {
+        let first = object("hlt\n");
+        let second = object("start: load $0 @start\nload $1 #(end - start)\nend: hlt\n");
+        let linked = Linker::new()
+            .object("a.o", first)
+            .object("b.o", second)
+            .link()
+            .unwrap();
+        assert_eq!(linked, vec![0, 1, 0, 0, 1, 1, 1, 0, 8, 0]);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let a = object(".global f\n.extern g\n.extern h\nf: load $0 @g\n");
+        let b = object(".global f\nf: hlt\n.global g\ng: hlt\n");
+        assert_eq!(
+            Linker::new().object("a.o", a).object("b.o", b).link(),
+            Err(vec![
+                LinkError::DuplicateSymbol {
+                    name: "f".to_string(),
+                    first: "a.o".to_string(),
+                    second: "b.o".to_string(),
+                },
+                LinkError::UndefinedSymbol {
+                    module: "a.o".to_string(),
+                    name: "h".to_string(),
+                },
+            ])
+        );
+
+        // Only an instruction that sign-extends its immediate takes a
+        // negative relocation.
+        let a = object(".extern f\nload $0 #(f - 1)\naddi $0 #(f - 1) $0\n");
+        let b = object(".global f\nf: hlt\n");
+        assert_eq!(
+            Linker::new().object("b.o", b).object("a.o", a).link(),
+            Err(vec![LinkError::BadRelocation {
+                module: "a.o".to_string(),
+                offset: 2,
+                error: AssemblerError::ImmediateOutOfRange {
+                    value: -1,
+                    bits: 16
+                },
+            }])
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+
+use nom::types::CompleteStr;
+
+use crate::assembler::expressions::{expression, Expression};
+
+const MAGIC: &[u8; 4] = b"AVMO";
+const VERSION: u8 = 2;
+
+/// A label defined by a module. Only exported symbols are visible to other
+/// modules, but every label is kept so that relocations can refer to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub offset: u32,
+    pub exported: bool,
+}
+
+/// A 16-bit field at `offset` in the code that has to be filled in with the
+/// value of `expression` once the final address of every label is known.
+/// `signed` is set if the instruction sign-extends the field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub offset: u32,
+    pub expression: Expression,
+    pub signed: bool,
+}
+
+/// The output of assembling a single module: code in which label references
+/// are left as zero, along with what is needed to link it to other modules.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectFile {
+    pub code: Vec<u8>,
+    pub symbols: Vec<ObjectSymbol>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ObjectError {
+    BadMagic,
+    UnsupportedVersion { version: u8 },
+    Truncated,
+    InvalidString,
+    InvalidExpression { text: String },
+    OffsetOutOfRange { offset: u32 },
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjectError::BadMagic => write!(f, "not an object file"),
+            ObjectError::UnsupportedVersion { version } => {
+                write!(f, "unsupported object file version {}", version)
+            }
+            ObjectError::Truncated => write!(f, "object file is truncated"),
+            ObjectError::InvalidString => write!(f, "object file contains invalid UTF-8"),
+            ObjectError::InvalidExpression { text } => {
+                write!(f, "invalid relocation expression `{}`", text)
+            }
+            ObjectError::OffsetOutOfRange { offset } => {
+                write!(f, "offset {} lies outside of the code", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ObjectError {}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_string(bytes: &mut Vec<u8>, string: &str) {
+    bytes.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], ObjectError> {
+        let end = self.position + length;
+        if end > self.bytes.len() {
+            return Err(ObjectError::Truncated);
+        }
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        return Ok(taken);
+    }
+
+    fn u8(&mut self) -> Result<u8, ObjectError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ObjectError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Result<String, ObjectError> {
+        let length = self.take(2)?;
+        let length = usize::from(u16::from_be_bytes([length[0], length[1]]));
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| ObjectError::InvalidString)
+    }
+}
+
+impl ObjectFile {
+    pub fn exports(&self) -> impl Iterator<Item = &ObjectSymbol> {
+        self.symbols.iter().filter(|symbol| symbol.exported)
+    }
+
+    /// Serialises the object. All integers are big-endian, like operands in
+    /// bytecode, and relocation expressions are stored as text.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        push_u32(&mut bytes, self.code.len() as u32);
+        bytes.extend_from_slice(&self.code);
+        push_u32(&mut bytes, self.symbols.len() as u32);
+        for symbol in &self.symbols {
+            push_string(&mut bytes, &symbol.name);
+            push_u32(&mut bytes, symbol.offset);
+            bytes.push(symbol.exported as u8);
+        }
+        push_u32(&mut bytes, self.imports.len() as u32);
+        for import in &self.imports {
+            push_string(&mut bytes, import);
+        }
+        push_u32(&mut bytes, self.relocations.len() as u32);
+        for relocation in &self.relocations {
+            push_u32(&mut bytes, relocation.offset);
+            push_string(&mut bytes, &relocation.expression.to_string());
+            bytes.push(relocation.signed as u8);
+        }
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ObjectFile, ObjectError> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.take(4).map_err(|_| ObjectError::BadMagic)? != MAGIC {
+            return Err(ObjectError::BadMagic);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(ObjectError::UnsupportedVersion { version });
+        }
+
+        let length = reader.u32()? as usize;
+        let code = reader.take(length)?.to_vec();
+
+        let mut symbols = vec![];
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            let offset = reader.u32()?;
+            if offset as usize > code.len() {
+                return Err(ObjectError::OffsetOutOfRange { offset });
+            }
+            let exported = reader.u8()? != 0;
+            symbols.push(ObjectSymbol {
+                name,
+                offset,
+                exported,
+            });
+        }
+
+        let mut imports = vec![];
+        for _ in 0..reader.u32()? {
+            imports.push(reader.string()?);
+        }
+
+        let mut relocations = vec![];
+        for _ in 0..reader.u32()? {
+            let offset = reader.u32()?;
+            if offset as usize + 2 > code.len() {
+                return Err(ObjectError::OffsetOutOfRange { offset });
+            }
+            let text = reader.string()?;
+            let expression = match expression(CompleteStr(&text)) {
+                Ok((rest, expression)) if rest.is_empty() => expression,
+                _ => return Err(ObjectError::InvalidExpression { text }),
+            };
+            let signed = reader.u8()? != 0;
+            relocations.push(Relocation {
+                offset,
+                expression,
+                signed,
+            });
+        }
+
+        if reader.position != bytes.len() {
+            return Err(ObjectError::Truncated);
+        }
+        Ok(ObjectFile {
+            code,
+            symbols,
+            imports,
+            relocations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::expressions::BinaryOp;
+
+    fn sample() -> ObjectFile {
+        ObjectFile {
+            code: vec![1, 7, 0, 0, 6, 7],
+            symbols: vec![ObjectSymbol {
+                name: "start".to_string(),
+                offset: 0,
+                exported: true,
+            }],
+            imports: vec!["square".to_string()],
+            relocations: vec![Relocation {
+                offset: 2,
+                expression: Expression::Binary(
+                    BinaryOp::Add,
+                    Box::new(Expression::Symbol("square".to_string())),
+                    Box::new(Expression::Number(-4)),
+                ),
+                signed: false,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_object_round_trip() {
+        let object = sample();
+        assert_eq!(ObjectFile::from_bytes(&object.to_bytes()), Ok(object));
+    }
+
+    #[test]
+    fn test_object_errors() {
+        assert_eq!(
+            ObjectFile::from_bytes(&[0, 1, 2, 3, 4]),
+            Err(ObjectError::BadMagic)
+        );
+        let bytes = sample().to_bytes();
+        assert_eq!(
+            ObjectFile::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ObjectError::Truncated)
+        );
+        let mut bad = sample();
+        bad.relocations[0].offset = 5;
+        assert_eq!(
+            ObjectFile::from_bytes(&bad.to_bytes()),
+            Err(ObjectError::OffsetOutOfRange { offset: 5 })
+        );
+    }
+}
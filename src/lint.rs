@@ -0,0 +1,404 @@
+use std::fmt;
+
+use crate::assembler::pseudo::SCRATCH_REGISTER;
+use crate::cfg::ControlFlowGraph;
+use crate::debug_info::{DebugEntry, DebugInfo};
+use crate::disassembler::{Decoded, DecodedItem, Operand};
+use crate::instruction::Opcode;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LintKind {
+    UninitializedRead { register: u8 },
+    DeadWrite { register: u8 },
+    UnreachableCode,
+    EndlessLoop,
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LintKind::UninitializedRead { register } => {
+                write!(f, "${} may be read before it is written", register)
+            }
+            LintKind::DeadWrite { register } => {
+                write!(f, "value written to ${} is never read", register)
+            }
+            LintKind::UnreachableCode => write!(f, "unreachable code"),
+            LintKind::EndlessLoop => write!(f, "loop has no exit"),
+        }
+    }
+}
+
+/// A lint about the instruction at `offset`, with the source it came from
+/// if the program was assembled with debug info.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub offset: usize,
+    pub kind: LintKind,
+    pub source: Option<DebugEntry>,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.source {
+            Some(entry) => write!(f, "{}: warning: {}\n    {}", entry, self.kind, entry.text),
+            None => write!(f, "byte {}: warning: {}", self.offset, self.kind),
+        }
+    }
+}
+
+// A set of registers, one bit each.
+type Registers = u32;
+
+const ALL: Registers = !0;
+
+fn bit(register: u8) -> Registers {
+    1u32.checked_shl(u32::from(register)).unwrap_or(0)
+}
+
+// The registers an instruction reads and writes.
+fn uses(item: &DecodedItem) -> (Vec<u8>, Vec<u8>) {
+    let (opcode, operands) = match &item.decoded {
+        Decoded::Instruction { opcode, operands } => (*opcode, operands),
+        Decoded::Byte(_) => return (vec![], vec![]),
+    };
+    let registers: Vec<u8> = operands
+        .iter()
+        .filter_map(|operand| match operand {
+            Operand::Register(register) => Some(*register),
+            Operand::Immediate(_) => None,
+        })
+        .collect();
+    match opcode {
+        Opcode::LOAD => (vec![], registers),
+        Opcode::LUI => (registers.clone(), registers),
+        Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
+            (registers[..2].to_vec(), registers[2..].to_vec())
+        }
+        Opcode::ADDI | Opcode::SUBI | Opcode::MULI => {
+            (registers[..1].to_vec(), registers[1..].to_vec())
+        }
+        Opcode::SPAWN | Opcode::RECV => (vec![], registers),
+        _ => (registers, vec![]),
+    }
+}
+
+/// Runs every lint over a program, using debug info to point at the source.
+/// Reachability and loop exits can't be judged when a jump goes somewhere
+/// that is only known at run time, so those lints are skipped then.
+pub fn lint(program: &[u8], debug_info: Option<&DebugInfo>) -> Vec<Warning> {
+    let cfg = ControlFlowGraph::new(program);
+    let mut warnings = vec![];
+    let mut warn = |offset: usize, kind: LintKind| {
+        warnings.push(Warning {
+            offset,
+            kind,
+            source: debug_info.and_then(|info| info.lookup(offset)).cloned(),
+        })
+    };
+
+    let reachable = reachable_from_entry(&cfg);
+    let exact = cfg.unresolved.is_empty();
+
+    for (offset, register) in uninitialized_reads(&cfg, &reachable) {
+        warn(offset, LintKind::UninitializedRead { register });
+    }
+    for (offset, register) in dead_writes(&cfg, &reachable) {
+        warn(offset, LintKind::DeadWrite { register });
+    }
+    if exact {
+        for (i, block) in cfg.blocks.iter().enumerate() {
+            // Report each unreachable stretch of code once.
+            if !reachable[i] && (i == 0 || reachable[i - 1]) {
+                warn(block.start, LintKind::UnreachableCode);
+            }
+        }
+        for offset in endless_loops(&cfg, &reachable) {
+            warn(offset, LintKind::EndlessLoop);
+        }
+    }
+
+    warnings.sort_by_key(|warning| warning.offset);
+    warnings
+}
+
+fn reachable_from_entry(cfg: &ControlFlowGraph) -> Vec<bool> {
+    let mut reachable = vec![false; cfg.blocks.len()];
+    let mut pending = cfg.entries.clone();
+    while let Some(block) = pending.pop() {
+        if block >= reachable.len() || reachable[block] {
+            continue;
+        }
+        reachable[block] = true;
+        pending.extend(cfg.successors(block).map(|edge| edge.to));
+    }
+    // Anything could follow a jump to an unknown address.
+    if !cfg.unresolved.is_empty() {
+        return vec![true; cfg.blocks.len()];
+    }
+    reachable
+}
+
+// Whether the item starts a thread, which gets a copy of every register.
+fn spawns(item: &DecodedItem) -> bool {
+    matches!(
+        item.decoded,
+        Decoded::Instruction {
+            opcode: Opcode::SPAWN,
+            ..
+        }
+    )
+}
+
+// Reads of registers that are not written on every path from the start.
+fn uninitialized_reads(cfg: &ControlFlowGraph, reachable: &[bool]) -> Vec<(usize, u8)> {
+    let transfer = |block: usize, mut written: Registers| {
+        for item in &cfg.blocks[block].instructions {
+            for register in uses(item).1 {
+                written |= bit(register);
+            }
+        }
+        written
+    };
+
+    // The registers written on every path into each block.
+    let mut written_in = vec![ALL; cfg.blocks.len()];
+    if !written_in.is_empty() {
+        written_in[0] = 0;
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in 1..cfg.blocks.len() {
+            let incoming = cfg
+                .predecessors(block)
+                .filter(|edge| reachable[edge.from])
+                .fold(ALL, |set, edge| {
+                    set & transfer(edge.from, written_in[edge.from])
+                });
+            if incoming != written_in[block] {
+                written_in[block] = incoming;
+                changed = true;
+            }
+        }
+    }
+
+    let mut reads = vec![];
+    for (block, written) in written_in.into_iter().enumerate() {
+        if !reachable[block] {
+            continue;
+        }
+        let mut written = written;
+        for item in &cfg.blocks[block].instructions {
+            let (read, write) = uses(item);
+            for register in read {
+                if written & bit(register) == 0 {
+                    reads.push((item.offset, register));
+                }
+            }
+            for register in write {
+                written |= bit(register);
+            }
+        }
+    }
+    reads
+}
+
+// Writes whose value is overwritten before it is read on every path.
+// Registers are assumed to be read once the program stops, except for the
+// scratch register.
+fn dead_writes(cfg: &ControlFlowGraph, reachable: &[bool]) -> Vec<(usize, u8)> {
+    let at_exit = ALL & !bit(SCRATCH_REGISTER);
+    let live_out = |block: usize, live_in: &[Registers]| {
+        let range = cfg.blocks[block].start..cfg.blocks[block].end;
+        if cfg.unresolved.iter().any(|offset| range.contains(offset)) {
+            return ALL;
+        }
+        let mut successors = cfg.successors(block).peekable();
+        if successors.peek().is_none() {
+            return at_exit;
+        }
+        successors.fold(0, |live, edge| live | live_in[edge.to])
+    };
+    let transfer = |block: usize, mut live: Registers| {
+        for item in cfg.blocks[block].instructions.iter().rev() {
+            if spawns(item) {
+                live = ALL;
+            }
+            let (read, write) = uses(item);
+            for register in write {
+                live &= !bit(register);
+            }
+            for register in read {
+                live |= bit(register);
+            }
+        }
+        live
+    };
+
+    // The registers that may be read after the start of each block.
+    let mut live_in = vec![0; cfg.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in (0..cfg.blocks.len()).rev() {
+            let live = transfer(block, live_out(block, &live_in));
+            if live != live_in[block] {
+                live_in[block] = live;
+                changed = true;
+            }
+        }
+    }
+
+    let mut writes = vec![];
+    for (block, contents) in cfg.blocks.iter().enumerate() {
+        if !reachable[block] {
+            continue;
+        }
+        let mut live = live_out(block, &live_in);
+        for item in contents.instructions.iter().rev() {
+            if spawns(item) {
+                live = ALL;
+            }
+            let (read, write) = uses(item);
+            for register in write {
+                if live & bit(register) == 0 {
+                    writes.push((item.offset, register));
+                }
+                live &= !bit(register);
+            }
+            for register in read {
+                live |= bit(register);
+            }
+        }
+    }
+    writes
+}
+
+// The first block of every loop that can't reach the end of the program.
+fn endless_loops(cfg: &ControlFlowGraph, reachable: &[bool]) -> Vec<usize> {
+    // Blocks from which the program can stop, found by walking backwards
+    // from the blocks that have nowhere to go or leave the program.
+    let mut exits = vec![false; cfg.blocks.len()];
+    let mut pending: Vec<usize> = (0..cfg.blocks.len())
+        .filter(|block| cfg.blocks[*block].exits || cfg.successors(*block).next().is_none())
+        .collect();
+    while let Some(block) = pending.pop() {
+        if exits[block] {
+            continue;
+        }
+        exits[block] = true;
+        pending.extend(cfg.predecessors(block).map(|edge| edge.from));
+    }
+
+    let mut loops = vec![];
+    for edge in &cfg.edges {
+        let backwards = cfg.blocks[edge.to].start <= cfg.blocks[edge.from].start;
+        if backwards && reachable[edge.to] && !exits[edge.to] {
+            let start = cfg.blocks[edge.to].start;
+            if !loops.contains(&start) {
+                loops.push(start);
+            }
+        }
+    }
+    loops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn lints(source: &str) -> Vec<String> {
+        let assembly = Assembler::new().assemble_detailed(source).unwrap();
+        lint(&assembly.bytes, Some(&assembly.debug_info))
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_uninitialized_reads() {
+        assert_eq!(
+            lints(
+                "\tload $0 #1\n\tlt $0 $1\n\tjmpc @skip\n\tload $2 #1\nskip: add $0 $2 $0\n\thlt\n"
+            ),
+            vec![
+                "line 2, column 2: warning: $1 may be read before it is written\n    lt $0 $1",
+                "line 5, column 7 in `skip`: warning: $2 may be read before it is written\n    \
+                 skip: add $0 $2 $0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dead_writes() {
+        assert_eq!(
+            lints("\tload $0 #1\n\tload $0 #2\n\tli $1 #70000\n\tload $1 #3\n\thlt\n"),
+            vec![
+                "line 1, column 2: warning: value written to $0 is never read\n    load $0 #1",
+                "line 3, column 2: warning: value written to $1 is never read\n    li $1 #70000",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unreachable_code_and_endless_loops() {
+        assert_eq!(
+            lints("\tload $0 #1\nloop: add $0 $0 $0\n\tjmp @loop\n\thlt\n\thlt\n"),
+            vec![
+                "line 2, column 7 in `loop`: warning: loop has no exit\n    loop: add $0 $0 $0",
+                "line 4, column 2 in `loop`: warning: unreachable code\n    hlt",
+            ]
+        );
+        assert_eq!(
+            lints("loop: load $0 #1\n\tlt $0 $0\n\tjmpc @loop\n\thlt\n"),
+            Vec::<String>::new()
+        );
+        // Running off the end of the program stops it like a `hlt`.
+        assert_eq!(
+            lints("loop: load $0 #1\n\tlt $0 $0\n\tjmpc @loop\n"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            lints("loop: load $0 #1\n\tbeq $0 $0 @end\n\tjmp @loop\nend:\n"),
+            Vec::<String>::new()
+        );
+        // `t` is reached again with another value in $0, so where its jump
+        // goes is unknown rather than a loop.
+        assert_eq!(
+            lints("\tload $0 @fine\nt:\tjmp $0\nfine:\tload $0 #1\n\tjmp @t\n"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_spawned_threads() {
+        // The child starts with the parent's registers, so the first load
+        // isn't dead, and the child's code is reachable.
+        assert_eq!(
+            lints(
+                "\tload $2 #5\n\tspawn $1 @child\n\tload $2 #6\n\thlt\n\
+                 child: add $2 $1 $3\n\thlt\n"
+            ),
+            Vec::<String>::new()
+        );
+        // Interrupt handlers are reachable too.
+        assert_eq!(
+            lints("\tload $0 #0\n\tsetvec $0 @tick\n\tei\n\thlt\ntick: iret\n"),
+            Vec::<String>::new()
+        );
+        // A received value counts as written.
+        assert_eq!(
+            lints("\trecv $1\n\tadd $1 $1 $2\n\thlt\n"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_unknown_jumps() {
+        // The jump could go anywhere, so nothing is unreachable.
+        let warnings = lint(&[1, 0, 0, 9, 6, 0, 0, 0], None);
+        assert!(warnings.is_empty());
+    }
+}
@@ -1,9 +1,43 @@
+// The codebase favours explicit `return`s and `assert_eq!(x, true)` in tests.
+#![allow(
+    clippy::needless_return,
+    clippy::bool_assert_comparison,
+    clippy::new_without_default
+)]
+
+pub mod aot;
 pub mod assembler;
+pub mod cfg;
+pub mod cli;
+pub mod debug_info;
+pub mod disassembler;
 pub mod instruction;
+pub mod linker;
+pub mod lint;
 pub mod repl;
+#[cfg(test)]
+mod scratch;
+pub mod verifier;
 pub mod vm;
 
 fn main() {
-    let mut repl = repl::REPL::new();
-    repl.run();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match cli::parse_args(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if command == cli::Command::Repl {
+        let mut repl = repl::REPL::new();
+        repl.run();
+        return;
+    }
+    if let Err(errors) = cli::run(command) {
+        for error in errors {
+            eprintln!("error: {}", error);
+        }
+        std::process::exit(1);
+    }
 }
@@ -2,6 +2,7 @@ use std;
 use std::io;
 use std::io::Write;
 
+use crate::assembler::Assembler;
 use crate::vm::VM;
 
 use colored::*;
@@ -20,10 +21,46 @@ impl REPL {
     }
 
     fn exit(&mut self) {
-        println!("");
+        println!();
         std::process::exit(0);
     }
 
+    fn load(&mut self, path: &str) {
+        match Assembler::new().assemble_file_detailed(path) {
+            Ok(assembly) => {
+                let length = assembly.bytes.len();
+                match self.vm.load(assembly.bytes, Some(assembly.debug_info)) {
+                    Ok(()) => println!("Loaded {} bytes", length),
+                    Err(problems) => {
+                        for problem in problems {
+                            println!("{}", problem.to_string().red());
+                        }
+                    }
+                }
+            }
+            Err(errors) => {
+                for error in errors {
+                    println!("{}", error.to_string().red());
+                }
+            }
+        }
+    }
+
+    // The current pc along with the line it was assembled from, if known.
+    fn print_location(&self) {
+        match self.vm.source() {
+            Some(entry) => println!("{:5}  {}\n       {}", self.vm.pc(), entry, entry.text),
+            None => println!("{:5}", self.vm.pc()),
+        }
+    }
+
+    fn print_registers(&self) {
+        for (i, row) in self.vm.registers().chunks(8).enumerate() {
+            let values: Vec<String> = row.iter().map(|value| format!("{:>8}", value)).collect();
+            println!("${:<2}..${:<2}  {}", i * 8, i * 8 + 7, values.join(" "));
+        }
+    }
+
     pub fn run(&mut self) {
         println!("{}{}", "ASMVM".yellow().bold(), "DB".red().italic());
         println!("v0.1\n");
@@ -52,6 +89,21 @@ impl REPL {
                         println!("{}", command);
                     }
                 }
+                ".step" => match self.vm.step() {
+                    Ok(_) => self.print_location(),
+                    Err(fault) => println!("{}", fault.to_string().red()),
+                },
+                ".run" => match self.vm.run() {
+                    Ok(()) => self.print_location(),
+                    Err(fault) => println!("{}", fault.to_string().red()),
+                },
+                ".where" => self.print_location(),
+                ".registers" => self.print_registers(),
+                ".trace on" => self.vm.set_tracer(|entry| println!("{}", entry)),
+                ".trace off" => self.vm.clear_tracer(),
+                _ if buffer.starts_with(".load ") => {
+                    self.load(buffer[".load ".len()..].trim());
+                }
                 _ => {
                     println!("Invalid input: {:?}", buffer);
                 }
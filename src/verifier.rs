@@ -0,0 +1,401 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+
+use crate::disassembler::{decode_at_with, Decoded, Operand};
+use crate::instruction::{Encoding, Opcode};
+use crate::vm::custom::CustomInstructions;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VerifyError {
+    Truncated,
+    InvalidRegister { register: u8 },
+    IllegalOpcode { opcode: u8 },
+    JumpOutOfBounds { target: i64 },
+    JumpIntoInstruction { target: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Truncated => {
+                write!(f, "instruction is cut short by the end of the program")
+            }
+            VerifyError::InvalidRegister { register } => {
+                write!(f, "invalid register ${}", register)
+            }
+            VerifyError::IllegalOpcode { opcode } => write!(f, "illegal opcode {}", opcode),
+            VerifyError::JumpOutOfBounds { target } => {
+                write!(f, "jump to {} lies outside of the program", target)
+            }
+            VerifyError::JumpIntoInstruction { target } => {
+                write!(
+                    f,
+                    "jump to {} lands in the middle of an instruction",
+                    target
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// A problem found by the verifier in the instruction at `offset`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Problem {
+    pub offset: usize,
+    pub error: VerifyError,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {}: {}", self.offset, self.error)
+    }
+}
+
+// Where each instruction starts when the program is decoded in order. A
+// truncated instruction is the last one.
+fn instruction_starts(
+    program: &[u8],
+    encoding: Encoding,
+    custom: &CustomInstructions,
+) -> HashSet<usize> {
+    let mut starts = HashSet::new();
+    let mut offset = 0;
+    while offset < program.len() {
+        starts.insert(offset);
+        let item = decode_at_with(program, offset, encoding, custom);
+        match item.decoded {
+            Decoded::Byte(byte) if is_opcode(byte, custom) => break,
+            Decoded::Byte(_) => offset += encoding.alignment(),
+            _ => offset += item.length,
+        }
+    }
+    starts
+}
+
+fn is_opcode(byte: u8, custom: &CustomInstructions) -> bool {
+    Opcode::from(byte) != Opcode::IGL || custom.get(byte).is_some()
+}
+
+/// Checks every instruction that can be reached from the start of the
+/// program. Jumps are followed when their target is a constant loaded
+/// earlier in the same straight line of code, which ends wherever another
+/// jump lands. If any jump goes somewhere
+/// that can't be worked out ahead of time, every instruction is checked.
+/// The program is read in the encoding its header names, and offsets count
+/// from after the header.
+pub fn verify(program: &[u8]) -> Result<(), Vec<Problem>> {
+    let (encoding, code) = Encoding::detect(program);
+    verify_with(code, encoding, &CustomInstructions::new())
+}
+
+/// Verifies code in `encoding` that may use the instructions in `custom`.
+/// Nothing is assumed about the registers after one of them.
+pub fn verify_with(
+    program: &[u8],
+    encoding: Encoding,
+    custom: &CustomInstructions,
+) -> Result<(), Vec<Problem>> {
+    let starts = instruction_starts(program, encoding, custom);
+    // Registers are only known along a straight line of code, which ends
+    // wherever a jump may land. Forgetting registers there can only lose
+    // targets, so this settles once no new ones turn up.
+    let mut leaders = HashSet::new();
+    loop {
+        let (problems, targets) = check(program, encoding, custom, &starts, &leaders);
+        if targets.is_subset(&leaders) {
+            return if problems.is_empty() {
+                Ok(())
+            } else {
+                Err(problems.into_iter().collect())
+            };
+        }
+        leaders.extend(targets);
+    }
+}
+
+// Walks the program, forgetting what is known about the registers at each
+// of `leaders`. Returns the problems found and every constant target of a
+// jump, branch, `spawn` or `setvec`.
+fn check(
+    program: &[u8],
+    encoding: Encoding,
+    custom: &CustomInstructions,
+    starts: &HashSet<usize>,
+    leaders: &HashSet<usize>,
+) -> (BTreeSet<Problem>, HashSet<usize>) {
+    let mut problems = BTreeSet::new();
+    let mut targets = HashSet::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![0];
+    let mut computed_jump = false;
+
+    while let Some(start) = pending.pop() {
+        let mut offset = start;
+        let mut known: [Option<i32>; 32] = [None; 32];
+        while offset < program.len() && visited.insert(offset) {
+            if leaders.contains(&offset) {
+                known = [None; 32];
+            }
+            let item = decode_at_with(program, offset, encoding, custom);
+            let (opcode, operands) = match item.decoded {
+                Decoded::Instruction { opcode, operands } => (opcode, operands),
+                Decoded::Byte(byte) => {
+                    let error = if is_opcode(byte, custom) {
+                        VerifyError::Truncated
+                    } else {
+                        VerifyError::IllegalOpcode { opcode: byte }
+                    };
+                    problems.insert(Problem { offset, error });
+                    break;
+                }
+            };
+
+            // Invalid registers are reported and then treated as unknown,
+            // so that the rest of the program is still checked.
+            let mut registers = vec![];
+            let mut immediate = None;
+            for operand in operands {
+                match operand {
+                    Operand::Register(register) if usize::from(register) >= known.len() => {
+                        problems.insert(Problem {
+                            offset,
+                            error: VerifyError::InvalidRegister { register },
+                        });
+                        registers.push(None);
+                    }
+                    Operand::Register(register) => registers.push(Some(usize::from(register))),
+                    Operand::Immediate(value) => immediate = Some(value),
+                }
+            }
+            let value = |known: &[Option<i32>; 32], i: usize| registers[i].and_then(|r| known[r]);
+
+            let next = offset + item.length;
+            // A new thread or an interrupt handler starts where `spawn` or
+            // `setvec` points, so that is checked like a jump.
+            let entry = matches!(opcode, Opcode::SPAWN | Opcode::SETVEC);
+            let target = match opcode {
+                Opcode::JMP | Opcode::JMPC => value(&known, 0).map(i64::from),
+                Opcode::JMPB => value(&known, 0).map(|value| next as i64 - i64::from(value)),
+                Opcode::JMPF => value(&known, 0).map(|value| next as i64 + i64::from(value)),
+                _ if opcode.is_branch() || entry => immediate.map(i64::from),
+                _ => None,
+            };
+            let written = match opcode {
+                Opcode::LOAD => Some((registers[0], immediate.map(i32::from))),
+                Opcode::LUI => {
+                    let lower = value(&known, 0);
+                    let value = lower.zip(immediate).map(|(lower, upper)| {
+                        ((u32::from(upper) << 16) | (lower as u32 & 0xFFFF)) as i32
+                    });
+                    Some((registers[0], value))
+                }
+                Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => Some((registers[2], None)),
+                Opcode::ADDI | Opcode::SUBI | Opcode::MULI => Some((registers[1], None)),
+                Opcode::SPAWN | Opcode::RECV => Some((registers[0], None)),
+                _ => None,
+            };
+            if let Some((Some(register), value)) = written {
+                known[register] = value;
+            }
+            if let Opcode::CUSTOM(_) = opcode {
+                known = [None; 32];
+            }
+
+            let jumps = matches!(
+                opcode,
+                Opcode::JMP | Opcode::JMPB | Opcode::JMPF | Opcode::JMPC
+            );
+            if jumps || opcode.is_branch() || entry {
+                // Jumping to the very end stops the program, like running
+                // off the last instruction does.
+                let error = match target {
+                    Some(target) if target < 0 || target > program.len() as i64 => {
+                        Some(VerifyError::JumpOutOfBounds { target })
+                    }
+                    Some(target)
+                        if !starts.contains(&(target as usize))
+                            && target as usize != program.len() =>
+                    {
+                        Some(VerifyError::JumpIntoInstruction {
+                            target: target as usize,
+                        })
+                    }
+                    Some(target) => {
+                        pending.push(target as usize);
+                        targets.insert(target as usize);
+                        None
+                    }
+                    None => {
+                        computed_jump = true;
+                        None
+                    }
+                };
+                if let Some(error) = error {
+                    problems.insert(Problem { offset, error });
+                }
+            }
+            match opcode {
+                // Returning from an interrupt goes back to code that is
+                // checked anyway.
+                Opcode::HLT | Opcode::IRET | Opcode::JMP | Opcode::JMPB | Opcode::JMPF => break,
+                _ => offset = next,
+            }
+        }
+
+        // A jump to an unknown address could land on any instruction.
+        if computed_jump && pending.is_empty() {
+            pending.extend(starts.iter().filter(|start| !visited.contains(*start)));
+        }
+    }
+    (problems, targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn problem(offset: usize, error: VerifyError) -> Problem {
+        Problem { offset, error }
+    }
+
+    #[test]
+    fn test_verify_assembled_program() {
+        let program = Assembler::new()
+            .assemble("load $0 #10\nloop: dec $0\n\tgt $0 $1\n\tjmpc @loop\n\tjmp @end\nend: hlt\n")
+            .unwrap();
+        assert_eq!(verify(&program), Ok(()));
+        assert_eq!(verify(&[]), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_reports_every_problem() {
+        let program = vec![
+            1, 40, 0, 1, //   LOAD $40 #1
+            1, 0, 0, 6, //    LOAD $0 #6
+            9, 0, //          JMPC $0, into the middle of the load above
+            1, 1, 0, 200, //  LOAD $1 #200
+            6, 1,   //          JMP $1
+            200, //           never reached, so not reported
+        ];
+        assert_eq!(
+            verify(&program),
+            Err(vec![
+                problem(0, VerifyError::InvalidRegister { register: 40 }),
+                problem(8, VerifyError::JumpIntoInstruction { target: 6 }),
+                problem(14, VerifyError::JumpOutOfBounds { target: 200 }),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_verify_follows_relative_jumps() {
+        // JMPF $0 skips the illegal byte, JMPB $1 then jumps back to it.
+        let program = vec![1, 0, 0, 1, 8, 0, 200, 1, 1, 0, 7, 7, 1];
+        assert_eq!(
+            verify(&program),
+            Err(vec![problem(6, VerifyError::IllegalOpcode { opcode: 200 })])
+        );
+    }
+
+    #[test]
+    fn test_verify_follows_branches() {
+        // The branch can skip over the illegal byte to a truncated load, or
+        // land in the middle of the subi.
+        let program = vec![
+            22, 0, 1, 0, 6,   //     BNEQ $0 $1 #6
+            200, //
+            18, 0, 0, 1, 1, //     SUBI $0 #1 $1
+            21, 0, 1, 0, 8, //     BEQ  $0 $1 #8
+            1, 0, //
+        ];
+        assert_eq!(
+            verify(&program),
+            Err(vec![
+                problem(5, VerifyError::IllegalOpcode { opcode: 200 }),
+                problem(11, VerifyError::JumpIntoInstruction { target: 8 }),
+                problem(16, VerifyError::Truncated),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_verify_follows_spawns() {
+        let program = vec![
+            27, 1, 0, 9, // SPAWN $1 #9
+            27, 2, 0, 6,   // SPAWN $2 #6, into the middle of itself
+            0,   //          HLT
+            200, //          only reached by the first thread spawned
+        ];
+        assert_eq!(
+            verify(&program),
+            Err(vec![
+                problem(4, VerifyError::JumpIntoInstruction { target: 6 }),
+                problem(9, VerifyError::IllegalOpcode { opcode: 200 }),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_verify_follows_interrupt_handlers() {
+        let program = vec![
+            34, 0, 0, 5, //  SETVEC $0 #5
+            0, //           HLT
+            1, 40, 0, 0,   //  LOAD $40 #0, in the handler
+            33,  //          IRET
+            200, //          never reached
+        ];
+        assert_eq!(
+            verify(&program),
+            Err(vec![problem(
+                5,
+                VerifyError::InvalidRegister { register: 40 }
+            )])
+        );
+    }
+
+    #[test]
+    fn test_verify_words() {
+        let program = Assembler::new()
+            .encoding(Encoding::Fixed)
+            .assemble("load $0 #6\njmp $0\nhlt\nhlt\n")
+            .unwrap();
+        assert_eq!(
+            verify(&program),
+            Err(vec![problem(
+                4,
+                VerifyError::JumpIntoInstruction { target: 6 }
+            )])
+        );
+    }
+
+    #[test]
+    fn test_verify_forgets_registers_at_jump_targets() {
+        // `t` is reached with $0 pointing at `fine` and then with $0 = 1,
+        // inside the first load, so its jump is computed and the illegal
+        // byte after the program is checked too.
+        let mut program = Assembler::new()
+            .assemble("\tload $0 @fine\nt:\tjmp $0\nfine:\tload $0 #1\n\tjmp @t\n")
+            .unwrap();
+        program.push(200);
+        assert_eq!(
+            verify(&program),
+            Err(vec![problem(
+                program.len() - 1,
+                VerifyError::IllegalOpcode { opcode: 200 }
+            )])
+        );
+    }
+
+    #[test]
+    fn test_computed_jumps_check_everything() {
+        // $0 is computed at run time, so the truncated load is reported.
+        let program = vec![2, 1, 1, 0, 6, 0, 0, 1, 3];
+        assert_eq!(
+            verify(&program),
+            Err(vec![problem(7, VerifyError::Truncated)])
+        );
+    }
+}
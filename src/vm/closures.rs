@@ -0,0 +1,335 @@
+use std::rc::Rc;
+
+use crate::instruction::{Encoding, Opcode};
+
+use super::custom::{CustomInstructions, VmState};
+use super::predecode::{decode_instr_with, DecodedInstr};
+use super::{Fault, VmError, VM};
+
+// What happens after an instruction.
+enum Flow {
+    Next,
+    // The instruction has set the program counter.
+    Jump,
+    Halt,
+}
+
+type Op = Box<dyn Fn(&mut VM) -> Result<Flow, VmError>>;
+
+// An instruction with its operands bound, along with where it starts and
+// where a fault leaves the program counter, as in the interpreter.
+struct Step {
+    op: Op,
+    pc: usize,
+    after_fault: usize,
+}
+
+// The instructions from one address up to the next jump.
+struct Block {
+    steps: Vec<Step>,
+    // Where the program carries on if the block doesn't jump.
+    end: usize,
+}
+
+fn compile_instr(instr: DecodedInstr, custom: &Rc<CustomInstructions>) -> Op {
+    let [a, b, c] = instr.operands;
+    let (a, b, c) = (usize::from(a), usize::from(b), usize::from(c));
+    let immediate = instr.immediate;
+    let signed = instr.signed_immediate();
+    let next = instr.next;
+    match instr.opcode {
+        Opcode::HLT => Box::new(move |vm| {
+            println!("HLT encountered");
+            vm.pc = next;
+            Ok(Flow::Halt)
+        }),
+        Opcode::LOAD => Box::new(move |vm| {
+            vm.registers[a] = i32::from(immediate);
+            Ok(Flow::Next)
+        }),
+        Opcode::ADD => Box::new(move |vm| {
+            vm.registers[c] = vm.registers[a].wrapping_add(vm.registers[b]);
+            Ok(Flow::Next)
+        }),
+        Opcode::SUB => Box::new(move |vm| {
+            vm.registers[c] = vm.registers[a].wrapping_sub(vm.registers[b]);
+            Ok(Flow::Next)
+        }),
+        Opcode::MUL => Box::new(move |vm| {
+            vm.registers[c] = vm.registers[a].wrapping_mul(vm.registers[b]);
+            Ok(Flow::Next)
+        }),
+        Opcode::DIV => Box::new(move |vm| {
+            let (dividend, divisor) = (vm.registers[a], vm.registers[b]);
+            if divisor == 0 {
+                return Err(VmError::DivisionByZero);
+            }
+            vm.registers[c] = dividend.wrapping_div(divisor);
+            vm.remainder = dividend.wrapping_rem(divisor) as u32;
+            Ok(Flow::Next)
+        }),
+        Opcode::JMP => Box::new(move |vm| jump(vm, i64::from(vm.registers[a]))),
+        Opcode::JMPB => Box::new(move |vm| jump(vm, next as i64 - i64::from(vm.registers[a]))),
+        Opcode::JMPF => Box::new(move |vm| jump(vm, next as i64 + i64::from(vm.registers[a]))),
+        Opcode::JMPC => Box::new(move |vm| {
+            if vm.conditional {
+                return jump(vm, i64::from(vm.registers[a]));
+            }
+            Ok(Flow::Next)
+        }),
+        Opcode::EQ => compare(a, b, |x, y| x == y),
+        Opcode::NEQ => compare(a, b, |x, y| x != y),
+        Opcode::GT => compare(a, b, |x, y| x > y),
+        Opcode::LT => compare(a, b, |x, y| x < y),
+        Opcode::GTQ => compare(a, b, |x, y| x >= y),
+        Opcode::LTQ => compare(a, b, |x, y| x <= y),
+        Opcode::LUI => Box::new(move |vm| {
+            let upper = u32::from(immediate) << 16;
+            let lower = vm.registers[a] as u32 & 0xFFFF;
+            vm.registers[a] = (upper | lower) as i32;
+            Ok(Flow::Next)
+        }),
+        Opcode::ADDI => Box::new(move |vm| {
+            vm.registers[b] = vm.registers[a].wrapping_add(signed);
+            Ok(Flow::Next)
+        }),
+        Opcode::SUBI => Box::new(move |vm| {
+            vm.registers[b] = vm.registers[a].wrapping_sub(signed);
+            Ok(Flow::Next)
+        }),
+        Opcode::MULI => Box::new(move |vm| {
+            vm.registers[b] = vm.registers[a].wrapping_mul(signed);
+            Ok(Flow::Next)
+        }),
+        Opcode::CMPI => Box::new(move |vm| {
+            vm.conditional = vm.registers[a] == signed;
+            Ok(Flow::Next)
+        }),
+        Opcode::BEQ | Opcode::BNEQ | Opcode::BGT | Opcode::BLT | Opcode::BGTQ | Opcode::BLTQ => {
+            let opcode = instr.opcode;
+            Box::new(move |vm| {
+                if opcode.compare(vm.registers[a], vm.registers[b]) {
+                    vm.pc = usize::from(immediate);
+                    return Ok(Flow::Jump);
+                }
+                Ok(Flow::Next)
+            })
+        }
+        Opcode::NOP => Box::new(|_| Ok(Flow::Next)),
+        Opcode::CUSTOM(byte) => {
+            let custom = Rc::clone(custom);
+            Box::new(move |vm| {
+                let instruction = match custom.get(byte) {
+                    Some(instruction) => instruction,
+                    None => unreachable!(),
+                };
+                let mut state = VmState {
+                    registers: &mut vm.registers,
+                    remainder: &mut vm.remainder,
+                    conditional: &mut vm.conditional,
+                };
+                instruction.run(&mut state, &instr)?;
+                Ok(Flow::Next)
+            })
+        }
+        // Only the interpreter runs threads of a scheduler.
+        Opcode::SPAWN | Opcode::SEND | Opcode::RECV => Box::new(|_| Err(VmError::NoScheduler)),
+        Opcode::YIELD => Box::new(|_| Ok(Flow::Next)),
+        // Turning on interrupts or the timer leaves the block, so that the
+        // interpreter can take over.
+        Opcode::EI => Box::new(move |vm| {
+            vm.interrupts.set_enabled(true);
+            vm.pc = next;
+            Ok(Flow::Jump)
+        }),
+        Opcode::DI => Box::new(|vm| {
+            vm.interrupts.set_enabled(false);
+            Ok(Flow::Next)
+        }),
+        Opcode::IRET => Box::new(|vm| {
+            let (pc, conditional) = vm.interrupts.ret()?;
+            vm.pc = pc;
+            vm.conditional = conditional;
+            Ok(Flow::Jump)
+        }),
+        Opcode::SETVEC => Box::new(move |vm| {
+            vm.interrupts
+                .set_vector(vm.registers[a], usize::from(immediate))?;
+            Ok(Flow::Next)
+        }),
+        Opcode::TIMER => Box::new(move |vm| {
+            vm.interrupts.set_timer(vm.registers[a]);
+            vm.pc = next;
+            Ok(Flow::Jump)
+        }),
+        Opcode::IGL => unreachable!(),
+    }
+}
+
+fn jump(vm: &mut VM, target: i64) -> Result<Flow, VmError> {
+    if target < 0 {
+        return Err(VmError::InvalidJump { target });
+    }
+    vm.pc = target as usize;
+    Ok(Flow::Jump)
+}
+
+fn compare<F: Fn(i32, i32) -> bool + 'static>(a: usize, b: usize, test: F) -> Op {
+    Box::new(move |vm| {
+        vm.conditional = test(vm.registers[a], vm.registers[b]);
+        Ok(Flow::Next)
+    })
+}
+
+fn compile_block(
+    program: &[u8],
+    start: usize,
+    encoding: Encoding,
+    custom: &Rc<CustomInstructions>,
+) -> Block {
+    let mut steps = vec![];
+    let mut offset = start;
+    while offset < program.len() {
+        let instr = match decode_instr_with(program, offset, encoding, custom) {
+            Ok(instr) => instr,
+            Err((error, after_fault)) => {
+                steps.push(Step {
+                    op: Box::new(move |_| Err(error.clone())),
+                    pc: offset,
+                    after_fault,
+                });
+                break;
+            }
+        };
+        steps.push(Step {
+            op: compile_instr(instr, custom),
+            pc: offset,
+            after_fault: instr.next,
+        });
+        offset = instr.next;
+        if let Opcode::HLT | Opcode::JMP | Opcode::JMPB | Opcode::JMPF | Opcode::JMPC = instr.opcode
+        {
+            break;
+        }
+        if instr.opcode.is_branch() {
+            break;
+        }
+    }
+    Block { steps, end: offset }
+}
+
+/// A program compiled into closures, one basic block at a time as each is
+/// first reached.
+pub struct Closures {
+    // One for each byte of the program.
+    blocks: Vec<Option<Block>>,
+}
+
+impl Closures {
+    pub fn new() -> Closures {
+        Closures { blocks: vec![] }
+    }
+
+    fn block(
+        &mut self,
+        program: &[u8],
+        pc: usize,
+        encoding: Encoding,
+        custom: &Rc<CustomInstructions>,
+    ) -> &Block {
+        if self.blocks.is_empty() {
+            self.blocks = (0..program.len()).map(|_| None).collect();
+        }
+        if self.blocks[pc].is_none() {
+            self.blocks[pc] = Some(compile_block(program, pc, encoding, custom));
+        }
+        match &self.blocks[pc] {
+            Some(block) => block,
+            None => unreachable!(),
+        }
+    }
+
+    /// Runs the program loaded into `vm` until it halts, runs off its end or
+    /// faults, or until it turns on interrupts, which only the interpreter
+    /// takes. Returns whether the program stopped.
+    pub fn run(&mut self, vm: &mut VM) -> Result<bool, Fault> {
+        while vm.pc < vm.program.len() {
+            if vm.interrupts.active() {
+                return Ok(false);
+            }
+            let block = self.block(&vm.program, vm.pc, vm.encoding, &vm.custom);
+            let mut flow = Flow::Next;
+            for step in &block.steps {
+                match (step.op)(vm) {
+                    Ok(Flow::Next) => {}
+                    Ok(other) => {
+                        flow = other;
+                        break;
+                    }
+                    Err(error) => {
+                        vm.pc = step.after_fault;
+                        return Err(vm.fault(step.pc, error));
+                    }
+                }
+            }
+            match flow {
+                Flow::Next => vm.pc = block.end,
+                Flow::Jump => {}
+                Flow::Halt => return Ok(true),
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::{testing, Engine};
+
+    fn differential(program: Vec<u8>) -> Result<(), Fault> {
+        testing::differential(program, Engine::Closures, |_| {})
+    }
+
+    #[test]
+    fn test_closures() {
+        let fib = Assembler::new()
+            .assemble(
+                "\tclr $0\n\tli $1 #1\n\tclr $4\n\tli $6 #20\n\
+                 loop:\n\tmov $1 $2\n\tadd $0 $1 $1\n\tmov $2 $0\n\tinc $4\n\
+                 \tlt $4 $6\n\tjmpc @loop\n\thlt\n",
+            )
+            .unwrap();
+        differential(fib).unwrap();
+        differential(vec![
+            1, 1, 0, 0, //  LOAD $1 #0, which ends in a HLT
+            1, 0, 0, 5, //  LOAD $0 #5
+            1, 2, 0, 1, //  LOAD $2 #1
+            1, 6, 0, 3, //  LOAD $6 #3
+            3, 0, 2, 0, //  SUB  $0 $2 $0
+            1, 5, 0, 1, //  LOAD $5 #1
+            10, 0, 1, //    EQ   $0 $1
+            9, 6, //        JMPC $6
+            8, 5,   //        JMPF $5, over the illegal byte
+            200, //
+            1, 4, 0, 22, // LOAD $4 #22
+            7, 4, //        JMPB $4, back to the SUB
+        ])
+        .unwrap();
+    }
+
+    #[test]
+    fn test_closure_faults() {
+        let faults = vec![
+            vec![1, 0, 0, 4, 1, 1, 0, 0, 5, 0, 1, 2],
+            vec![1, 0, 0, 8, 7, 0],
+            vec![1, 0, 0, 1, 2, 0, 0, 0, 200],
+            vec![1, 0, 0, 1, 2, 0, 40, 0],
+            vec![1, 0, 0, 1, 1, 0],
+        ];
+        for program in faults {
+            assert!(differential(program).is_err());
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::assembler::pseudo::PseudoOp;
+use crate::disassembler::Operand;
+use crate::instruction::{Opcode, OperandKind};
+
+use super::predecode::DecodedInstr;
+use super::{VmError, REGISTER_COUNT};
+
+/// Opcode bytes set aside for instructions added by embedders. The built-in
+/// instruction set will never use them.
+pub const CUSTOM_OPCODES: RangeInclusive<u8> = 128..=254;
+
+/// The operand lists the assembler can parse, in order.
+const OPERAND_SHAPES: &[&[OperandKind]] = {
+    use OperandKind::{Immediate, Register};
+    &[
+        &[],
+        &[Register],
+        &[Immediate],
+        &[Register, Register],
+        &[Register, Immediate],
+        &[Register, Register, Register],
+        &[Register, Immediate, Register],
+        &[Register, Register, Immediate],
+    ]
+};
+
+/// The parts of the machine a custom instruction can see and change.
+pub struct VmState<'a> {
+    pub registers: &'a mut [i32; REGISTER_COUNT],
+    pub remainder: &'a mut u32,
+    pub conditional: &'a mut bool,
+}
+
+/// Runs a custom instruction, given its operands in order.
+pub type Handler = fn(&mut VmState, &[Operand]) -> Result<(), VmError>;
+
+#[derive(Debug, Clone)]
+pub struct CustomInstruction {
+    pub byte: u8,
+    pub mnemonic: String,
+    pub operands: Vec<OperandKind>,
+    pub handler: Handler,
+}
+
+impl CustomInstruction {
+    /// Runs the instruction with the operands it was decoded with.
+    pub fn run(&self, state: &mut VmState, instr: &DecodedInstr) -> Result<(), VmError> {
+        let mut operands = [Operand::Immediate(0); 4];
+        let mut registers = instr.operands.iter();
+        for (operand, kind) in operands.iter_mut().zip(&self.operands) {
+            *operand = match kind {
+                OperandKind::Register => Operand::Register(*registers.next().unwrap_or(&0)),
+                OperandKind::Immediate => Operand::Immediate(instr.immediate),
+            };
+        }
+        (self.handler)(state, &operands[..self.operands.len()])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CustomInstructionError {
+    InvalidMnemonic { mnemonic: String },
+    MnemonicTaken { mnemonic: String },
+    UnsupportedOperands { mnemonic: String },
+    NoOpcodesLeft,
+}
+
+impl fmt::Display for CustomInstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CustomInstructionError::InvalidMnemonic { mnemonic } => {
+                write!(f, "`{}` is not a valid mnemonic", mnemonic)
+            }
+            CustomInstructionError::MnemonicTaken { mnemonic } => {
+                write!(f, "there is already an instruction called `{}`", mnemonic)
+            }
+            CustomInstructionError::UnsupportedOperands { mnemonic } => write!(
+                f,
+                "`{}` takes operands in an order the assembler can't parse",
+                mnemonic
+            ),
+            CustomInstructionError::NoOpcodesLeft => {
+                write!(f, "every custom opcode is already in use")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CustomInstructionError {}
+
+/// Instructions added by an embedder, each with an opcode from
+/// `CUSTOM_OPCODES`. The VM runs them, and the assembler and disassembler
+/// handed the same registry know their mnemonics.
+#[derive(Debug, Clone, Default)]
+pub struct CustomInstructions {
+    instructions: Vec<CustomInstruction>,
+}
+
+impl CustomInstructions {
+    pub fn new() -> CustomInstructions {
+        CustomInstructions {
+            instructions: vec![],
+        }
+    }
+
+    /// Adds an instruction, giving it the lowest free opcode. Mnemonics are
+    /// lowercase letters, like the built-in ones. Operands take one of the
+    /// shapes built-in instructions have: up to three registers, or a
+    /// register and an immediate with an optional register before or after
+    /// it, or a lone immediate.
+    pub fn register(
+        &mut self,
+        mnemonic: &str,
+        operands: &[OperandKind],
+        handler: Handler,
+    ) -> Result<u8, CustomInstructionError> {
+        if mnemonic.is_empty() || !mnemonic.chars().all(|c| c.is_ascii_lowercase()) {
+            return Err(CustomInstructionError::InvalidMnemonic {
+                mnemonic: mnemonic.to_string(),
+            });
+        }
+        if Opcode::from_mnemonic(mnemonic) != Opcode::IGL
+            || PseudoOp::from_mnemonic(mnemonic).is_some()
+            || self.find(mnemonic).is_some()
+        {
+            return Err(CustomInstructionError::MnemonicTaken {
+                mnemonic: mnemonic.to_string(),
+            });
+        }
+        if !OPERAND_SHAPES.contains(&operands) {
+            return Err(CustomInstructionError::UnsupportedOperands {
+                mnemonic: mnemonic.to_string(),
+            });
+        }
+        let byte = match CUSTOM_OPCODES
+            .clone()
+            .find(|byte| self.get(*byte).is_none())
+        {
+            Some(byte) => byte,
+            None => return Err(CustomInstructionError::NoOpcodesLeft),
+        };
+        self.instructions.push(CustomInstruction {
+            byte,
+            mnemonic: mnemonic.to_string(),
+            operands: operands.to_vec(),
+            handler,
+        });
+        Ok(byte)
+    }
+
+    /// The instruction with the opcode `byte`, if there is one.
+    pub fn get(&self, byte: u8) -> Option<&CustomInstruction> {
+        self.instructions
+            .iter()
+            .find(|instruction| instruction.byte == byte)
+    }
+
+    /// The instruction called `mnemonic`, if there is one.
+    pub fn find(&self, mnemonic: &str) -> Option<&CustomInstruction> {
+        self.instructions
+            .iter()
+            .find(|instruction| instruction.mnemonic == mnemonic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::disassembler::Disassembler;
+    use crate::vm::{Engine, Fault, VM};
+    use std::rc::Rc;
+
+    // `sqrt $a $b` puts the square root of $a in $b.
+    fn sqrt(state: &mut VmState, operands: &[Operand]) -> Result<(), VmError> {
+        match operands {
+            [Operand::Register(a), Operand::Register(b)] => {
+                let value = state.registers[usize::from(*a)];
+                if value < 0 {
+                    return Err(VmError::Custom {
+                        message: format!("square root of {}", value),
+                    });
+                }
+                state.registers[usize::from(*b)] = f64::from(value).sqrt() as i32;
+                Ok(())
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // `addv $a #i` adds `i` to $a and sets the flag if it overflowed.
+    fn addv(state: &mut VmState, operands: &[Operand]) -> Result<(), VmError> {
+        if let [Operand::Register(a), Operand::Immediate(i)] = operands {
+            let register = &mut state.registers[usize::from(*a)];
+            let (sum, overflowed) = register.overflowing_add(i32::from(*i));
+            *register = sum;
+            *state.conditional = overflowed;
+        }
+        Ok(())
+    }
+
+    fn vm() -> VM {
+        use OperandKind::{Immediate, Register};
+        VM::builder()
+            .custom_instruction("sqrt", &[Register, Register], sqrt)
+            .custom_instruction("addv", &[Register, Immediate], addv)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_register() {
+        let mut custom = CustomInstructions::new();
+        assert_eq!(custom.register("sqrt", &[], sqrt), Ok(128));
+        assert_eq!(custom.register("cube", &[], sqrt), Ok(129));
+        assert_eq!(custom.find("cube").map(|c| c.byte), Some(129));
+        for taken in &["sqrt", "add", "mov"] {
+            assert_eq!(
+                custom.register(taken, &[], sqrt),
+                Err(CustomInstructionError::MnemonicTaken {
+                    mnemonic: taken.to_string()
+                })
+            );
+        }
+        assert!(custom.register("Sqrt2", &[], sqrt).is_err());
+        assert!(VM::builder()
+            .custom_instruction("add", &[], sqrt)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_operand_shapes() {
+        use OperandKind::{Immediate, Register};
+        let mut custom = CustomInstructions::new();
+        let refused: [&[OperandKind]; 5] = [
+            &[Immediate, Register],
+            &[Immediate, Register, Register],
+            &[Register, Register, Register, Immediate],
+            &[Immediate, Immediate],
+            &[Register; 4],
+        ];
+        for operands in &refused {
+            assert_eq!(
+                custom.register("odd", operands, sqrt),
+                Err(CustomInstructionError::UnsupportedOperands {
+                    mnemonic: "odd".to_string()
+                })
+            );
+        }
+
+        // Every shape that is accepted can be assembled.
+        let uses = [
+            "a",
+            "b $1",
+            "c #1",
+            "d $1 $2",
+            "e $1 #2",
+            "f $1 $2 $3",
+            "g $1 #2 $3",
+            "h $1 $2 #3",
+        ];
+        for (operands, line) in OPERAND_SHAPES.iter().zip(&uses) {
+            custom.register(&line[..1], operands, sqrt).unwrap();
+        }
+        let custom = Rc::new(custom);
+        for line in &uses {
+            assert!(Assembler::new()
+                .custom_instructions(Rc::clone(&custom))
+                .assemble(line)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn test_custom_instructions() {
+        let mut vm = vm();
+        let program = Assembler::new()
+            .custom_instructions(vm.custom_instructions())
+            .assemble("\tload $0 #150\n\tsqrt $0 $1\n\taddv $1 #30000\n\thlt\n")
+            .unwrap();
+        assert_eq!(&program[4..7], &[128, 0, 1]);
+        assert_eq!(
+            Disassembler::new()
+                .custom_instructions(vm.custom_instructions())
+                .disassemble_to_string(&program),
+            "0000: load $0 #150\n0004: sqrt $0 $1\n0007: addv $1 #30000\n0011: hlt\n"
+        );
+
+        for engine in &[Engine::Interpreter, Engine::Closures, Engine::Jit] {
+            vm.set_engine(*engine);
+            vm.load(program.clone(), None).unwrap();
+            vm.run().unwrap();
+            assert_eq!(vm.registers()[1], 30012);
+            assert_eq!(vm.conditional(), false);
+        }
+
+        let program = Assembler::new()
+            .custom_instructions(vm.custom_instructions())
+            .assemble("\tli $0 #-4\n\tsqrt $0 $1\n")
+            .unwrap();
+        vm.load(program, None).unwrap();
+        assert_eq!(
+            vm.run(),
+            Err(Fault {
+                pc: 8,
+                error: VmError::Custom {
+                    message: "square root of -4".to_string()
+                },
+                source: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_custom_mnemonics_in_assembler() {
+        let custom = vm().custom_instructions();
+        let assemble = |source: &str| {
+            Assembler::new()
+                .custom_instructions(Rc::clone(&custom))
+                .assemble(source)
+                .map_err(|errors| errors[0].error.to_string())
+        };
+        assert_eq!(
+            assemble("sqrt $1 #2\n"),
+            Err("invalid operands for `sqrt`".to_string())
+        );
+        assert_eq!(
+            assemble("cube $1 $2\n"),
+            Err("unknown instruction `cube`".to_string())
+        );
+        // Without the registry, custom opcodes are illegal.
+        assert!(Assembler::new().assemble("sqrt $1 $2\n").is_err());
+        let mut plain = VM::new();
+        plain.load(vec![128, 0, 1], None).unwrap();
+        assert_eq!(
+            plain.run().map_err(|fault| fault.error),
+            Err(VmError::IllegalOpcode { opcode: 128 })
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use super::VmError;
+
+/// How many interrupts a program can set handlers for with `setvec`.
+pub const INTERRUPT_COUNT: usize = 8;
+
+/// The interrupt raised by the timer.
+pub const TIMER_INTERRUPT: usize = 0;
+
+/// The interrupt controller and timer of a VM. Interrupts start out
+/// disabled, with no handlers and the timer stopped.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupts {
+    enabled: bool,
+    // One bit for each interrupt that has been raised but not taken yet.
+    pending: u32,
+    vectors: [Option<usize>; INTERRUPT_COUNT],
+    // Where to go back to, and the flag to restore, while in a handler.
+    saved: Option<(usize, bool)>,
+    // The timer fires every `period` instructions, when `countdown` runs
+    // out. A period of 0 stops it.
+    period: u32,
+    countdown: u32,
+}
+
+impl Interrupts {
+    /// Whether an interrupt may be taken or the timer is counting, which
+    /// the VM has to check for between every instruction.
+    pub fn active(&self) -> bool {
+        self.enabled || self.period > 0
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Marks `interrupt` as pending. Returns false if there is no such
+    /// interrupt.
+    pub fn raise(&mut self, interrupt: usize) -> bool {
+        if interrupt >= INTERRUPT_COUNT {
+            return false;
+        }
+        self.pending |= 1 << interrupt;
+        true
+    }
+
+    pub fn set_vector(&mut self, interrupt: i32, handler: usize) -> Result<(), VmError> {
+        match self.vectors.get_mut(interrupt as usize) {
+            Some(vector) if interrupt >= 0 => *vector = Some(handler),
+            _ => return Err(VmError::NoSuchInterrupt { interrupt }),
+        }
+        Ok(())
+    }
+
+    /// Starts the timer, counting from the instruction that starts it, or
+    /// stops it if `period` isn't positive.
+    pub fn set_timer(&mut self, period: i32) {
+        self.period = period.max(0) as u32;
+        self.countdown = self.period;
+    }
+
+    /// Counts an instruction towards the timer.
+    pub fn tick(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.raise(TIMER_INTERRUPT);
+            self.countdown = self.period;
+        }
+    }
+
+    /// If an interrupt can be taken before the instruction at `pc`, saves
+    /// `pc` and `conditional`, disables interrupts and returns the handler
+    /// to go to. The lowest pending interrupt goes first, and any without a
+    /// handler are dropped. Handlers don't nest.
+    pub fn take(&mut self, pc: usize, conditional: bool) -> Option<usize> {
+        if !self.enabled || self.saved.is_some() {
+            return None;
+        }
+        while self.pending != 0 {
+            let interrupt = self.pending.trailing_zeros() as usize;
+            self.pending &= !(1 << interrupt);
+            if let Some(handler) = self.vectors[interrupt] {
+                self.saved = Some((pc, conditional));
+                self.enabled = false;
+                return Some(handler);
+            }
+        }
+        None
+    }
+
+    /// Leaves a handler, enabling interrupts again. Returns where the
+    /// interrupt was taken and the flag as it was then.
+    pub fn ret(&mut self) -> Result<(usize, bool), VmError> {
+        let saved = self.saved.take().ok_or(VmError::NotInInterrupt)?;
+        self.enabled = true;
+        Ok(saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::{Engine, VM};
+
+    fn loaded(source: &str, engine: Engine) -> VM {
+        let mut vm = VM::new();
+        vm.set_engine(engine);
+        vm.load(Assembler::new().assemble(source).unwrap(), None)
+            .unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_timer() {
+        // Counts in $2 until the timer has fired three times. Each period
+        // is two instructions of handler and four iterations of the loop.
+        let source = "\tsetvec $0 @tick\n\tload $1 #10\n\ttimer $1\n\tload $3 #1\n\
+                      \tload $6 #3\n\tei\nloop:\tadd $2 $3 $2\n\tbneq $5 $6 @loop\n\thlt\n\
+                      tick:\tadd $5 $3 $5\n\tiret\n";
+        for &engine in &[Engine::Interpreter, Engine::Closures, Engine::Jit] {
+            let mut vm = loaded(source, engine);
+            vm.run().unwrap();
+            assert_eq!(vm.registers()[5], 3);
+            assert_eq!(vm.registers()[2], 12);
+            assert!(vm.interrupts_enabled());
+        }
+    }
+
+    #[test]
+    fn test_raised_interrupts() {
+        // The interrupt waits for `ei`, and `iret` puts the flag back.
+        let source = "\tload $0 #1\n\tsetvec $0 @handler\n\tload $1 #5\n\tei\n\
+                      \tload $1 #6\n\thlt\nhandler:\tload $2 #7\n\teq $2 $2\n\tiret\n";
+        let mut vm = loaded(source, Engine::Interpreter);
+        assert!(vm.raise_interrupt(1));
+        assert!(vm.raise_interrupt(2));
+        assert!(!vm.raise_interrupt(INTERRUPT_COUNT));
+        vm.run().unwrap();
+        assert_eq!(vm.registers()[1], 6);
+        assert_eq!(vm.registers()[2], 7);
+        assert!(!vm.conditional());
+
+        let mut vm = loaded(
+            "\tdi\n\tload $0 #1\n\tsetvec $0 @end\nend:\thlt\n",
+            Engine::Closures,
+        );
+        vm.raise_interrupt(1);
+        vm.run().unwrap();
+        assert_eq!(vm.pc(), 10);
+    }
+
+    #[test]
+    fn test_interrupt_faults() {
+        for &engine in &[Engine::Interpreter, Engine::Closures] {
+            let mut vm = loaded("\tload $0 #8\n\tsetvec $0 #0\n", engine);
+            let fault = vm.run().unwrap_err();
+            assert_eq!(fault.error, VmError::NoSuchInterrupt { interrupt: 8 });
+            assert_eq!(fault.pc, 4);
+
+            let mut vm = loaded("\tei\n\tiret\n", engine);
+            assert_eq!(vm.run().unwrap_err().error, VmError::NotInInterrupt);
+        }
+    }
+}
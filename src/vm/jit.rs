@@ -0,0 +1,557 @@
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+use crate::instruction::{Encoding, Opcode};
+
+use super::custom::CustomInstructions;
+use super::predecode::decode_instr_with;
+use super::REGISTER_COUNT;
+
+/// How many times the interpreter has to reach an instruction before the
+/// code starting there is compiled.
+pub const HOT_THRESHOLD: u32 = 50;
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const PROT_EXEC: c_int = 4;
+const MAP_PRIVATE: c_int = 2;
+const MAP_ANONYMOUS: c_int = 0x20;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+// A compiled block takes the register file, the conditional flag and the
+// remainder, and returns the address to carry on from. With `INTERPRET` set,
+// the instruction at that address has to be run by the interpreter first.
+type NativeBlock = extern "C" fn(*mut i32, *mut bool, *mut u32) -> u64;
+
+const INTERPRET: u64 = 1 << 63;
+
+/// Where execution goes after a compiled block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    /// Carry on from this address.
+    Continue(usize),
+    /// Run the instruction at this address in the interpreter. It either
+    /// isn't supported by the JIT or it faults.
+    Interpret(usize),
+}
+
+// Native code in memory that has been made executable.
+struct Block {
+    memory: *mut c_void,
+    len: usize,
+}
+
+impl Block {
+    fn new(code: &[u8]) -> Option<Block> {
+        unsafe {
+            let memory = mmap(
+                ptr::null_mut(),
+                code.len(),
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if memory as isize == -1 {
+                return None;
+            }
+            let block = Block {
+                memory,
+                len: code.len(),
+            };
+            ptr::copy_nonoverlapping(code.as_ptr(), memory as *mut u8, code.len());
+            if mprotect(memory, code.len(), PROT_READ | PROT_EXEC) != 0 {
+                return None;
+            }
+            Some(block)
+        }
+    }
+
+    fn call(
+        &self,
+        registers: &mut [i32; REGISTER_COUNT],
+        conditional: &mut bool,
+        remainder: &mut u32,
+    ) -> Exit {
+        let native: NativeBlock = unsafe { std::mem::transmute(self.memory) };
+        let exit = native(registers.as_mut_ptr(), conditional, remainder);
+        if exit & INTERPRET != 0 {
+            Exit::Interpret((exit & !INTERPRET) as usize)
+        } else {
+            Exit::Continue(exit as usize)
+        }
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.memory, self.len);
+        }
+    }
+}
+
+// x86-64 machine code for a block. The register file is addressed through
+// rdi, the conditional flag through rsi and the remainder through r8.
+struct Emitter {
+    code: Vec<u8>,
+}
+
+impl Emitter {
+    fn new() -> Emitter {
+        // mov r8, rdx
+        Emitter {
+            code: vec![0x49, 0x89, 0xD0],
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, value: u32) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // mov eax, [register]
+    fn load_eax(&mut self, register: u8) {
+        self.emit(&[0x8B, 0x47, register * 4]);
+    }
+
+    // mov ecx, [register]
+    fn load_ecx(&mut self, register: u8) {
+        self.emit(&[0x8B, 0x4F, register * 4]);
+    }
+
+    // mov [register], eax
+    fn store_eax(&mut self, register: u8) {
+        self.emit(&[0x89, 0x47, register * 4]);
+    }
+
+    // mov rax, INTERPRET | pc; ret
+    fn interpret(&mut self, pc: usize) {
+        self.emit(&[0x48, 0xB8]);
+        self.code
+            .extend_from_slice(&(INTERPRET | pc as u64).to_le_bytes());
+        self.emit(&[0xC3]);
+    }
+
+    // mov eax, pc; ret
+    fn continue_at(&mut self, pc: usize) {
+        self.emit(&[0xB8]);
+        self.emit_u32(pc as u32);
+        self.emit(&[0xC3]);
+    }
+
+    // Jumps to the address in rax. A block that jumps back to its own start
+    // loops without leaving native code, and otherwise returns the address.
+    // A negative address is left to the interpreter to report as a fault of
+    // the jump at `pc`.
+    fn jump_to_rax(&mut self, pc: usize, start: usize) {
+        // test rax, rax; jns over the bail-out
+        self.emit(&[0x48, 0x85, 0xC0, 0x79, 11]);
+        self.interpret(pc);
+        // cmp rax, start; je to just after the prologue
+        self.emit(&[0x48, 0x3D]);
+        self.emit_u32(start as u32);
+        self.emit(&[0x0F, 0x84]);
+        let after = self.code.len() as i32 + 4;
+        self.emit_u32((PROLOGUE_LENGTH as i32 - after) as u32);
+        self.emit(&[0xC3]);
+    }
+
+    // Bails out to the interpreter when the flags say `condition` (a short
+    // jcc opcode) does not hold.
+    fn unless(&mut self, condition: u8, pc: usize) {
+        self.emit(&[condition, 11]);
+        self.interpret(pc);
+    }
+}
+
+const JNZ: u8 = 0x75;
+const PROLOGUE_LENGTH: usize = 3;
+
+// Compiles the straight line of code starting at `start`, up to and
+// including the first jump. Returns `None` if the first instruction can't
+// be compiled.
+fn compile(program: &[u8], start: usize, encoding: Encoding) -> Option<Vec<u8>> {
+    let custom = CustomInstructions::new();
+    let mut emitter = Emitter::new();
+    let mut offset = start;
+    while offset < program.len() {
+        let instr = match decode_instr_with(program, offset, encoding, &custom) {
+            Ok(instr) => instr,
+            Err(_) => break,
+        };
+        let [a, b, c] = instr.operands;
+        let next = instr.next;
+        match instr.opcode {
+            Opcode::LOAD => {
+                // mov dword [a], immediate
+                emitter.emit(&[0xC7, 0x47, a * 4]);
+                emitter.emit_u32(u32::from(instr.immediate));
+            }
+            Opcode::ADD | Opcode::SUB | Opcode::MUL => {
+                emitter.load_eax(a);
+                emitter.load_ecx(b);
+                emitter.emit(match instr.opcode {
+                    Opcode::ADD => &[0x01, 0xC8],
+                    Opcode::SUB => &[0x29, 0xC8],
+                    _ => &[0x0F, 0xAF, 0xC1],
+                });
+                emitter.store_eax(c);
+            }
+            Opcode::DIV => {
+                emitter.load_eax(a);
+                emitter.load_ecx(b);
+                // Division by zero faults, and dividing the smallest
+                // number by -1 overflows, so both are interpreted.
+                emitter.emit(&[0x85, 0xC9]); // test ecx, ecx
+                emitter.unless(JNZ, offset);
+                emitter.emit(&[0x83, 0xF9, 0xFF]); // cmp ecx, -1
+                emitter.unless(JNZ, offset);
+                emitter.emit(&[0x99, 0xF7, 0xF9]); // cdq; idiv ecx
+                emitter.store_eax(c);
+                emitter.emit(&[0x41, 0x89, 0x10]); // mov [r8], edx
+            }
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTQ | Opcode::LTQ => {
+                let setcc = match instr.opcode {
+                    Opcode::EQ => 0x94,
+                    Opcode::NEQ => 0x95,
+                    Opcode::GT => 0x9F,
+                    Opcode::LT => 0x9C,
+                    Opcode::GTQ => 0x9D,
+                    _ => 0x9E,
+                };
+                emitter.load_eax(a);
+                emitter.load_ecx(b);
+                // cmp eax, ecx; setcc al; mov [rsi], al
+                emitter.emit(&[0x39, 0xC8, 0x0F, setcc, 0xC0, 0x88, 0x06]);
+            }
+            Opcode::LUI => {
+                emitter.load_eax(a);
+                emitter.emit(&[0x25, 0xFF, 0xFF, 0x00, 0x00]); // and eax, 0xFFFF
+                emitter.emit(&[0x0D]); // or eax, immediate << 16
+                emitter.emit_u32(u32::from(instr.immediate) << 16);
+                emitter.store_eax(a);
+            }
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => {
+                emitter.load_eax(a);
+                emitter.emit(match instr.opcode {
+                    Opcode::ADDI => &[0x05], // add eax, immediate
+                    Opcode::SUBI => &[0x2D], // sub eax, immediate
+                    _ => &[0x69, 0xC0],      // imul eax, eax, immediate
+                });
+                emitter.emit_u32(instr.signed_immediate() as u32);
+                emitter.store_eax(b);
+            }
+            Opcode::CMPI => {
+                emitter.load_eax(a);
+                emitter.emit(&[0x3D]); // cmp eax, immediate
+                emitter.emit_u32(instr.signed_immediate() as u32);
+                // sete al; mov [rsi], al
+                emitter.emit(&[0x0F, 0x94, 0xC0, 0x88, 0x06]);
+            }
+            Opcode::BEQ
+            | Opcode::BNEQ
+            | Opcode::BGT
+            | Opcode::BLT
+            | Opcode::BGTQ
+            | Opcode::BLTQ => {
+                // The short jcc that skips the jump when the comparison
+                // doesn't hold.
+                let skip_unless = match instr.opcode {
+                    Opcode::BEQ => 0x75,
+                    Opcode::BNEQ => 0x74,
+                    Opcode::BGT => 0x7E,
+                    Opcode::BLT => 0x7D,
+                    Opcode::BGTQ => 0x7C,
+                    _ => 0x7F,
+                };
+                emitter.load_eax(a);
+                emitter.load_ecx(b);
+                emitter.emit(&[0x39, 0xC8, skip_unless, 0]); // cmp eax, ecx
+                let skip = emitter.code.len();
+                emitter.emit(&[0xB8]); // mov eax, target
+                emitter.emit_u32(u32::from(instr.immediate));
+                emitter.jump_to_rax(offset, start);
+                emitter.code[skip - 1] = (emitter.code.len() - skip) as u8;
+                emitter.continue_at(next);
+                return Some(emitter.code);
+            }
+            Opcode::NOP => {}
+            Opcode::JMP => {
+                emitter.emit(&[0x48, 0x63, 0x47, a * 4]); // movsxd rax, [a]
+                emitter.jump_to_rax(offset, start);
+                return Some(emitter.code);
+            }
+            Opcode::JMPF => {
+                emitter.emit(&[0x48, 0x63, 0x47, a * 4]); // movsxd rax, [a]
+                emitter.emit(&[0x48, 0x05]); // add rax, next
+                emitter.emit_u32(next as u32);
+                emitter.jump_to_rax(offset, start);
+                return Some(emitter.code);
+            }
+            Opcode::JMPB => {
+                emitter.emit(&[0x48, 0x63, 0x4F, a * 4]); // movsxd rcx, [a]
+                emitter.emit(&[0xB8]); // mov eax, next
+                emitter.emit_u32(next as u32);
+                emitter.emit(&[0x48, 0x29, 0xC8]); // sub rax, rcx
+                emitter.jump_to_rax(offset, start);
+                return Some(emitter.code);
+            }
+            Opcode::JMPC => {
+                // cmp byte [rsi], 0; je over the jump
+                emitter.emit(&[0x80, 0x3E, 0x00, 0x74, 0]);
+                let skip = emitter.code.len();
+                emitter.emit(&[0x48, 0x63, 0x47, a * 4]); // movsxd rax, [a]
+                emitter.jump_to_rax(offset, start);
+                emitter.code[skip - 1] = (emitter.code.len() - skip) as u8;
+                emitter.continue_at(next);
+                return Some(emitter.code);
+            }
+            // Halting prints a message, so it is left to the interpreter,
+            // along with anything that faults. Custom instructions don't
+            // decode here, so they are interpreted as well, and so are the
+            // instructions for threads and interrupts.
+            Opcode::HLT
+            | Opcode::IGL
+            | Opcode::CUSTOM(_)
+            | Opcode::SPAWN
+            | Opcode::YIELD
+            | Opcode::SEND
+            | Opcode::RECV
+            | Opcode::EI
+            | Opcode::DI
+            | Opcode::IRET
+            | Opcode::SETVEC
+            | Opcode::TIMER => break,
+        }
+        offset = next;
+    }
+    if offset == start {
+        return None;
+    }
+    if offset < program.len() {
+        emitter.interpret(offset);
+    } else {
+        emitter.continue_at(offset);
+    }
+    Some(emitter.code)
+}
+
+/// Compiles code to x86-64 once the interpreter has reached it often
+/// enough. Each compiled block runs a straight line of code and returns to
+/// the VM at the first jump.
+pub struct Jit {
+    threshold: u32,
+    encoding: Encoding,
+    // One for each byte of the program.
+    slots: Vec<Slot>,
+}
+
+enum Slot {
+    // Reached this many times by the interpreter.
+    Cold(u32),
+    Compiled(Block),
+    // Starts with an instruction the JIT can't compile.
+    Interpreted,
+}
+
+impl Jit {
+    pub fn new(threshold: u32, encoding: Encoding) -> Jit {
+        Jit {
+            threshold,
+            encoding,
+            slots: vec![],
+        }
+    }
+
+    /// Runs the compiled block at `pc`, compiling it first if the code there
+    /// has become hot. Returns `None` if the interpreter should run the
+    /// instruction at `pc` instead.
+    pub fn enter(
+        &mut self,
+        program: &[u8],
+        pc: usize,
+        registers: &mut [i32; REGISTER_COUNT],
+        conditional: &mut bool,
+        remainder: &mut u32,
+    ) -> Option<Exit> {
+        if self.slots.is_empty() {
+            self.slots = (0..program.len()).map(|_| Slot::Cold(0)).collect();
+        }
+        if let Slot::Cold(count) = self.slots[pc] {
+            if count + 1 < self.threshold {
+                self.slots[pc] = Slot::Cold(count + 1);
+                return None;
+            }
+            self.slots[pc] =
+                match compile(program, pc, self.encoding).and_then(|code| Block::new(&code)) {
+                    Some(block) => Slot::Compiled(block),
+                    None => Slot::Interpreted,
+                };
+        }
+        match &self.slots[pc] {
+            Slot::Compiled(block) => Some(block.call(registers, conditional, remainder)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::{testing, Engine, Fault, VmError, VM};
+
+    // Compiles every block the first time it is reached.
+    fn differential(program: Vec<u8>) -> Result<(), Fault> {
+        testing::differential(program, Engine::Jit, |vm| {
+            vm.jit = Some(Jit::new(1, vm.encoding))
+        })
+    }
+
+    fn assemble(source: &str) -> Vec<u8> {
+        Assembler::new().assemble(source).unwrap()
+    }
+
+    #[test]
+    fn test_compiled_fib() {
+        let program = assemble(
+            "\tclr $0\n\tli $1 #1\n\tclr $4\n\tli $6 #20\n\
+             loop:\n\tmov $1 $2\n\tadd $0 $1 $1\n\tmov $2 $0\n\tinc $4\n\
+             \tlt $4 $6\n\tjmpc @loop\n\thlt\n",
+        );
+        let mut vm = VM::new();
+        vm.load(program.clone(), None).unwrap();
+        vm.set_engine(Engine::Jit);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], 10946);
+        differential(program).unwrap();
+    }
+
+    #[test]
+    fn test_compiled_arithmetic() {
+        differential(assemble(
+            "\tli $0 #-70000\n\tli $1 #7\n\tmul $0 $1 $2\n\tsub $2 $1 $3\n\
+             \tdiv $3 $1 $4\n\tload $5 #65535\n\tlui $5 #32768\n\tdiv $5 $1 $6\n\
+             \tli $7 #-1\n\tdiv $5 $7 $8\n\tdiv $0 $7 $9\n\
+             \teq $0 $0\n\tneq $0 $1\n\tgt $1 $0\n\tlt $0 $1\n\tgtq $1 $1\n\tltq $1 $0\n",
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_compiled_jumps() {
+        // Counts $0 down to zero with relative jumps, then jumps into the
+        // middle of the first load, which ends in a HLT.
+        differential(vec![
+            1, 1, 0, 0, //  LOAD $1 #0
+            1, 0, 0, 5, //  LOAD $0 #5
+            1, 2, 0, 1, //  LOAD $2 #1
+            1, 6, 0, 3, //  LOAD $6 #3
+            3, 0, 2, 0, //  SUB  $0 $2 $0
+            1, 5, 0, 1, //  LOAD $5 #1
+            10, 0, 1, //    EQ   $0 $1
+            9, 6, //        JMPC $6
+            8, 5,   //        JMPF $5, over the illegal byte
+            200, //
+            1, 4, 0, 22, // LOAD $4 #22
+            7, 4, //        JMPB $4, back to the SUB
+        ])
+        .unwrap();
+    }
+
+    #[test]
+    fn test_compiled_branches() {
+        // Sums 1 to 100 in a loop closed by a branch back to its own start,
+        // then takes each kind of branch once.
+        differential(assemble(
+            "\tload $1 #100\nloop:\taddi $0 #1 $0\n\tadd $2 $0 $2\n\tblt $0 $1 @loop\n\
+             \tmuli $2 #-3 $3\n\tsubi $3 #7 $3\n\tcmpi $3 #-15157\n\
+             \tbeq $0 $1 @a\n\thlt\na:\tbneq $0 $2 @b\n\thlt\nb:\tbgt $2 $0 @c\n\thlt\n\
+             c:\tbgtq $0 $0 @d\n\thlt\nd:\tbltq $3 $0 @e\n\thlt\ne:\tblt $0 $0 @a\n\tload $4 #1\n",
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_compiled_words() {
+        let program = Assembler::new()
+            .encoding(Encoding::Fixed)
+            .assemble(
+                "\tli $1 #100000\nloop:\taddi $0 #-3 $0\n\tsubi $1 #1 $1\n\
+                 \tbneq $1 $2 @loop\n\tload $3 #6\n\tjmp $3\n",
+            )
+            .unwrap();
+        assert_eq!(
+            differential(program).map_err(|fault| fault.error),
+            Err(VmError::UnalignedInstruction)
+        );
+    }
+
+    #[test]
+    fn test_compiled_faults() {
+        let faults = vec![
+            vec![1, 0, 0, 4, 1, 1, 0, 0, 5, 0, 1, 2],
+            vec![1, 0, 0, 8, 7, 0],
+            vec![1, 0, 0, 1, 2, 0, 0, 0, 200],
+            vec![1, 0, 0, 1, 2, 0, 40, 0],
+            vec![1, 0, 0, 1, 1, 0],
+        ];
+        for program in faults {
+            assert!(differential(program).is_err());
+        }
+    }
+
+    #[test]
+    fn test_random_programs() {
+        // Straight-line code with a backwards jump at the end that runs it
+        // ten times, so every block gets compiled.
+        let mut seed: u32 = 12345;
+        let mut random = move |limit: u32| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) % limit
+        };
+        for _ in 0..200 {
+            let mut program = vec![1, 30, 0, 10, 1, 29, 0, 1, 1, 28, 0, 12];
+            for _ in 0..20 {
+                // Loads, arithmetic and comparisons on $0 to $7.
+                let opcodes = [
+                    1, 1, 1, 16, 2, 3, 4, 5, 10, 11, 12, 13, 14, 15, 17, 18, 19, 20,
+                ];
+                let opcode = opcodes[random(opcodes.len() as u32) as usize];
+                program.push(opcode);
+                program.push(random(8) as u8);
+                match opcode {
+                    1 | 16 | 20 => {
+                        program.extend_from_slice(&[random(256) as u8, random(256) as u8])
+                    }
+                    17..=19 => program.extend_from_slice(&[
+                        random(256) as u8,
+                        random(256) as u8,
+                        random(8) as u8,
+                    ]),
+                    2..=5 => program.extend_from_slice(&[random(8) as u8, random(8) as u8]),
+                    _ => program.push(random(8) as u8),
+                }
+            }
+            // Counts $30 down and jumps back to the start of the random code.
+            program.extend_from_slice(&[3, 30, 29, 30, 12, 30, 31, 9, 28]);
+            let _ = differential(program);
+        }
+    }
+}
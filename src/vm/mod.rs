@@ -0,0 +1,1341 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::debug_info::{DebugEntry, DebugInfo};
+use crate::disassembler::{decode_at_with, Disassembler};
+use crate::instruction::{Encoding, Opcode, OperandKind};
+use crate::verifier::{verify_with, Problem};
+
+pub mod closures;
+pub mod custom;
+pub mod interrupts;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+pub mod predecode;
+pub mod scheduler;
+#[cfg(test)]
+mod testing;
+
+use self::closures::Closures;
+use self::custom::{CustomInstructionError, CustomInstructions, Handler, VmState};
+use self::interrupts::Interrupts;
+use self::predecode::{decode_instr_with, DecodedInstr, Fused, Predecoded};
+
+pub const REGISTER_COUNT: usize = 32;
+
+/// Whether this build can compile hot code to native code. That takes the
+/// `jit` feature, on x86-64 Linux.
+pub const JIT_SUPPORTED: bool = cfg!(all(
+    feature = "jit",
+    target_arch = "x86_64",
+    target_os = "linux"
+));
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    IllegalOpcode { opcode: u8 },
+    UnexpectedEndOfProgram,
+    InvalidRegister { register: u8 },
+    DivisionByZero,
+    InvalidJump { target: i64 },
+    UnalignedInstruction,
+    NoScheduler,
+    NoSuchThread { id: i32 },
+    NoSuchInterrupt { interrupt: i32 },
+    NotInInterrupt,
+    Custom { message: String },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::IllegalOpcode { opcode } => write!(f, "illegal opcode {}", opcode),
+            VmError::UnexpectedEndOfProgram => {
+                write!(f, "program ends in the middle of an instruction")
+            }
+            VmError::InvalidRegister { register } => write!(f, "invalid register ${}", register),
+            VmError::DivisionByZero => write!(f, "division by zero"),
+            VmError::InvalidJump { target } => write!(f, "jump to invalid address {}", target),
+            VmError::UnalignedInstruction => {
+                write!(f, "instruction does not start on a word boundary")
+            }
+            VmError::NoScheduler => write!(f, "no scheduler to run threads"),
+            VmError::NoSuchThread { id } => write!(f, "there is no thread {}", id),
+            VmError::NoSuchInterrupt { interrupt } => {
+                write!(f, "there is no interrupt {}", interrupt)
+            }
+            VmError::NotInInterrupt => write!(f, "iret outside of an interrupt handler"),
+            VmError::Custom { message } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// An error raised by the instruction at `pc`, along with the source line it
+/// was assembled from if the program was loaded with debug info.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub pc: usize,
+    pub error: VmError,
+    pub source: Option<DebugEntry>,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.source {
+            Some(entry) => write!(
+                f,
+                "{}: {} (byte {})\n    {}",
+                entry, self.error, self.pc, entry.text
+            ),
+            None => write!(f, "byte {}: {}", self.pc, self.error),
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
+
+/// The instruction about to be executed, as passed to the tracer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry<'a> {
+    pub pc: usize,
+    pub instruction: String,
+    pub source: Option<&'a DebugEntry>,
+}
+
+impl<'a> fmt::Display for TraceEntry<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:5}  {:<16}", self.pc, self.instruction)?;
+        if let Some(entry) = self.source {
+            write!(f, "  {}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+type Tracer = Box<dyn FnMut(&TraceEntry)>;
+
+/// Whether programs have to pass the verifier before they can be loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadMode {
+    /// Programs are loaded as they are. Problems show up as faults once the
+    /// offending instruction runs.
+    Unchecked,
+    /// Programs the verifier rejects are refused.
+    Verified,
+}
+
+/// How `run` executes a program. `step`, and `run` while there is a
+/// tracer, always use the interpreter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    /// Dispatches on each instruction in turn.
+    Interpreter,
+    /// Compiles each basic block, as it is first reached, into closures
+    /// with their operands bound.
+    Closures,
+    /// Compiles hot code to native code, and interprets the rest. Without
+    /// `JIT_SUPPORTED` this is the same as `Interpreter`.
+    Jit,
+    /// Decodes each instruction from the bytes every time it runs, as the
+    /// interpreter did before programs were decoded up front. Only useful
+    /// as a baseline for benchmarks.
+    Decoding,
+}
+
+pub struct VM {
+    registers: [i32; REGISTER_COUNT],
+    pc: usize,
+    // The code, without the header it may have been loaded with.
+    program: Vec<u8>,
+    encoding: Encoding,
+    // Decoded the first time the program runs.
+    code: Option<Predecoded>,
+    remainder: u32,
+    conditional: bool,
+    debug_info: Option<DebugInfo>,
+    tracer: Option<Tracer>,
+    load_mode: LoadMode,
+    engine: Engine,
+    // Compiled the first time the program runs with the matching engine.
+    closures: Option<Closures>,
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    jit: Option<jit::Jit>,
+    custom: Rc<CustomInstructions>,
+    // Whether the VM is a thread of a scheduler, which is what makes the
+    // instructions for threads do anything.
+    scheduled: bool,
+    // Set by an instruction that hands control back to the scheduler.
+    pause: Option<Pause>,
+    interrupts: Interrupts,
+}
+
+// Why a VM stopped running for its scheduler.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pause {
+    Halted,
+    OutOfTime,
+    Yielded,
+    Spawned { register: usize, target: usize },
+    // `pc` is where the `send` is, for a fault if there is no such thread.
+    Sent { pc: usize, to: i32, value: i32 },
+    Receiving { register: usize },
+}
+
+/// Sets up a VM with instructions of the embedder's own.
+pub struct VmBuilder {
+    custom: Result<CustomInstructions, CustomInstructionError>,
+}
+
+impl VmBuilder {
+    /// Adds an instruction that runs `handler`. Its opcode is the lowest
+    /// free one in `CUSTOM_OPCODES`.
+    pub fn custom_instruction(
+        mut self,
+        mnemonic: &str,
+        operands: &[OperandKind],
+        handler: Handler,
+    ) -> VmBuilder {
+        if let Ok(custom) = self.custom.as_mut() {
+            if let Err(error) = custom.register(mnemonic, operands, handler) {
+                self.custom = Err(error);
+            }
+        }
+        self
+    }
+
+    /// The VM, or the first instruction that couldn't be registered.
+    pub fn build(self) -> Result<VM, CustomInstructionError> {
+        let mut vm = VM::new();
+        vm.custom = Rc::new(self.custom?);
+        Ok(vm)
+    }
+}
+
+impl VM {
+    pub fn builder() -> VmBuilder {
+        VmBuilder {
+            custom: Ok(CustomInstructions::new()),
+        }
+    }
+
+    pub fn new() -> VM {
+        VM {
+            registers: [0; REGISTER_COUNT],
+            pc: 0,
+            program: vec![],
+            encoding: Encoding::Variable,
+            code: None,
+            remainder: 0,
+            conditional: false,
+            debug_info: None,
+            tracer: None,
+            load_mode: LoadMode::Unchecked,
+            engine: Engine::Interpreter,
+            closures: None,
+            #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+            jit: None,
+            custom: Rc::new(CustomInstructions::new()),
+            scheduled: false,
+            pause: None,
+            interrupts: Interrupts::default(),
+        }
+    }
+
+    /// The instructions added with the builder, to hand to the assembler
+    /// and disassembler.
+    pub fn custom_instructions(&self) -> Rc<CustomInstructions> {
+        Rc::clone(&self.custom)
+    }
+
+    pub fn set_load_mode(&mut self, load_mode: LoadMode) {
+        self.load_mode = load_mode;
+    }
+
+    /// Replaces the program and resets the machine. The program is run in
+    /// the encoding its header names, or the variable-length one if it has
+    /// no header. With debug info, faults and traces refer to source lines.
+    /// In `LoadMode::Verified` a program the verifier rejects is not loaded,
+    /// and the previous one is kept.
+    pub fn load(
+        &mut self,
+        program: Vec<u8>,
+        debug_info: Option<DebugInfo>,
+    ) -> Result<(), Vec<Problem>> {
+        let (encoding, code) = Encoding::detect(&program);
+        if self.load_mode == LoadMode::Verified {
+            verify_with(code, encoding, &self.custom)?;
+        }
+        self.registers = [0; REGISTER_COUNT];
+        self.pc = 0;
+        self.program = code.to_vec();
+        self.encoding = encoding;
+        self.code = None;
+        self.remainder = 0;
+        self.conditional = false;
+        self.debug_info = debug_info;
+        self.interrupts = Interrupts::default();
+        self.closures = None;
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        {
+            self.jit = None;
+        }
+        return Ok(());
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    /// Calls `tracer` before every instruction is executed.
+    pub fn set_tracer<F: FnMut(&TraceEntry) + 'static>(&mut self, tracer: F) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    pub fn clear_tracer(&mut self) {
+        self.tracer = None;
+    }
+
+    pub fn registers(&self) -> &[i32; REGISTER_COUNT] {
+        &self.registers
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn remainder(&self) -> u32 {
+        self.remainder
+    }
+
+    pub fn conditional(&self) -> bool {
+        self.conditional
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts.enabled()
+    }
+
+    /// Raises `interrupt`, as a device would. It is taken before the next
+    /// instruction once interrupts are enabled. Returns false if there is
+    /// no such interrupt.
+    pub fn raise_interrupt(&mut self, interrupt: usize) -> bool {
+        self.interrupts.raise(interrupt)
+    }
+
+    /// The source of the instruction at `pc`, if debug info is loaded.
+    pub fn source(&self) -> Option<&DebugEntry> {
+        self.debug_info
+            .as_ref()
+            .and_then(|info| info.lookup(self.pc))
+    }
+
+    fn jump(&mut self, target: i64) -> Result<(), VmError> {
+        if target < 0 {
+            return Err(VmError::InvalidJump { target });
+        }
+        self.pc = target as usize;
+        return Ok(());
+    }
+
+    /// Executes one instruction. Returns `Ok(false)` once the program has
+    /// halted or run off its end.
+    pub fn step(&mut self) -> Result<bool, Fault> {
+        self.execute_instruction()
+    }
+
+    pub fn execute_instruction(&mut self) -> Result<bool, Fault> {
+        if self.pc >= self.program.len() {
+            return Ok(false);
+        }
+        if let Some(handler) = self.interrupts.take(self.pc, self.conditional) {
+            self.pc = handler;
+            if self.pc >= self.program.len() {
+                return Ok(false);
+            }
+        }
+
+        let pc = self.pc;
+        if let Some(tracer) = self.tracer.as_mut() {
+            let decoded = decode_at_with(&self.program, pc, self.encoding, &self.custom).decoded;
+            let disassembler = Disassembler::new().custom_instructions(Rc::clone(&self.custom));
+            tracer(&TraceEntry {
+                pc,
+                instruction: disassembler.text(&decoded),
+                source: self.debug_info.as_ref().and_then(|info| info.lookup(pc)),
+            });
+        }
+        if self.code.is_none() {
+            self.code = Some(Predecoded::new(
+                &self.program,
+                self.encoding,
+                Rc::clone(&self.custom),
+            ));
+        }
+        let instr = match self.code.as_mut() {
+            Some(code) => code.at(&self.program, pc),
+            None => unreachable!(),
+        };
+        let result = self.execute(pc, instr);
+        self.interrupts.tick();
+        result.map_err(|error| self.fault(pc, error))
+    }
+
+    fn fault(&self, pc: usize, error: VmError) -> Fault {
+        Fault {
+            pc,
+            error,
+            source: self
+                .debug_info
+                .as_ref()
+                .and_then(|info| info.lookup(pc))
+                .cloned(),
+        }
+    }
+
+    fn execute(&mut self, pc: usize, instr: DecodedInstr) -> Result<bool, VmError> {
+        self.pc = instr.next;
+        let [a, b, c] = instr.operands;
+        // Registers were checked when decoding. Taking them modulo the
+        // count tells the compiler so, which saves a bounds check.
+        let register = |register: u8| usize::from(register) % REGISTER_COUNT;
+        match instr.opcode {
+            Opcode::HLT => {
+                println!("HLT encountered");
+                return Ok(false);
+            }
+            Opcode::LOAD => {
+                self.registers[register(a)] = i32::from(instr.immediate);
+            }
+            Opcode::ADD => {
+                self.registers[register(c)] =
+                    self.registers[register(a)].wrapping_add(self.registers[register(b)]);
+            }
+            Opcode::SUB => {
+                self.registers[register(c)] =
+                    self.registers[register(a)].wrapping_sub(self.registers[register(b)]);
+            }
+            Opcode::MUL => {
+                self.registers[register(c)] =
+                    self.registers[register(a)].wrapping_mul(self.registers[register(b)]);
+            }
+            Opcode::DIV => {
+                let register1 = self.registers[register(a)];
+                let register2 = self.registers[register(b)];
+                if register2 == 0 {
+                    return Err(VmError::DivisionByZero);
+                }
+                self.registers[register(c)] = register1.wrapping_div(register2);
+                self.remainder = register1.wrapping_rem(register2) as u32;
+            }
+            Opcode::JMP => {
+                self.jump(i64::from(self.registers[register(a)]))?;
+            }
+            Opcode::JMPB => {
+                self.jump(self.pc as i64 - i64::from(self.registers[register(a)]))?;
+            }
+            Opcode::JMPF => {
+                self.jump(self.pc as i64 + i64::from(self.registers[register(a)]))?;
+            }
+            Opcode::JMPC => {
+                if self.conditional {
+                    self.jump(i64::from(self.registers[register(a)]))?;
+                }
+            }
+            Opcode::EQ => {
+                self.conditional = self.registers[register(a)] == self.registers[register(b)];
+            }
+            Opcode::NEQ => {
+                self.conditional = self.registers[register(a)] != self.registers[register(b)];
+            }
+            Opcode::GT => {
+                self.conditional = self.registers[register(a)] > self.registers[register(b)];
+            }
+            Opcode::LT => {
+                self.conditional = self.registers[register(a)] < self.registers[register(b)];
+            }
+            Opcode::GTQ => {
+                self.conditional = self.registers[register(a)] >= self.registers[register(b)];
+            }
+            Opcode::LTQ => {
+                self.conditional = self.registers[register(a)] <= self.registers[register(b)];
+            }
+            Opcode::LUI => {
+                let upper = u32::from(instr.immediate) << 16;
+                let lower = self.registers[register(a)] as u32 & 0xFFFF;
+                self.registers[register(a)] = (upper | lower) as i32;
+            }
+            Opcode::ADDI => {
+                self.registers[register(b)] =
+                    self.registers[register(a)].wrapping_add(instr.signed_immediate());
+            }
+            Opcode::SUBI => {
+                self.registers[register(b)] =
+                    self.registers[register(a)].wrapping_sub(instr.signed_immediate());
+            }
+            Opcode::MULI => {
+                self.registers[register(b)] =
+                    self.registers[register(a)].wrapping_mul(instr.signed_immediate());
+            }
+            Opcode::CMPI => {
+                self.conditional = self.registers[register(a)] == instr.signed_immediate();
+            }
+            Opcode::BEQ
+            | Opcode::BNEQ
+            | Opcode::BGT
+            | Opcode::BLT
+            | Opcode::BGTQ
+            | Opcode::BLTQ => {
+                let (left, right) = (self.registers[register(a)], self.registers[register(b)]);
+                if instr.opcode.compare(left, right) {
+                    self.pc = usize::from(instr.immediate);
+                }
+            }
+            Opcode::NOP => {
+                // No code on a no-op
+                // ;)))
+            }
+            Opcode::CUSTOM(byte) => {
+                let instruction = match self.custom.get(byte) {
+                    Some(instruction) => instruction,
+                    None => unreachable!(),
+                };
+                let mut state = VmState {
+                    registers: &mut self.registers,
+                    remainder: &mut self.remainder,
+                    conditional: &mut self.conditional,
+                };
+                instruction.run(&mut state, &instr)?;
+            }
+            Opcode::SPAWN => {
+                if !self.scheduled {
+                    return Err(VmError::NoScheduler);
+                }
+                self.pause = Some(Pause::Spawned {
+                    register: register(a),
+                    target: usize::from(instr.immediate),
+                });
+            }
+            Opcode::YIELD => {
+                if self.scheduled {
+                    self.pause = Some(Pause::Yielded);
+                }
+            }
+            Opcode::SEND => {
+                if !self.scheduled {
+                    return Err(VmError::NoScheduler);
+                }
+                self.pause = Some(Pause::Sent {
+                    pc,
+                    to: self.registers[register(a)],
+                    value: self.registers[register(b)],
+                });
+            }
+            Opcode::RECV => {
+                if !self.scheduled {
+                    return Err(VmError::NoScheduler);
+                }
+                self.pause = Some(Pause::Receiving {
+                    register: register(a),
+                });
+            }
+            Opcode::EI => self.interrupts.set_enabled(true),
+            Opcode::DI => self.interrupts.set_enabled(false),
+            Opcode::IRET => {
+                let (pc, conditional) = self.interrupts.ret()?;
+                self.pc = pc;
+                self.conditional = conditional;
+            }
+            Opcode::SETVEC => self
+                .interrupts
+                .set_vector(self.registers[register(a)], usize::from(instr.immediate))?,
+            Opcode::TIMER => self.interrupts.set_timer(self.registers[register(a)]),
+            Opcode::IGL => {
+                // Faults are rare, so they are worked out again from the
+                // bytes rather than stored.
+                let (error, next) =
+                    match decode_instr_with(&self.program, pc, self.encoding, &self.custom) {
+                        Err(fault) => fault,
+                        Ok(_) => unreachable!(),
+                    };
+                self.pc = next;
+                return Err(error);
+            }
+        }
+        return Ok(true);
+    }
+
+    // Executes a superinstruction made of `instrs`, leaving the machine as
+    // running them one by one would. A fault comes with the offset of the
+    // instruction that raised it.
+    fn execute_fused(
+        &mut self,
+        fused: Fused,
+        instrs: &[DecodedInstr],
+    ) -> Result<(), (usize, VmError)> {
+        let register = |register: u8| usize::from(register) % REGISTER_COUNT;
+        let first = instrs[0];
+        let last = instrs[instrs.len() - 1];
+        self.pc = last.next;
+        match fused {
+            Fused::CompareJump | Fused::CompareLoadJump => {
+                let [a, b, _] = first.operands;
+                let (left, right) = (self.registers[register(a)], self.registers[register(b)]);
+                self.conditional = first.opcode.compare(left, right);
+                if fused == Fused::CompareLoadJump {
+                    self.registers[register(instrs[1].operands[0])] =
+                        i32::from(instrs[1].immediate);
+                }
+                if self.conditional {
+                    let target = i64::from(self.registers[register(last.operands[0])]);
+                    let jump_pc = instrs[instrs.len() - 2].next;
+                    self.jump(target).map_err(|error| (jump_pc, error))?;
+                }
+            }
+            Fused::LoadArithmetic => {
+                self.registers[register(first.operands[0])] = i32::from(first.immediate);
+                let [a, b, c] = last.operands;
+                let (left, right) = (self.registers[register(a)], self.registers[register(b)]);
+                self.registers[register(c)] = match last.opcode {
+                    Opcode::ADD => left.wrapping_add(right),
+                    Opcode::SUB => left.wrapping_sub(right),
+                    _ => left.wrapping_mul(right),
+                };
+            }
+            Fused::LoadJump => {
+                self.registers[register(first.operands[0])] = i32::from(first.immediate);
+                let target = i64::from(self.registers[register(last.operands[0])]);
+                self.jump(target).map_err(|error| (first.next, error))?;
+            }
+            Fused::Single => unreachable!(),
+        }
+        return Ok(());
+    }
+
+    /// Runs until the program halts, runs off its end or faults.
+    pub fn run(&mut self) -> Result<(), Fault> {
+        if self.tracer.is_none() && !self.interrupts.active() {
+            let stopped = match self.engine {
+                Engine::Closures => {
+                    let mut closures = self.closures.take().unwrap_or_else(Closures::new);
+                    let result = closures.run(self);
+                    self.closures = Some(closures);
+                    result
+                }
+                #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+                Engine::Jit => self.run_jit(),
+                Engine::Decoding => self.run_undecoded(),
+                _ => self.run_decoded(),
+            }?;
+            if stopped {
+                return Ok(());
+            }
+        }
+        // The tracer sees every instruction, and interrupts are only taken
+        // between instructions executed one at a time.
+        while self.execute_instruction()? {}
+        return Ok(());
+    }
+
+    // Runs straight through the decoded program, and only looks addresses
+    // up after a jump. Returns whether the program stopped, rather than
+    // turning on interrupts.
+    fn run_decoded(&mut self) -> Result<bool, Fault> {
+        if self.pc >= self.program.len() {
+            return Ok(true);
+        }
+        let mut code = match self.code.take() {
+            Some(code) => code,
+            None => Predecoded::new(&self.program, self.encoding, Rc::clone(&self.custom)),
+        };
+        let mut index = code.index_of(&self.program, self.pc);
+        let result = loop {
+            let pc = self.pc;
+            let fused = code.fused[index];
+            if fused != Fused::Single {
+                let last = index + fused.length() - 1;
+                match self.execute_fused(fused, &code.instructions[index..=last]) {
+                    Ok(()) if self.pc < self.program.len() => {
+                        index = code.index_after(&self.program, last, self.pc);
+                        continue;
+                    }
+                    Ok(()) => break Ok(true),
+                    Err((pc, error)) => break Err(self.fault(pc, error)),
+                }
+            }
+            match self.execute(pc, code.instructions[index]) {
+                Ok(true) if self.pc < self.program.len() && !self.interrupts.active() => {
+                    index = code.index_after(&self.program, index, self.pc);
+                }
+                Ok(true) if self.pc < self.program.len() => break Ok(false),
+                Ok(_) => break Ok(true),
+                Err(error) => break Err(self.fault(pc, error)),
+            }
+        };
+        self.code = Some(code);
+        return result;
+    }
+
+    // Decodes each instruction as it is reached and keeps nothing. Returns
+    // whether the program stopped, as above.
+    fn run_undecoded(&mut self) -> Result<bool, Fault> {
+        while self.pc < self.program.len() {
+            let pc = self.pc;
+            let instr = match decode_instr_with(&self.program, pc, self.encoding, &self.custom) {
+                Ok(instr) => instr,
+                Err((error, next)) => {
+                    self.pc = next;
+                    return Err(self.fault(pc, error));
+                }
+            };
+            match self.execute(pc, instr) {
+                Ok(true) if self.interrupts.active() => return Ok(false),
+                Ok(true) => {}
+                Ok(false) => return Ok(true),
+                Err(error) => return Err(self.fault(pc, error)),
+            }
+        }
+        Ok(true)
+    }
+
+    // Runs at most `limit` instructions, stopping early at one that hands
+    // control back to the scheduler. Also returns how many were run.
+    fn run_for(&mut self, limit: usize) -> Result<(Pause, usize), Fault> {
+        for count in 1..=limit {
+            if !self.execute_instruction()? {
+                return Ok((Pause::Halted, count));
+            }
+            if let Some(pause) = self.pause.take() {
+                return Ok((pause, count));
+            }
+        }
+        Ok((Pause::OutOfTime, limit))
+    }
+
+    // Runs compiled blocks where there are any, and the interpreter
+    // everywhere else. Returns whether the program stopped, as above.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    fn run_jit(&mut self) -> Result<bool, Fault> {
+        let mut jit = self
+            .jit
+            .take()
+            .unwrap_or_else(|| jit::Jit::new(jit::HOT_THRESHOLD, self.encoding));
+        let result = loop {
+            if self.pc >= self.program.len() {
+                break Ok(true);
+            }
+            let exit = jit.enter(
+                &self.program,
+                self.pc,
+                &mut self.registers,
+                &mut self.conditional,
+                &mut self.remainder,
+            );
+            match exit {
+                Some(jit::Exit::Continue(pc)) => {
+                    self.pc = pc;
+                    continue;
+                }
+                Some(jit::Exit::Interpret(pc)) => self.pc = pc,
+                None => {}
+            }
+            match self.execute_instruction() {
+                Ok(true) if self.interrupts.active() => break Ok(false),
+                Ok(true) => {}
+                Ok(false) => break Ok(true),
+                Err(fault) => break Err(fault),
+            }
+        };
+        self.jit = Some(jit);
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::assemble;
+    use crate::assembler::Assembler;
+    use crate::verifier::VerifyError;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_create_vm() {
+        let test_vm = VM::new();
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_opcode_hlt() {
+        let mut test_vm = VM::new();
+        let test_bytes = vec![0, 0, 0, 0];
+        test_vm.program = test_bytes;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.pc, 1);
+    }
+
+    #[test]
+    fn test_opcode_nop() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![255, 255, 255];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.pc, 3);
+    }
+
+    #[test]
+    fn test_opcode_igl() {
+        let mut test_vm = VM::new();
+        let test_bytes = vec![200, 0, 0, 0];
+        test_vm.program = test_bytes;
+        assert_eq!(
+            test_vm.run(),
+            Err(Fault {
+                pc: 0,
+                error: VmError::IllegalOpcode { opcode: 200 },
+                source: None,
+            })
+        );
+        assert_eq!(test_vm.pc, 1);
+    }
+
+    #[test]
+    fn test_opcode_load() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 1, 244];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 500);
+    }
+
+    #[test]
+    fn test_opcode_add() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            1, 0, 1, 244, // LOAD $0 #500
+            1, 1, 0, 10, //  LOAD $1 #10
+            2, 0, 1, 0, //   ADD  $0 $1 $0
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 510);
+    }
+
+    #[test]
+    fn test_opcode_sub() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            1, 0, 1, 244, // LOAD $0 #500
+            1, 1, 0, 10, //  LOAD $1 #10
+            3, 0, 1, 0, //   SUB  $0 $1 $0
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 490);
+    }
+
+    #[test]
+    fn test_opcode_mul() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            1, 0, 1, 244, // LOAD $0 #500
+            1, 1, 0, 10, //  LOAD $1 #10
+            4, 0, 1, 3, //   SUB  $0 $1 $0
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3], 5000);
+    }
+
+    #[test]
+    fn test_opcode_div() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            1, 0, 1, 244, // LOAD $0 #500
+            1, 1, 0, 6, //   LOAD $1 #6
+            5, 0, 1, 3, //   DIV  $0 $1 $0
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3], 83);
+        assert_eq!(test_vm.remainder, 2);
+    }
+
+    #[test]
+    fn test_opcode_jmp() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.program = vec![6, 0, 0, 0];
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.pc, 5);
+    }
+
+    #[test]
+    fn test_opcode_jmpb() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 6;
+        test_vm.pc = 1;
+        test_vm.program = vec![0, 255, 255, 255, 7, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.pc, 1);
+    }
+
+    #[test]
+    fn test_jump_into_instruction() {
+        let program = vec![
+            1, 1, 0, 0, // LOAD $1 #0, which ends in a HLT
+            1, 0, 0, 3, // LOAD $0 #3
+            6, 0, //       JMP  $0
+        ];
+        let mut test_vm = VM::new();
+        test_vm.load(program.clone(), None).unwrap();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.pc, 4);
+
+        test_vm.load(program, None).unwrap();
+        while test_vm.step().unwrap() {}
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_jmpf() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.program = vec![255, 8, 0];
+        test_vm.step().unwrap();
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_opcode_jmpc() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.program = vec![9, 0];
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.pc, 2);
+        test_vm.pc = 0;
+        test_vm.conditional = true;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.pc, 5);
+    }
+
+    #[test]
+    fn test_opcode_eq() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![10, 0, 1];
+        test_vm.registers[0] = 3;
+        test_vm.registers[1] = 3;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, true);
+        test_vm.pc = 0;
+        test_vm.registers[1] = 4;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, false);
+    }
+
+    #[test]
+    fn test_opcode_neq() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![11, 0, 1];
+        test_vm.registers[0] = 3;
+        test_vm.registers[1] = 3;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, false);
+        test_vm.pc = 0;
+        test_vm.registers[1] = 4;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, true);
+    }
+
+    #[test]
+    fn test_opcode_gt() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![12, 0, 1];
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 3;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, true);
+        test_vm.pc = 0;
+        test_vm.registers[1] = 7;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, false);
+    }
+
+    #[test]
+    fn test_opcode_lt() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![13, 0, 1];
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 3;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, false);
+        test_vm.pc = 0;
+        test_vm.registers[1] = 7;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, true);
+    }
+
+    #[test]
+    fn test_opcode_gtq() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![14, 0, 1];
+        test_vm.registers[0] = 3;
+        test_vm.registers[1] = 3;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, true);
+        test_vm.pc = 0;
+        test_vm.registers[1] = 2;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, true);
+        test_vm.pc = 0;
+        test_vm.registers[1] = 5;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, false);
+    }
+
+    #[test]
+    fn test_opcode_ltq() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![15, 0, 1];
+        test_vm.registers[0] = 3;
+        test_vm.registers[1] = 3;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, true);
+        test_vm.pc = 0;
+        test_vm.registers[1] = 5;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, true);
+        test_vm.pc = 0;
+        test_vm.registers[0] = 2;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.conditional, true);
+    }
+
+    #[test]
+    fn test_opcode_lui() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            1, 0, 0x11, 0x70, // LOAD $0 #0x1170
+            16, 0, 0, 1, //      LUI  $0 #1
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 70000);
+    }
+
+    #[test]
+    fn test_opcode_immediate_arithmetic() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            17, 0, 0, 100, 1, //    ADDI $0 #100 $1
+            18, 1, 0xFF, 0xFF, 2, // SUBI $1 #-1 $2
+            19, 2, 0xFF, 0xFE, 3, // MULI $2 #-2 $3
+            20, 3, 0xFF, 0x36, //    CMPI $3 #-202
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], 100);
+        assert_eq!(test_vm.registers[2], 101);
+        assert_eq!(test_vm.registers[3], -202);
+        assert_eq!(test_vm.conditional, true);
+    }
+
+    #[test]
+    fn test_opcode_branches() {
+        for (opcode, taken) in [
+            (21, false),
+            (22, true),
+            (23, false),
+            (24, true),
+            (25, false),
+            (26, true),
+        ] {
+            let mut test_vm = VM::new();
+            test_vm.registers[1] = 1;
+            // Bxx $0 $1 #100, without touching the flag.
+            test_vm.program = vec![opcode, 0, 1, 0, 100];
+            test_vm.step().unwrap();
+            assert_eq!(test_vm.pc, if taken { 100 } else { 5 });
+            assert_eq!(test_vm.conditional, false);
+        }
+    }
+
+    #[test]
+    fn test_assemble_negative_load() {
+        let mut test_vm = VM::new();
+        test_vm.program = assemble("load $0 #-5\nload $1 #'a'\nhlt".to_string()).unwrap();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], -5);
+        assert_eq!(test_vm.registers[1], 97);
+    }
+
+    #[test]
+    fn test_fib() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            1, 0, 0, 0, //   load $0 #0
+            1, 1, 0, 1, //   load $1 #1
+            1, 2, 0, 0, //   load $2 #0
+            1, 3, 0, 0, //   load $3 #0
+            1, 4, 0, 0, //   load $4 #0   <- fjöldi ítrana
+            1, 5, 0, 1, //   load $5 #1   <- tala til að incrementa með
+            1, 6, 0, 20, //  load $6 #10  <- hámarksfjöldi ítrana
+            2, 1, 3, 2, //   add $1 $3 $2 <- mov 1 => 2
+            2, 0, 1, 1, //   add $0 $1 $1
+            2, 2, 3, 0, //   add $2 $3 $0 <- mov 2 => 0
+            2, 4, 5, 4, //   add $4 $5 $4 <- inc 4
+            13, 4, 6, //     lt $4 $6
+            1, 7, 0, 28, //  load $7 $28
+            9, 7, //         jmpc $7
+            0, //            hlt
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], 10946);
+    }
+
+    #[test]
+    fn test_assembled_fib() {
+        let mut test_vm = VM::new();
+        test_vm.program = assemble(
+            "; fibonacci, mirroring test_fib\n\
+             \tload $0 #0\n\tload $1 #1\n\tload $2 #0\n\tload $3 #0\n\
+             \tload $4 #0     ; iterations so far\n\
+             \tload $5 #1     ; increment\n\
+             \tload $6 #20    ; iteration limit\n\
+             \n\
+             \tadd $1 $3 $2   ; byte 28: mov $1 => $2\n\
+             \tadd $0 $1 $1\n\
+             \tadd $2 $3 $0   // mov $2 => $0\n\
+             \tadd $4 $5 $4\n\
+             \tlt $4 $6\n\
+             \tload $7 #28\n\
+             \tjmpc $7\n\
+             \thlt\n"
+                .to_string(),
+        )
+        .unwrap();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], 10946);
+    }
+
+    #[test]
+    fn test_assembled_pseudo_fib() {
+        let mut test_vm = VM::new();
+        test_vm.program = assemble(
+            "\tclr $0\n\tli $1 #1\n\tclr $4\n\tli $6 #20\n\
+             loop:\n\
+             \tmov $1 $2\n\
+             \tadd $0 $1 $1\n\
+             \tmov $2 $0\n\
+             \tinc $4\n\
+             \tlt $4 $6\n\
+             \tjmpc @loop\n\
+             \thlt\n"
+                .to_string(),
+        )
+        .unwrap();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], 10946);
+    }
+
+    #[test]
+    fn test_assembled_macros() {
+        let mut test_vm = VM::new();
+        test_vm.program = assemble(
+            "; $31 is kept at zero and $30 at one\n\
+             .macro mov from, to\n\
+             \tadd \\from $31 \\to\n\
+             .endm\n\
+             .macro inc r\n\
+             \tadd \\r $30 \\r\n\
+             .endm\n\
+             .macro loop_while_lt a, b, target\n\
+             \tlt \\a \\b\n\
+             \tload $29 \\target\n\
+             \tjmpc $29\n\
+             .endm\n\
+             \n\
+             \tload $30 #1\n\
+             \tload $1 #1\n\
+             \tload $6 #20\n\
+             loop:\n\
+             \tmov $1, $2\n\
+             \tadd $0 $1 $1\n\
+             \tmov $2, $0\n\
+             \tinc $4\n\
+             \tloop_while_lt $4, $6, @loop\n\
+             \thlt\n"
+                .to_string(),
+        )
+        .unwrap();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], 10946);
+    }
+
+    #[test]
+    fn test_assembly_program() {
+        let mut test_vm = VM::new();
+        test_vm.program =
+            assemble("load $0 #100\nload $1 #50\nmul $0 $1 $0\nhlt".to_string()).unwrap();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 5000);
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut test_vm = VM::new();
+        let faults: Vec<(Vec<u8>, VmError)> = vec![
+            (vec![1, 0, 1], VmError::UnexpectedEndOfProgram),
+            (vec![2, 0, 32, 1], VmError::InvalidRegister { register: 32 }),
+            (vec![5, 0, 1, 2], VmError::DivisionByZero),
+            (vec![1, 0, 0, 8, 7, 0], VmError::InvalidJump { target: -2 }),
+        ];
+        for (program, error) in faults {
+            test_vm.load(program, None).unwrap();
+            assert_eq!(test_vm.run().map_err(|fault| fault.error), Err(error));
+        }
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        let programs = [
+            // A comparison, `load` and `jmpc`, and `load`s with arithmetic.
+            "\tclr $0\n\tli $1 #1\n\tclr $4\n\tli $6 #20\n\
+             loop:\n\tmov $1 $2\n\tadd $0 $1 $1\n\tmov $2 $0\n\tinc $4\n\
+             \tlt $4 $6\n\tjmpc @loop\n\thlt\n",
+            // A jump to the second half of a `load` and `add`.
+            "\tjmp @add\n\tload $1 #5\nadd: add $1 $1 $2\n\tmul $2 $2 $3\n\thlt\n",
+            // A comparison and `jmpc` that faults.
+            "\tli $0 #-1\n\teq $1 $1\n\tjmpc $0\n",
+        ];
+        for source in programs.iter() {
+            let program = Assembler::new().assemble(source).unwrap();
+            let mut fused = VM::new();
+            fused.load(program.clone(), None).unwrap();
+            let result = fused.run();
+            let mut stepped = VM::new();
+            stepped.load(program, None).unwrap();
+            let expected = loop {
+                match stepped.step() {
+                    Ok(true) => {}
+                    Ok(false) => break Ok(()),
+                    Err(fault) => break Err(fault),
+                }
+            };
+            assert_eq!(result, expected);
+            assert_eq!(fused.registers, stepped.registers);
+            assert_eq!(fused.pc, stepped.pc);
+            assert_eq!(fused.conditional, stepped.conditional);
+        }
+    }
+
+    #[test]
+    fn test_decoding_engine() {
+        let fib = Assembler::new()
+            .assemble(
+                "\tclr $0\n\tli $1 #1\n\tclr $4\n\tli $6 #20\n\
+                 loop:\n\tmov $1 $2\n\tadd $0 $1 $1\n\tmov $2 $0\n\tinc $4\n\
+                 \tlt $4 $6\n\tjmpc @loop\n\thlt\n",
+            )
+            .unwrap();
+        testing::differential(fib, Engine::Decoding, |_| {}).unwrap();
+        for program in [vec![1, 0, 1], vec![1, 0, 0, 8, 7, 0], vec![200, 0]] {
+            assert!(testing::differential(program, Engine::Decoding, |_| {}).is_err());
+        }
+    }
+
+    #[test]
+    fn test_fixed_width_programs() {
+        let source = "\tclr $0\n\tli $1 #1\n\tclr $4\n\tli $6 #30\n\
+                      loop:\n\tmov $1 $2\n\tadd $0 $1 $1\n\tmov $2 $0\n\taddi $4 #1 $4\n\
+                      \tblt $4 $6 @loop\n\tmuli $1 #-2 $5\n\tjmp @end\n\tnop\nend:\thlt\n";
+        let variable = Assembler::new().assemble(source).unwrap();
+        let fixed = Assembler::new()
+            .encoding(Encoding::Fixed)
+            .assemble_detailed(source)
+            .unwrap();
+        assert_eq!(&fixed.bytes[..4], b"AVM\x01");
+        assert_eq!(fixed.bytes.len(), 4 + 4 * 18);
+
+        let mut expected = VM::new();
+        expected.load(variable, None).unwrap();
+        expected.run().unwrap();
+        for engine in &[Engine::Interpreter, Engine::Closures, Engine::Jit] {
+            let mut test_vm = VM::new();
+            test_vm.set_engine(*engine);
+            test_vm.set_load_mode(LoadMode::Verified);
+            test_vm
+                .load(fixed.bytes.clone(), Some(fixed.debug_info.clone()))
+                .unwrap();
+            test_vm.run().unwrap();
+            // The scratch register holds the address `jmp @end` went to.
+            assert_eq!(test_vm.registers[..31], expected.registers[..31]);
+            assert_eq!(test_vm.registers[31], 68);
+            assert_eq!(test_vm.pc, 72);
+        }
+
+        // Jumping between words faults where it lands.
+        let program = Assembler::new()
+            .encoding(Encoding::Fixed)
+            .assemble("\tload $0 #6\n\tjmp $0\n\thlt\n")
+            .unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load(program, None).unwrap();
+        let fault = test_vm.run().unwrap_err();
+        assert_eq!((fault.pc, fault.error), (6, VmError::UnalignedInstruction));
+    }
+
+    #[test]
+    fn test_fault_source_location() {
+        let assembly = Assembler::new()
+            .assemble_detailed("start: load $1 #4\nloop:\n\tdiv $1 $0 $2 ; oops\n\thlt\n")
+            .unwrap();
+        let mut test_vm = VM::new();
+        test_vm
+            .load(assembly.bytes, Some(assembly.debug_info))
+            .unwrap();
+        let fault = test_vm.run().unwrap_err();
+        assert_eq!(fault.pc, 4);
+        assert_eq!(
+            fault.to_string(),
+            "line 3, column 2 in `loop`: division by zero (byte 4)\n    div $1 $0 $2"
+        );
+    }
+
+    #[test]
+    fn test_tracer() {
+        let assembly = Assembler::new()
+            .assemble_detailed("\tload $0 #2\nagain: dec $0\n\thlt\n")
+            .unwrap();
+        let mut test_vm = VM::new();
+        test_vm
+            .load(assembly.bytes, Some(assembly.debug_info))
+            .unwrap();
+        let trace = Rc::new(RefCell::new(vec![]));
+        let log = Rc::clone(&trace);
+        test_vm.set_tracer(move |entry| log.borrow_mut().push(entry.to_string()));
+        test_vm.run().unwrap();
+        assert_eq!(
+            *trace.borrow(),
+            vec![
+                "    0  load $0 #2        line 1, column 2",
+                "    4  load $31 #1       line 2, column 8 in `again`",
+                "    8  sub $0 $31 $0     line 2, column 8 in `again`",
+                "   12  hlt               line 3, column 2 in `again`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verified_load_mode() {
+        let mut test_vm = VM::new();
+        test_vm.set_load_mode(LoadMode::Verified);
+        assert_eq!(test_vm.load(vec![1, 0, 0, 7], None), Ok(()));
+        assert_eq!(
+            test_vm.load(vec![1, 0, 0, 3, 6, 0, 0], None),
+            Err(vec![Problem {
+                offset: 4,
+                error: VerifyError::JumpIntoInstruction { target: 3 },
+            }])
+        );
+        // The rejected program was not loaded.
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 7);
+    }
+}
@@ -0,0 +1,375 @@
+use std::rc::Rc;
+
+use crate::instruction::{Encoding, Opcode, OperandKind, Word, WORD_SIZE};
+
+use super::custom::CustomInstructions;
+use super::{VmError, REGISTER_COUNT};
+
+/// An instruction decoded ahead of time, with its registers already checked.
+/// `next` is the byte offset of the instruction that follows it. An
+/// instruction that faults is stored as `Opcode::IGL`, with `next` pointing
+/// where the program counter is left by the fault.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodedInstr {
+    pub opcode: Opcode,
+    pub operands: [u8; 3],
+    pub immediate: u16,
+    pub next: usize,
+}
+
+impl DecodedInstr {
+    /// The immediate, sign-extended, as `addi` and friends use it.
+    pub fn signed_immediate(&self) -> i32 {
+        i32::from(self.immediate as i16)
+    }
+}
+
+/// Decodes the instruction at `offset`. If it can't be run, returns the
+/// fault it raises and the offset the program counter is left at.
+pub fn decode_instr(program: &[u8], offset: usize) -> Result<DecodedInstr, (VmError, usize)> {
+    decode_instr_with(
+        program,
+        offset,
+        Encoding::Variable,
+        &CustomInstructions::new(),
+    )
+}
+
+/// Decodes the instruction at `offset` in a program in `encoding`, which
+/// may be one of `custom`.
+pub fn decode_instr_with(
+    program: &[u8],
+    offset: usize,
+    encoding: Encoding,
+    custom: &CustomInstructions,
+) -> Result<DecodedInstr, (VmError, usize)> {
+    if !offset.is_multiple_of(encoding.alignment()) {
+        return Err((VmError::UnalignedInstruction, offset + 1));
+    }
+    let (opcode, kinds) = match custom.get(program[offset]) {
+        Some(instruction) => (
+            Opcode::CUSTOM(instruction.byte),
+            instruction.operands.as_slice(),
+        ),
+        None => {
+            let opcode = Opcode::from(program[offset]);
+            (opcode, opcode.operands())
+        }
+    };
+    if opcode == Opcode::IGL {
+        let error = VmError::IllegalOpcode {
+            opcode: program[offset],
+        };
+        return Err((error, offset + encoding.alignment()));
+    }
+
+    let end_of_program = (VmError::UnexpectedEndOfProgram, program.len());
+    if encoding == Encoding::Fixed {
+        let bytes = program
+            .get(offset..offset + WORD_SIZE)
+            .ok_or(end_of_program)?;
+        let word = Word::from_bytes(
+            [bytes[0], bytes[1], bytes[2], bytes[3]],
+            kinds,
+            opcode.sign_extends(),
+        );
+        return Ok(DecodedInstr {
+            opcode,
+            operands: word.registers,
+            immediate: word.immediate,
+            next: offset + WORD_SIZE,
+        });
+    }
+
+    let mut instr = DecodedInstr {
+        opcode,
+        operands: [0; 3],
+        immediate: 0,
+        next: offset + 1,
+    };
+    let mut registers = instr.operands.iter_mut();
+    for kind in kinds {
+        match kind {
+            OperandKind::Register => {
+                let register = *program.get(instr.next).ok_or(end_of_program.clone())?;
+                instr.next += 1;
+                if usize::from(register) >= REGISTER_COUNT {
+                    return Err((VmError::InvalidRegister { register }, instr.next));
+                }
+                if let Some(operand) = registers.next() {
+                    *operand = register;
+                }
+            }
+            OperandKind::Immediate => {
+                let bytes = program
+                    .get(instr.next..instr.next + 2)
+                    .ok_or(end_of_program.clone())?;
+                instr.immediate = (u16::from(bytes[0]) << 8) | u16::from(bytes[1]);
+                instr.next += 2;
+            }
+        }
+    }
+    Ok(instr)
+}
+
+/// A run of instructions that the interpreter executes in one dispatch
+/// when it reaches the first of them. Jumping to one of the others still
+/// runs it on its own, and stepping always runs one instruction at a time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fused {
+    /// Not fused with what follows it.
+    Single,
+    /// A comparison, then `jmpc`.
+    CompareJump,
+    /// A comparison, a `load` and a `jmpc`, which is what `jmpc @label`
+    /// after a comparison assembles to.
+    CompareLoadJump,
+    /// A `load`, then `add`, `sub` or `mul`, as from `inc`, `mov` and
+    /// friends.
+    LoadArithmetic,
+    /// A `load`, then `jmp`.
+    LoadJump,
+}
+
+impl Fused {
+    /// The number of instructions fused.
+    pub fn length(self) -> usize {
+        match self {
+            Fused::Single => 1,
+            Fused::CompareLoadJump => 3,
+            _ => 2,
+        }
+    }
+}
+
+fn is_comparison(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTQ | Opcode::LTQ
+    )
+}
+
+// The superinstruction starting with the first of `instrs`, which were
+// decoded in order. Only an instruction that faults isn't followed by the
+// next one, and none of the patterns has one before its end.
+fn fuse(instrs: &[DecodedInstr]) -> Fused {
+    let opcodes: Vec<Opcode> = instrs.iter().map(|instr| instr.opcode).collect();
+    match opcodes.as_slice() {
+        [compare, Opcode::JMPC, ..] if is_comparison(*compare) => Fused::CompareJump,
+        [compare, Opcode::LOAD, Opcode::JMPC] if is_comparison(*compare) => Fused::CompareLoadJump,
+        [Opcode::LOAD, Opcode::ADD | Opcode::SUB | Opcode::MUL, ..] => Fused::LoadArithmetic,
+        [Opcode::LOAD, Opcode::JMP, ..] => Fused::LoadJump,
+        _ => Fused::Single,
+    }
+}
+
+const UNDECODED: u32 = u32::MAX;
+
+/// A program decoded once, in order, when it is loaded. `index` maps each
+/// byte offset to the instruction starting there, so jumps can keep using
+/// byte addresses. An offset in the middle of an instruction is decoded the
+/// first time something jumps to it, and added after the others.
+/// Instructions decoded in order are also fused into superinstructions.
+#[derive(Debug, Clone)]
+pub struct Predecoded {
+    pub instructions: Vec<DecodedInstr>,
+    // One for each instruction.
+    pub fused: Vec<Fused>,
+    index: Vec<u32>,
+    // The instructions decoded in order. Each of them is followed by the
+    // next one, unless it faults.
+    in_order: usize,
+    encoding: Encoding,
+    custom: Rc<CustomInstructions>,
+}
+
+impl Predecoded {
+    pub fn new(program: &[u8], encoding: Encoding, custom: Rc<CustomInstructions>) -> Predecoded {
+        let mut predecoded = Predecoded {
+            instructions: vec![],
+            fused: vec![],
+            index: vec![UNDECODED; program.len()],
+            in_order: 0,
+            encoding,
+            custom,
+        };
+        let mut offset = 0;
+        while offset < program.len() {
+            let instr = predecoded.decode(program, offset);
+            // Carry on after a faulting instruction as if it were as short
+            // as an instruction can be.
+            offset = match instr.opcode {
+                Opcode::IGL => offset + encoding.alignment(),
+                _ => instr.next,
+            };
+        }
+        predecoded.in_order = predecoded.instructions.len();
+        for index in 0..predecoded.in_order {
+            let end = predecoded.in_order.min(index + 3);
+            predecoded.fused[index] = fuse(&predecoded.instructions[index..end]);
+        }
+        predecoded
+    }
+
+    fn decode(&mut self, program: &[u8], offset: usize) -> DecodedInstr {
+        let decoded = decode_instr_with(program, offset, self.encoding, &self.custom);
+        let instr = decoded.unwrap_or_else(|(_, next)| DecodedInstr {
+            opcode: Opcode::IGL,
+            operands: [0; 3],
+            immediate: 0,
+            next,
+        });
+        self.index[offset] = self.instructions.len() as u32;
+        self.instructions.push(instr);
+        self.fused.push(Fused::Single);
+        instr
+    }
+
+    /// The index of the instruction starting at `offset`, which must lie in
+    /// `program`.
+    pub fn index_of(&mut self, program: &[u8], offset: usize) -> usize {
+        if self.index[offset] == UNDECODED {
+            self.decode(program, offset);
+        }
+        self.index[offset] as usize
+    }
+
+    /// The index of the instruction after the one at `index`, which has
+    /// just run and left the program counter at `pc`. Unless it jumped,
+    /// that is usually the next one along.
+    pub fn index_after(&mut self, program: &[u8], index: usize, pc: usize) -> usize {
+        if index + 1 < self.in_order && self.instructions[index].next == pc {
+            return index + 1;
+        }
+        self.index_of(program, pc)
+    }
+
+    /// The instruction starting at `offset`, which must lie in `program`.
+    pub fn at(&mut self, program: &[u8], offset: usize) -> DecodedInstr {
+        let index = self.index_of(program, offset);
+        self.instructions[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_instr() {
+        assert_eq!(
+            decode_instr(&[2, 0, 1, 2], 0),
+            Ok(DecodedInstr {
+                opcode: Opcode::ADD,
+                operands: [0, 1, 2],
+                immediate: 0,
+                next: 4,
+            })
+        );
+        assert_eq!(
+            decode_instr(&[0, 1, 3, 1, 244], 1),
+            Ok(DecodedInstr {
+                opcode: Opcode::LOAD,
+                operands: [3, 0, 0],
+                immediate: 500,
+                next: 5,
+            })
+        );
+        assert_eq!(
+            decode_instr(&[2, 0, 32, 1], 0),
+            Err((VmError::InvalidRegister { register: 32 }, 3))
+        );
+        assert_eq!(
+            decode_instr(&[1, 0, 1], 0),
+            Err((VmError::UnexpectedEndOfProgram, 3))
+        );
+        assert_eq!(
+            decode_instr(&[200], 0),
+            Err((VmError::IllegalOpcode { opcode: 200 }, 1))
+        );
+    }
+
+    #[test]
+    fn test_decode_words() {
+        let decode = |program: &[u8], offset| {
+            decode_instr_with(program, offset, Encoding::Fixed, &CustomInstructions::new())
+        };
+        // ADDI $1 #-3 $2, then LOAD $3 #500.
+        let program = [17, 0x08, 0xBF, 0xFD, 1, 0x18, 1, 244];
+        assert_eq!(
+            decode(&program, 0),
+            Ok(DecodedInstr {
+                opcode: Opcode::ADDI,
+                operands: [1, 2, 0],
+                immediate: -3i16 as u16,
+                next: 4,
+            })
+        );
+        assert_eq!(
+            decode(&program, 4),
+            Ok(DecodedInstr {
+                opcode: Opcode::LOAD,
+                operands: [3, 0, 0],
+                immediate: 500,
+                next: 8,
+            })
+        );
+        assert_eq!(decode(&program, 2), Err((VmError::UnalignedInstruction, 3)));
+        assert_eq!(
+            decode(&[200, 0, 0, 0], 0),
+            Err((VmError::IllegalOpcode { opcode: 200 }, 4))
+        );
+        assert_eq!(
+            decode(&[2, 0, 0], 0),
+            Err((VmError::UnexpectedEndOfProgram, 3))
+        );
+    }
+
+    #[test]
+    fn test_jumps_into_instructions() {
+        // LOAD $0 #1 holds a NOP in its last byte.
+        let program = [1, 0, 0, 255, 0];
+        let mut predecoded = Predecoded::new(&program, Encoding::Variable, Rc::default());
+        assert_eq!(predecoded.instructions.len(), 2);
+        assert_eq!(predecoded.at(&program, 4).opcode, Opcode::HLT);
+        assert_eq!(predecoded.at(&program, 3).opcode, Opcode::NOP);
+        assert_eq!(predecoded.instructions.len(), 3);
+        assert_eq!(predecoded.at(&program, 3).next, 4);
+        assert_eq!(predecoded.instructions.len(), 3);
+
+        assert_eq!(predecoded.index_after(&program, 0, 4), 1);
+        // The NOP was decoded last, so the HLT after it has to be looked up.
+        assert_eq!(predecoded.index_after(&program, 2, 4), 1);
+    }
+
+    #[test]
+    fn test_fuse() {
+        let program = [
+            13, 0, 1, //    LT   $0 $1
+            1, 2, 0, 0, //  LOAD $2 #0
+            9, 2, //        JMPC $2
+            1, 3, 0, 1, //  LOAD $3 #1
+            2, 0, 3, 0, //  ADD  $0 $3 $0
+            13, 0, 1,   //    LT   $0 $1
+            200, //
+            1, 2, 0, 0, //  LOAD $2 #0
+            6, 2, //        JMP  $2
+        ];
+        let predecoded = Predecoded::new(&program, Encoding::Variable, Rc::default());
+        assert_eq!(
+            predecoded.fused,
+            vec![
+                Fused::CompareLoadJump,
+                Fused::Single,
+                Fused::Single,
+                Fused::LoadArithmetic,
+                Fused::Single,
+                // Nothing is fused across a fault.
+                Fused::Single,
+                Fused::Single,
+                Fused::LoadJump,
+                Fused::Single,
+            ]
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use super::{Fault, Pause, VmError, VM};
+
+/// How a thread ended. Running off the end of the program counts as
+/// halting, as it does for `VM::run`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExitStatus {
+    Halted,
+    Faulted(Fault),
+    /// The thread was waiting for a message when every thread still running
+    /// was waiting too, so none could ever arrive.
+    Deadlocked,
+}
+
+struct Thread {
+    vm: VM,
+    // Set once the thread has ended.
+    status: Option<ExitStatus>,
+    // Messages sent to the thread that it hasn't received yet.
+    mailbox: VecDeque<i32>,
+    // The register a `recv` with an empty mailbox is waiting to fill.
+    waiting: Option<usize>,
+}
+
+/// Runs several VMs in turn on the current thread, each for a time slice of
+/// at most a set number of instructions. A thread gives up the rest of its
+/// slice with `yield`, and starts another thread with `spawn`. Threads are
+/// numbered from 0 in the order they are added or spawned, and always run
+/// in the interpreter.
+///
+/// Each thread has a mailbox. `send` adds a value to another thread's, and
+/// `recv` takes the oldest value from the thread's own, waiting until one
+/// arrives if it is empty.
+pub struct Scheduler {
+    threads: Vec<Thread>,
+    time_slice: usize,
+}
+
+impl Scheduler {
+    pub fn new(time_slice: usize) -> Scheduler {
+        Scheduler {
+            threads: vec![],
+            time_slice: time_slice.max(1),
+        }
+    }
+
+    /// Adds a VM with its program loaded, and returns its thread id.
+    pub fn add(&mut self, mut vm: VM) -> usize {
+        vm.scheduled = true;
+        self.threads.push(Thread {
+            vm,
+            status: None,
+            mailbox: VecDeque::new(),
+            waiting: None,
+        });
+        self.threads.len() - 1
+    }
+
+    pub fn thread(&self, id: usize) -> Option<&VM> {
+        self.threads.get(id).map(|thread| &thread.vm)
+    }
+
+    /// How the thread ended, or `None` if it is still running.
+    pub fn status(&self, id: usize) -> Option<&ExitStatus> {
+        self.threads
+            .get(id)
+            .and_then(|thread| thread.status.as_ref())
+    }
+
+    /// Puts `value` in the mailbox of thread `to`, as a `send` would.
+    /// Returns false if there is no such thread.
+    pub fn send(&mut self, to: usize, value: i32) -> bool {
+        let thread = match self.threads.get_mut(to) {
+            Some(thread) => thread,
+            None => return false,
+        };
+        match thread.waiting.take() {
+            Some(register) => thread.vm.registers[register] = value,
+            None => thread.mailbox.push_back(value),
+        }
+        true
+    }
+
+    /// Gives every running thread that isn't waiting for a message one time
+    /// slice, including threads spawned during the round. Returns whether
+    /// any thread is still running. If every one is waiting, they are all
+    /// deadlocked.
+    pub fn run_round(&mut self) -> bool {
+        let mut id = 0;
+        while id < self.threads.len() {
+            let thread = &self.threads[id];
+            if thread.status.is_none() && thread.waiting.is_none() {
+                self.run_slice(id);
+            }
+            id += 1;
+        }
+        let mut running = self
+            .threads
+            .iter_mut()
+            .filter(|thread| thread.status.is_none())
+            .peekable();
+        if running.peek().is_none() {
+            return false;
+        }
+        if running.all(|thread| thread.waiting.is_some()) {
+            for thread in &mut self.threads {
+                if thread.waiting.take().is_some() {
+                    thread.status = Some(ExitStatus::Deadlocked);
+                }
+            }
+            return false;
+        }
+        true
+    }
+
+    /// Runs until every thread has ended, and returns how each one did.
+    pub fn run(&mut self) -> Vec<ExitStatus> {
+        while self.run_round() {}
+        self.threads
+            .iter()
+            .filter_map(|thread| thread.status.clone())
+            .collect()
+    }
+
+    fn run_slice(&mut self, id: usize) {
+        let mut budget = self.time_slice;
+        while budget > 0 {
+            let (pause, count) = match self.threads[id].vm.run_for(budget) {
+                Ok(result) => result,
+                Err(fault) => {
+                    self.threads[id].status = Some(ExitStatus::Faulted(fault));
+                    return;
+                }
+            };
+            budget -= count;
+            match pause {
+                Pause::Spawned { register, target } => self.spawn(id, register, target),
+                Pause::Sent { pc, to, value } => {
+                    if to < 0 || !self.send(to as usize, value) {
+                        let thread = &mut self.threads[id];
+                        let fault = thread.vm.fault(pc, VmError::NoSuchThread { id: to });
+                        thread.status = Some(ExitStatus::Faulted(fault));
+                        return;
+                    }
+                }
+                Pause::Receiving { register } => {
+                    let thread = &mut self.threads[id];
+                    match thread.mailbox.pop_front() {
+                        Some(value) => thread.vm.registers[register] = value,
+                        None => {
+                            thread.waiting = Some(register);
+                            return;
+                        }
+                    }
+                }
+                Pause::OutOfTime | Pause::Yielded => return,
+                Pause::Halted => {
+                    self.threads[id].status = Some(ExitStatus::Halted);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Starts a thread at `target` in the program of thread `parent`, and
+    // puts its id in `register`. The new thread starts with a copy of its
+    // parent's registers, so it finds its own id there too.
+    fn spawn(&mut self, parent: usize, register: usize, target: usize) {
+        let id = self.threads.len();
+        let parent = &mut self.threads[parent].vm;
+        parent.registers[register] = id as i32;
+        let mut vm = VM::new();
+        vm.registers = parent.registers;
+        vm.pc = target;
+        vm.program = parent.program.clone();
+        vm.encoding = parent.encoding;
+        vm.debug_info = parent.debug_info.clone();
+        vm.load_mode = parent.load_mode;
+        vm.custom = Rc::clone(&parent.custom);
+        self.add(vm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn scheduler(time_slice: usize, source: &str) -> Scheduler {
+        let mut vm = VM::new();
+        vm.load(Assembler::new().assemble(source).unwrap(), None)
+            .unwrap();
+        let mut scheduler = Scheduler::new(time_slice);
+        scheduler.add(vm);
+        scheduler
+    }
+
+    #[test]
+    fn test_spawn() {
+        let mut scheduler = scheduler(
+            10,
+            "\tspawn $1 @child\n\tli $2 #7\n\thlt\n\
+             child:\n\tli $3 #9\n\tli $4 #0\n\tdiv $3 $4 $5\n",
+        );
+        assert_eq!(scheduler.status(0), None);
+        let statuses = scheduler.run();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0], ExitStatus::Halted);
+        match &statuses[1] {
+            ExitStatus::Faulted(fault) => assert_eq!(fault.error, VmError::DivisionByZero),
+            status => panic!("unexpected status {:?}", status),
+        }
+
+        let (parent, child) = (scheduler.thread(0).unwrap(), scheduler.thread(1).unwrap());
+        assert_eq!(parent.registers()[1], 1);
+        assert_eq!(parent.registers()[2], 7);
+        assert_eq!(child.registers()[1], 1);
+        assert_eq!(child.registers()[2], 0);
+        assert_eq!(child.registers()[3], 9);
+        assert!(scheduler.thread(2).is_none());
+    }
+
+    #[test]
+    fn test_time_slices() {
+        // Each thread counts down from 100, one instruction at a time.
+        let source = "\tspawn $0 @count\ncount:\n\tload $1 #100\n\
+                      loop:\n\tsubi $1 #1 $1\n\tbneq $1 $2 @loop\n";
+        let mut scheduler = scheduler(3, source);
+        assert!(scheduler.run_round());
+        // The parent spent one instruction of its slice on the spawn, and
+        // the child got a full slice in the same round.
+        assert_eq!(scheduler.thread(0).unwrap().registers()[1], 99);
+        assert_eq!(scheduler.thread(1).unwrap().registers()[1], 99);
+        assert!(scheduler.run_round());
+        assert_eq!(scheduler.thread(0).unwrap().registers()[1], 98);
+        assert_eq!(scheduler.thread(1).unwrap().registers()[1], 97);
+        assert_eq!(
+            scheduler.run(),
+            vec![ExitStatus::Halted, ExitStatus::Halted]
+        );
+    }
+
+    #[test]
+    fn test_yield() {
+        let mut scheduler = scheduler(100, "\tli $1 #1\n\tyield\n\tli $1 #2\n");
+        assert!(scheduler.run_round());
+        assert_eq!(scheduler.thread(0).unwrap().registers()[1], 1);
+        assert!(!scheduler.run_round());
+        assert_eq!(scheduler.thread(0).unwrap().registers()[1], 2);
+        assert_eq!(scheduler.status(0), Some(&ExitStatus::Halted));
+    }
+
+    #[test]
+    fn test_messages() {
+        // The child doubles what the parent sends it, and sends it back to
+        // thread 0.
+        let source = "\tspawn $1 @child\n\tload $2 #21\n\tsend $1 $2\n\trecv $3\n\thlt\n\
+                      child:\n\trecv $4\n\tadd $4 $4 $5\n\tsend $0 $5\n\thlt\n";
+        for time_slice in [1, 2, 100] {
+            let mut scheduler = scheduler(time_slice, source);
+            assert_eq!(
+                scheduler.run(),
+                vec![ExitStatus::Halted, ExitStatus::Halted]
+            );
+            assert_eq!(scheduler.thread(0).unwrap().registers()[3], 42);
+        }
+
+        let mut scheduler = scheduler(10, "\trecv $1\n\trecv $2\n");
+        assert!(scheduler.send(0, 7));
+        assert!(scheduler.send(0, 8));
+        assert!(!scheduler.send(1, 9));
+        assert_eq!(scheduler.run(), vec![ExitStatus::Halted]);
+        assert_eq!(scheduler.thread(0).unwrap().registers()[1], 7);
+        assert_eq!(scheduler.thread(0).unwrap().registers()[2], 8);
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let mut scheduler = scheduler(
+            10,
+            "\tspawn $1 @child\n\trecv $2\n\thlt\nchild: spawn $1 @done\n\trecv $2\ndone: hlt\n",
+        );
+        // Both waiting threads are found out in the first round.
+        assert!(!scheduler.run_round());
+        assert_eq!(
+            scheduler.run(),
+            vec![
+                ExitStatus::Deadlocked,
+                ExitStatus::Deadlocked,
+                ExitStatus::Halted
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_to_missing_thread() {
+        let mut scheduler = scheduler(10, "\tload $1 #7\n\tsend $1 $1\n\thlt\n");
+        match &scheduler.run()[..] {
+            [ExitStatus::Faulted(fault)] => {
+                assert_eq!(fault.error, VmError::NoSuchThread { id: 7 });
+                assert_eq!(fault.pc, 4);
+            }
+            statuses => panic!("unexpected statuses {:?}", statuses),
+        }
+    }
+
+    #[test]
+    fn test_threads_need_a_scheduler() {
+        let program = Assembler::new()
+            .assemble("\tyield\n\tspawn $0 #0\n")
+            .unwrap();
+        let mut vm = VM::new();
+        vm.load(program, None).unwrap();
+        assert_eq!(vm.run().unwrap_err().error, VmError::NoScheduler);
+        assert_eq!(vm.pc(), 5);
+    }
+}
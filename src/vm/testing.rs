@@ -0,0 +1,23 @@
+use super::{Engine, Fault, VM};
+
+/// Runs a program in the interpreter and with `engine`, and checks that the
+/// two end up the same. `prepare` can adjust the second VM once the program
+/// is loaded.
+pub fn differential(program: Vec<u8>, engine: Engine, prepare: fn(&mut VM)) -> Result<(), Fault> {
+    let mut interpreted = VM::new();
+    interpreted.load(program.clone(), None).unwrap();
+    let expected = interpreted.run();
+
+    let mut compiled = VM::new();
+    compiled.load(program, None).unwrap();
+    compiled.set_engine(engine);
+    prepare(&mut compiled);
+    let result = compiled.run();
+
+    assert_eq!(result, expected);
+    assert_eq!(compiled.registers, interpreted.registers);
+    assert_eq!(compiled.pc, interpreted.pc);
+    assert_eq!(compiled.conditional, interpreted.conditional);
+    assert_eq!(compiled.remainder, interpreted.remainder);
+    result
+}